@@ -0,0 +1,40 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use markdown_query::interactive::xapian_utils::parse_user_query;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{note, TestIndex};
+
+fn parse(c: &mut Criterion) {
+    c.bench_function("parse_user_query", |b| {
+        b.iter(|| {
+            parse_user_query(black_box(
+                r#"title:"rust lifetimes" +tag:rust,go -tag:draft author:steve borrow* path:work date:2021.. ;"#,
+            ))
+            .unwrap()
+        })
+    });
+}
+
+fn bulk_index(c: &mut Criterion) {
+    let docs: Vec<_> = (0..500)
+        .map(|i| {
+            note(
+                i,
+                "Some markdown body with a few words in it, enough to keep the term generator busy",
+                &["rust", "notes"],
+            )
+        })
+        .collect();
+    c.bench_function("index 500 notes", |b| {
+        b.iter_batched(
+            TestIndex::new,
+            |index| index.add(&docs),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+criterion_group!(benches, parse, bulk_index);
+criterion_main!(benches);
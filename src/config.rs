@@ -0,0 +1,189 @@
+use color_eyre::Report;
+use eyre::{eyre, WrapErr};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where a vault keeps its settings, found by walking up from the current directory like git
+/// finds `.git`
+pub const VAULT_CONFIG: &str = ".mdq/config.toml";
+
+/// Settings from a `config.toml`, standing in for flags that weren't given. For example:
+///
+/// ```toml
+/// db_path = ".mdq/db"
+/// default_fields = ["title", "tags"]
+/// field_config = ".mdq/fields.yaml"
+/// select_symbol = "▶ "
+/// select_style = "bold,bg:blue"
+///
+/// [notebooks]
+/// work = "~/notes/work"
+///
+/// [keys]
+/// "ctrl-g" = "todo add {path}"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The index, like `--db-path`
+    pub db_path: Option<String>,
+    /// Fields bare query words are searched in, like `--default-fields`
+    pub default_fields: Option<Vec<String>>,
+    /// Extra frontmatter fields to index, like `--field-config`
+    pub field_config: Option<String>,
+    /// The directory each notebook is in, like `update --notebook`
+    pub notebooks: Option<BTreeMap<String, String>>,
+    /// Drawn before the selected match in interactive mode, `"> "` by default
+    pub select_symbol: Option<String>,
+    /// How the selected match is drawn, see `styles::parse_style`, `"reversed"` by default
+    pub select_style: Option<String>,
+    /// Shell commands run on the selected match in interactive mode, by key, see
+    /// `keys::parse_bindings`
+    pub keys: Option<BTreeMap<String, String>>,
+}
+
+impl Config {
+    /// Read the config at `path`, taking relative paths in it from `base`
+    pub fn load(path: &Path, base: &Path) -> Result<Config, Report> {
+        let text = fs::read_to_string(path)
+            .wrap_err_with(|| format!("while reading config {}", path.display()))?;
+        let config: Config =
+            toml::from_str(&text).map_err(|e| eyre!("Invalid config {}: {}", path.display(), e))?;
+        Ok(Config {
+            db_path: config.db_path.map(|p| resolve(&p, base)),
+            field_config: config.field_config.map(|p| resolve(&p, base)),
+            notebooks: config.notebooks.map(|notebooks| {
+                notebooks
+                    .into_iter()
+                    .map(|(name, p)| (name, resolve(&p, base)))
+                    .collect()
+            }),
+            ..config
+        })
+    }
+
+    /// The global config, overridden by that of the vault `cwd` is in, if either exists
+    pub fn discover(cwd: &Path) -> Result<Config, Report> {
+        let mut config = Config::default();
+        if let Some(path) = global_config() {
+            let base = path.parent().unwrap_or(&path).to_path_buf();
+            config = Config::load(&path, &base)?;
+        }
+        if let Some(path) = find_vault_config(cwd) {
+            // Relative to the vault, the directory holding `.mdq`
+            let base = path.ancestors().nth(2).unwrap_or(cwd).to_path_buf();
+            config = config.merge(Config::load(&path, &base)?);
+        }
+        Ok(config)
+    }
+
+    /// These settings, with any set in `over` taking their place
+    pub fn merge(self, over: Config) -> Config {
+        Config {
+            db_path: over.db_path.or(self.db_path),
+            default_fields: over.default_fields.or(self.default_fields),
+            field_config: over.field_config.or(self.field_config),
+            notebooks: over.notebooks.or(self.notebooks),
+            select_symbol: over.select_symbol.or(self.select_symbol),
+            select_style: over.select_style.or(self.select_style),
+            keys: over.keys.or(self.keys),
+        }
+    }
+}
+
+/// `config.toml` in the user's config directory, e.g. `~/.config/mdq/config.toml`
+fn global_config() -> Option<PathBuf> {
+    dirs::config_dir()
+        .map(|dir| dir.join("mdq").join("config.toml"))
+        .filter(|path| path.is_file())
+}
+
+/// The nearest `.mdq/config.toml` in `start` or any directory above it
+pub fn find_vault_config(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(VAULT_CONFIG))
+        .find(|path| path.is_file())
+}
+
+// `path` with a leading `~` expanded, and taken from `base` when relative
+fn resolve(path: &str, base: &Path) -> String {
+    let expanded: String = shellexpand::tilde(path).into();
+    base.join(expanded).to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    fn write_config(dir: &Path, config: &str) {
+        fs::create_dir_all(dir.join(".mdq")).unwrap();
+        fs::write(dir.join(VAULT_CONFIG), config).unwrap();
+    }
+
+    #[test]
+    fn nearest_vault_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outer = tmp.path().join("notes");
+        let inner = outer.join("work");
+        let cwd = inner.join("projects").join("apollo");
+        fs::create_dir_all(&cwd).unwrap();
+        write_config(&outer, "db_path = \"outer-db\"\n");
+        write_config(
+            &inner,
+            "db_path = \".mdq/db\"\ndefault_fields = [\"title\", \"tags\"]\n\n[notebooks]\nwork = \"work\"\n",
+        );
+
+        assert_eq!(Some(inner.join(VAULT_CONFIG)), find_vault_config(&cwd));
+        let config = Config::discover(&cwd).unwrap();
+        assert_eq!(
+            Some(inner.join(".mdq/db").to_string_lossy().to_string()),
+            config.db_path
+        );
+        assert_eq!(
+            Some(vec![String::from("title"), String::from("tags")]),
+            config.default_fields
+        );
+        assert_eq!(
+            Some(BTreeMap::from([(
+                String::from("work"),
+                inner.join("work").to_string_lossy().to_string()
+            )])),
+            config.notebooks
+        );
+
+        // Above the inner vault, the outer one's is found
+        assert_eq!(Some(outer.join(VAULT_CONFIG)), find_vault_config(&outer));
+        assert_eq!(None, find_vault_config(tmp.path()));
+    }
+
+    #[test]
+    fn merge() {
+        let global = Config {
+            db_path: Some(String::from("/global-db")),
+            field_config: Some(String::from("/fields.yaml")),
+            ..Config::default()
+        };
+        let vault = Config {
+            db_path: Some(String::from("/vault-db")),
+            select_style: Some(String::from("bold")),
+            ..Config::default()
+        };
+        let merged = global.merge(vault);
+        assert_eq!(Some(String::from("/vault-db")), merged.db_path);
+        assert_eq!(Some(String::from("bold")), merged.select_style);
+        assert_eq!(None, merged.select_symbol);
+        assert_eq!(Some(String::from("/fields.yaml")), merged.field_config);
+        assert_eq!(None, merged.default_fields);
+    }
+
+    #[test]
+    fn unknown_setting() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_config(tmp.path(), "db_pth = \"db\"\n");
+        let e = Config::load(&tmp.path().join(VAULT_CONFIG), tmp.path()).unwrap_err();
+        assert!(e.to_string().contains("db_pth"), "{}", e);
+    }
+}
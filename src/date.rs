@@ -1,11 +1,13 @@
-use chrono::{offset, DateTime, NaiveDateTime, Utc};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{offset, DateTime, Duration, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use color_eyre::Report;
 use eyre::{eyre, Result};
 use serde::{de, Deserialize, Deserializer, Serialize};
+use std::cell::RefCell;
 use std::str::FromStr;
 use std::{fmt, marker::PhantomData};
 
-#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Date(i64);
 
 #[derive(Debug)]
@@ -18,18 +20,133 @@ impl Date {
     pub fn new(d: i64) -> Date {
         Date(d)
     }
+
+    /// Epoch seconds
+    pub fn timestamp(&self) -> i64 {
+        self.0
+    }
+
+    /// The day of the date as `YYYY-MM-DD`, in the timezone dates are displayed in
+    pub fn day(&self) -> String {
+        DATE_FORMAT.with(|format| {
+            DateFormat {
+                offset: format.borrow().offset,
+                format: Some(String::from("%Y-%m-%d")),
+            }
+            .render(self)
+        })
+    }
+
+    /// Parse `s` as the last moment it covers, so that a plain `YYYY-MM-DD` takes in that whole
+    /// day. Anything else `from_str` accepts is a single moment already.
+    pub fn parse_end(s: &str) -> Result<Date, Report> {
+        match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            Ok(day) => Ok(Date::new(day.and_hms(23, 59, 59).timestamp())),
+            Err(_) => Date::from_str(s),
+        }
+    }
 }
 
-impl fmt::Display for Date {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Create a NaiveDateTime from the timestamp
-        let naive = NaiveDateTime::from_timestamp(self.0, 0);
+/// How `Date`s are displayed, and so written out for humans and to disk. The index always
+/// stores epoch seconds, whatever the format.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DateFormat {
+    /// Offset from UTC to show times at, the local timezone when `None`
+    pub offset: Option<FixedOffset>,
+    /// strftime-style format, RFC 3339 when `None`
+    pub format: Option<String>,
+}
+
+impl DateFormat {
+    /// `tz` is `local`, `utc` or an offset like `+05:30`, and `format` a strftime-style format
+    /// such as `%Y-%m-%d`, or empty for RFC 3339
+    pub fn new(tz: &str, format: &str) -> Result<DateFormat, Report> {
+        let offset = match tz.to_ascii_lowercase().as_str() {
+            "local" => None,
+            "utc" | "z" => Some(FixedOffset::east(0)),
+            _ => Some(parse_offset(tz)?),
+        };
+        let format = if format.is_empty() {
+            None
+        } else if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return Err(eyre!("Invalid date format '{}'", format));
+        } else {
+            Some(format.to_string())
+        };
+        Ok(DateFormat { offset, format })
+    }
 
-        // Create a normal DateTime from the NaiveDateTime
+    fn render(&self, date: &Date) -> String {
+        let naive = NaiveDateTime::from_timestamp(date.0, 0);
         let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+        match self.offset {
+            Some(offset) => render(&datetime.with_timezone(&offset), &self.format),
+            None => render(&datetime.with_timezone(&offset::Local), &self.format),
+        }
+    }
+}
 
-        // Format the datetime how you want
-        write!(f, "{}", datetime.with_timezone(&offset::Local).to_rfc3339())
+fn render<Tz: TimeZone>(datetime: &DateTime<Tz>, format: &Option<String>) -> String
+where
+    Tz::Offset: fmt::Display,
+{
+    match format {
+        Some(format) => datetime.format(format).to_string(),
+        None => datetime.to_rfc3339(),
+    }
+}
+
+// An offset like `+05:30`, `-0400` or `+09`
+fn parse_offset(s: &str) -> Result<FixedOffset, Report> {
+    let invalid = || {
+        eyre!(
+            "Invalid timezone '{}', expected local, utc or an offset like +05:30",
+            s
+        )
+    };
+    let (sign, rest) = match s.chars().next() {
+        Some('+') => (1, &s[1..]),
+        Some('-') => (-1, &s[1..]),
+        _ => return Err(invalid()),
+    };
+    let digits: String = rest.chars().filter(|c| *c != ':').collect();
+    if !(digits.len() == 2 || digits.len() == 4) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let hours: i32 = digits[..2].parse()?;
+    let minutes: i32 = if digits.len() == 4 {
+        digits[2..].parse()?
+    } else {
+        0
+    };
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(FixedOffset::east(sign * (hours * 3600 + minutes * 60)))
+}
+
+thread_local! {
+    static DATE_FORMAT: RefCell<DateFormat> = RefCell::new(DateFormat::default());
+}
+
+/// Display `Date`s on the current thread with `format` from now on
+pub fn set_date_format(format: DateFormat) {
+    DATE_FORMAT.with(|current| *current.borrow_mut() = format);
+}
+
+/// Run `f` with `Date`s on the current thread displayed with `format`, restoring the previous
+/// format afterwards
+pub fn with_date_format<T>(format: DateFormat, f: impl FnOnce() -> T) -> T {
+    let previous = DATE_FORMAT.with(|current| current.replace(format));
+    let out = f();
+    set_date_format(previous);
+    out
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rendered = DATE_FORMAT.with(|format| format.borrow().render(self));
+        write!(f, "{}", rendered)
     }
 }
 
@@ -43,12 +160,61 @@ impl FromStr for Date {
             Ok(Date::new(s.timestamp()))
         } else if let Ok(s) = s.parse::<i64>() {
             Ok(Date::new(s))
+        } else if let Ok(day) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            Ok(Date::new(day.and_hms(0, 0, 0).timestamp()))
         } else {
             Err(eyre!("❌ Failed to convert {} to str", s))
         }
     }
 }
 
+/// A span of time back from now, e.g. `7d`, `2w`, `3m` or `1y`, counting a month as 30 days
+/// and a year as 365
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recency {
+    amount: i64,
+    unit: char,
+}
+
+impl Recency {
+    /// The span ending at `now`
+    pub fn range(&self, now: DateTime<Utc>) -> DateRange {
+        let days = match self.unit {
+            'w' => 7,
+            'm' => 30,
+            'y' => 365,
+            _ => 1,
+        };
+        DateRange {
+            start: now - Duration::days(self.amount * days),
+            end: now,
+        }
+    }
+}
+
+impl FromStr for Recency {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Recency, Self::Err> {
+        let invalid = || eyre!("Invalid span '{}', expected e.g. 7d, 2w, 3m or 1y", s);
+        let unit = s.chars().last().ok_or_else(invalid)?.to_ascii_lowercase();
+        if !"dwmy".contains(unit) {
+            return Err(invalid());
+        }
+        let amount = s[..s.len() - 1].parse::<i64>().map_err(|_| invalid())?;
+        if amount <= 0 {
+            return Err(invalid());
+        }
+        Ok(Recency { amount, unit })
+    }
+}
+
+impl fmt::Display for Recency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.amount, self.unit)
+    }
+}
+
 /// Support Deserializing a date from either a string or i64
 pub fn date_deserializer<'de, D>(deserializer: D) -> Result<Date, D::Error>
 where
@@ -67,7 +233,7 @@ where
         where
             E: de::Error,
         {
-            Ok(Date::from_str(value).unwrap())
+            Date::from_str(value).map_err(E::custom)
         }
 
         fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
@@ -101,3 +267,83 @@ where
 
     deserializer.deserialize_any(StringOrVec(PhantomData))
 }
+
+#[cfg(test)]
+mod date_tests {
+    use super::*;
+
+    #[test]
+    fn recency() {
+        let now = Utc.ymd(2022, 3, 31).and_hms(12, 0, 0);
+        for (span, start) in [
+            ("7d", Utc.ymd(2022, 3, 24).and_hms(12, 0, 0)),
+            ("30d", Utc.ymd(2022, 3, 1).and_hms(12, 0, 0)),
+            ("2w", Utc.ymd(2022, 3, 17).and_hms(12, 0, 0)),
+            ("1M", Utc.ymd(2022, 3, 1).and_hms(12, 0, 0)),
+            ("1y", Utc.ymd(2021, 3, 31).and_hms(12, 0, 0)),
+        ] {
+            let range = Recency::from_str(span).unwrap().range(now);
+            assert_eq!(start, range.start, "{}", span);
+            assert_eq!(now, range.end, "{}", span);
+        }
+        assert_eq!("1m", Recency::from_str("1M").unwrap().to_string());
+        for span in ["", "d", "7", "0d", "-7d", "7x", "7 d", "١d"] {
+            assert!(Recency::from_str(span).is_err(), "{}", span);
+        }
+    }
+
+    #[test]
+    fn plain_day() {
+        assert_eq!(
+            1609459200,
+            Date::from_str("2021-01-01").unwrap().timestamp()
+        );
+        assert_eq!(
+            1609545599,
+            Date::parse_end("2021-01-01").unwrap().timestamp()
+        );
+    }
+
+    #[test]
+    fn end_of_a_moment() {
+        assert_eq!(
+            Date::from_str("2021-01-01T12:00:00+00:00").unwrap(),
+            Date::parse_end("2021-01-01T12:00:00+00:00").unwrap()
+        );
+        assert!(Date::parse_end("next tuesday").is_err());
+    }
+
+    #[test]
+    fn output_timezone() {
+        let date = Date::new(1624380496);
+        let utc = DateFormat::new("utc", "").unwrap();
+        assert_eq!(
+            "2021-06-22T16:48:16+00:00",
+            with_date_format(utc, || date.to_string())
+        );
+        let east = DateFormat::new("+05:30", "").unwrap();
+        assert_eq!(
+            "2021-06-22T22:18:16+05:30",
+            with_date_format(east, || date.to_string())
+        );
+        let west = DateFormat::new("-0400", "%Y-%m-%d %H:%M").unwrap();
+        assert_eq!(
+            "2021-06-22 12:48",
+            with_date_format(west, || date.to_string())
+        );
+        // Back to the default afterwards
+        assert_eq!(DateFormat::default().render(&date), date.to_string());
+    }
+
+    #[test]
+    fn output_format() {
+        let format = DateFormat::new("utc", "%Y-%m-%d").unwrap();
+        assert_eq!(
+            "2021-06-22",
+            with_date_format(format, || Date::new(1624380496).to_string())
+        );
+        assert!(DateFormat::new("utc", "%Q").is_err());
+        assert!(DateFormat::new("Mars/Olympus", "").is_err());
+        assert!(DateFormat::new("+25:00", "").is_err());
+    }
+}
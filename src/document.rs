@@ -1,22 +1,31 @@
-use crate::date::{date_deserializer, Date};
+use crate::date::{date_deserializer, with_date_format, Date, DateFormat};
+use crate::interactive::xapian_utils::{path_terms, CustomField};
+use chrono::Utc;
 use color_eyre::Report;
-use eyre::Result;
+use eyre::{Result, WrapErr};
+use pulldown_cmark::{Event, Parser, Tag};
 use serde::{
-    de, ser::SerializeSeq, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer,
+    de, ser::SerializeMap, ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer,
 };
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::io::{Error, ErrorKind};
 
 use std::{fmt, fs, io, marker::PhantomData};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use unicode_width::UnicodeWidthStr;
 use uuid_b64::UuidB64;
-use xapian_rusty::{Document as XapDoc, TermGenerator, WritableDatabase};
+use xapian_rusty::{Document as XapDoc, TermGenerator, TermGeneratorFlag, WritableDatabase};
 use yaml_rust::YamlEmitter;
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 pub enum SerializationType {
     /// Serialize body only when putting into Storage
     Storage,
+    /// The frontmatter as written to a note's file
     Disk,
+    /// What a person reading it wants: the frontmatter, with the path and body
     Human,
     Preview,
 }
@@ -27,6 +36,22 @@ impl Default for SerializationType {
     }
 }
 
+impl std::str::FromStr for SerializationType {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<SerializationType, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "storage" => Ok(SerializationType::Storage),
+            "disk" => Ok(SerializationType::Disk),
+            "human" => Ok(SerializationType::Human),
+            _ => Err(eyre::eyre!(
+                "Unknown projection '{}', expected storage, disk or human",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Deserialize)]
 pub struct VecString(Vec<String>);
 
@@ -34,6 +59,10 @@ impl VecString {
     pub fn new(v: Vec<String>) -> VecString {
         VecString(v)
     }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, String> {
+        self.0.iter()
+    }
 }
 
 impl fmt::Display for VecString {
@@ -78,6 +107,19 @@ pub struct Document {
     #[serde(default)]
     #[serde(skip)]
     pub serialization_type: SerializationType,
+    /// Relevance of this document to the query it was retrieved by, never stored
+    #[serde(skip)]
+    pub score: f64,
+    #[serde(skip)]
+    pub percent: i32,
+    /// How many other matches were collapsed into this one as the same note, see
+    /// `search::dedupe`, never stored
+    #[serde(skip)]
+    pub duplicates: usize,
+    /// The line of the file the body starts on, just past the frontmatter, for opening editors
+    /// there; set by `parse_file`, never stored
+    #[serde(skip)]
+    pub body_line: usize,
 
     /// FrontMatter-derived metadata about the document
     #[serde(default, alias = "author")]
@@ -88,6 +130,17 @@ pub struct Document {
     #[serde(deserialize_with = "date_deserializer")]
     pub date: Date,
 
+    /// When the note was last edited, the file's modification time unless the frontmatter says
+    #[serde(default, deserialize_with = "date_deserializer")]
+    pub modified: Date,
+
+    /// Modification time and contents hash of the file the document was loaded from, stored so
+    /// that an update can skip files which haven't changed
+    #[serde(default, deserialize_with = "date_deserializer")]
+    pub file_modified: Date,
+    #[serde(default)]
+    pub content_hash: String,
+
     #[serde(default)]
     #[serde(deserialize_with = "string_or_list_string", alias = "tag")]
     pub tags: Vec<String>,
@@ -103,9 +156,25 @@ pub struct Document {
     #[serde(default)]
     pub subtitle: String,
 
+    /// Other names the note goes by, searched along with the title
+    #[serde(default, deserialize_with = "string_or_list_string")]
+    pub aliases: Vec<String>,
+
+    /// Where the note is in a workflow, e.g. draft, active or archived, for `status:` filters
+    #[serde(default)]
+    pub status: String,
+
+    /// The notebook the note was indexed into, going by the directory it's in, empty for none
+    #[serde(default)]
+    pub notebook: String,
+
     /// The Markdown-formatted body of the document
     #[serde(default)]
     pub body: String,
+
+    /// Any other frontmatter, e.g. `project` or `priority`, kept so it's written back out as is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_yaml::Value>,
 }
 
 #[allow(dead_code)]
@@ -120,7 +189,19 @@ impl Document {
         }
     }
 
-    pub fn parse_file(path: &std::path::Path) -> Result<Document, io::Error> {
+    /// The document, serializing as `serialization_type`, e.g. to print it as JSON without
+    /// the fields only kept for the index
+    pub fn with_serialization(mut self, serialization_type: SerializationType) -> Document {
+        self.serialization_type = serialization_type;
+        self
+    }
+
+    /// Parse a Markdown+FrontMatter file. Without frontmatter the file is an error, unless
+    /// `infer_frontmatter` is set in which case its metadata is made up, see `inferred`.
+    pub fn parse_file(
+        path: &std::path::Path,
+        infer_frontmatter: bool,
+    ) -> Result<Document, io::Error> {
         let full_path = path.to_str().unwrap();
         let s = fs::read_to_string(full_path)?;
 
@@ -133,63 +214,1399 @@ impl Document {
                     emitter.dump(&yaml).unwrap(); // dump the YAML object to a String
                 }
 
-                let mut doc: Document = match serde_yaml::from_str(&out_str) {
-                    Ok(d) => d,
-                    Err(e) => {
-                        eprintln!("Error reading yaml {}: {:?} {}", full_path, e, out_str);
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            format!("Error reading yaml {}: {}", path.display(), e),
-                        ));
-                    }
+                // Check the frontmatter first, so that mistakes get a helpful message rather than
+                // whatever serde makes of them
+                let problems = match serde_yaml::from_str(&out_str) {
+                    Ok(value) => validate_frontmatter(&value),
+                    Err(e) => vec![e.to_string()],
                 };
+                if !problems.is_empty() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{}: {}", path.display(), problems.join("; ")),
+                    ));
+                }
+                let mut doc: Document = serde_yaml::from_str(&out_str).map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+                })?;
                 doc.fullpath = String::from(full_path);
                 doc.body = content.to_string();
+                doc.body_line = line_of(&s, content);
+                doc.tags = clean_tags(doc.tags, false);
                 if doc.id.width() == 0 {
                     let uuid = UuidB64::new();
                     doc.id = uuid.to_string();
                 }
+                doc.file_modified = file_modified(path)?;
+                doc.content_hash = content_hash(s.as_bytes());
+                if yaml["modified"].is_badvalue() {
+                    doc.modified = doc.file_modified.clone();
+                }
 
                 Ok(doc)
             }
+            None if infer_frontmatter => {
+                let mut doc = Document::inferred(path, &s);
+                doc.body_line = 1;
+                doc.file_modified = file_modified(path)?;
+                doc.content_hash = content_hash(s.as_bytes());
+                doc.modified = doc.file_modified.clone();
+                Ok(doc)
+            }
             None => Err(Error::new(
                 ErrorKind::Other,
-                format!("Failed to process file {}", path.display()),
+                format!(
+                    "{}: no frontmatter found, start the file with a `---` block holding at least \
+                     `title` and `date`, or index it with --infer-frontmatter",
+                    path.display()
+                ),
             )),
         }
     }
 
+    /// Document for a plain markdown file: titled by its first `# ` heading, or failing that its
+    /// file name, dated now and untagged
+    fn inferred(path: &std::path::Path, content: &str) -> Document {
+        let title = content
+            .lines()
+            .filter_map(|line| line.strip_prefix("# "))
+            .map(str::trim)
+            .find(|title| !title.is_empty())
+            .map(String::from)
+            .unwrap_or_else(|| {
+                path.file_stem()
+                    .map_or_else(String::new, |stem| stem.to_string_lossy().to_string())
+            });
+
+        Document {
+            fullpath: path.to_string_lossy().to_string(),
+            id: UuidB64::new().to_string(),
+            date: Date::new(Utc::now().timestamp()),
+            title,
+            body: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The text of the extra frontmatter field `name`, one entry per list item if it's a list
+    pub fn extra_values(&self, name: &str) -> Vec<String> {
+        use serde_yaml::Value;
+
+        fn scalar(value: &Value) -> Option<String> {
+            match value {
+                Value::String(s) => Some(s.clone()),
+                Value::Number(n) => Some(n.to_string()),
+                Value::Bool(b) => Some(b.to_string()),
+                _ => None,
+            }
+        }
+        match self.extra.get(name) {
+            Some(Value::Sequence(items)) => items.iter().filter_map(scalar).collect(),
+            Some(value) => scalar(value).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// One line excerpt of the body, at most `width` characters, centered on the earliest
+    /// occurrence of one of the (lowercase) `terms`. Cut off text is marked with `…`, and without
+    /// any match it's the start of the body.
+    pub fn snippet(&self, terms: &[String], width: usize) -> String {
+        let text = self
+            .body
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+        // ASCII lowercasing keeps byte offsets identical to `text`
+        let lower = text.to_ascii_lowercase();
+        let found = terms
+            .iter()
+            .filter(|t| !t.is_empty())
+            .filter_map(|t| lower.find(t.as_str()).map(|pos| (pos, t.chars().count())))
+            .min();
+
+        let chars: Vec<char> = text.chars().collect();
+        let start = match found {
+            Some((pos, len)) => {
+                let pos = text[..pos].chars().count();
+                let start = pos.saturating_sub(width.saturating_sub(len) / 2);
+                // Use up the whole width when the match is near the end
+                start.min(chars.len().saturating_sub(width))
+            }
+            None => 0,
+        };
+        let end = (start + width).min(chars.len());
+
+        let mut snippet = chars[start..end].to_vec();
+        if start > 0 {
+            if let Some(first) = snippet.first_mut() {
+                *first = '…';
+            }
+        }
+        if end < chars.len() {
+            if let Some(last) = snippet.last_mut() {
+                *last = '…';
+            }
+        }
+        snippet.into_iter().collect()
+    }
+
     pub fn update_index(
         &self,
         db: &mut WritableDatabase,
         tg: &mut TermGenerator,
+    ) -> Result<(), Report> {
+        self.update_index_with(db, tg, false, &[], false, TextIndexing::default())
+    }
+
+    /// Like `update_index`, but leave the body out of the index altogether, both its words and
+    /// the stored copy, so only the metadata can be searched
+    pub fn update_index_metadata_only(
+        &self,
+        db: &mut WritableDatabase,
+        tg: &mut TermGenerator,
+    ) -> Result<(), Report> {
+        self.update_index_with(db, tg, true, &[], false, TextIndexing::default())
+    }
+
+    /// Index the document, leaving out the body when `metadata_only`, and also making each of
+    /// `custom_fields` found in its frontmatter searchable. With `hierarchical_tags` a tag like
+    /// `project/apollo` is also found by a search for its ancestor `tag:project`. Words are
+    /// picked out of the text as `text` says.
+    pub fn update_index_with(
+        &self,
+        db: &mut WritableDatabase,
+        tg: &mut TermGenerator,
+        metadata_only: bool,
+        custom_fields: &[CustomField],
+        hierarchical_tags: bool,
+        text: TextIndexing,
+    ) -> Result<(), Report> {
+        self.index(
+            db,
+            tg,
+            metadata_only,
+            custom_fields,
+            hierarchical_tags,
+            text,
+        )
+        .wrap_err_with(|| format!("while indexing {}", self.fullpath))
+    }
+
+    fn index(
+        &self,
+        db: &mut WritableDatabase,
+        tg: &mut TermGenerator,
+        metadata_only: bool,
+        custom_fields: &[CustomField],
+        hierarchical_tags: bool,
+        text: TextIndexing,
     ) -> Result<(), Report> {
         // Create a new Xapian Document to store attributes on the passed-in Document
         let mut doc = XapDoc::new()?;
         tg.set_document(&mut doc)?;
+        // Record the spelling of indexed words, so that queries can be corrected against them
+        tg.set_database(db)?;
+        let mut flags = TermGeneratorFlag::FlagSpelling as i32;
+        if text.cjk {
+            flags |= TermGeneratorFlag::FlagCjkNgram as i32;
+        }
+        tg.set_flags(flags)?;
 
-        tg.index_text_with_prefix(&self.authors.to_string(), "A")?;
-        tg.index_text_with_prefix(&self.date.to_string(), "D")?;
+        for author in self.authors.iter() {
+            tg.index_text_with_prefix(author, "A")?;
+        }
+        if !self.authors.is_empty() {
+            doc.add_boolean_term(&has_term("A"))?;
+        }
+        // Terms for `date:` searches by day, month or year, and the exact time in a value slot
+        // for ranges
+        for term in date_terms(&self.date) {
+            doc.add_boolean_term(&term)?;
+        }
+        doc.add_long(DATE_SLOT, self.date.timestamp())?;
+        // Kept in a value slot too, for readers of the index sorting with Xapian itself
+        doc.add_long(MODIFIED_SLOT, self.modified.timestamp())?;
         tg.index_text_with_prefix(&self.fullpath, "U")?;
+        // The file name again on its own, so `filename:` doesn't match directories
+        if let Some(name) = std::path::Path::new(&self.fullpath).file_name() {
+            tg.index_text_with_prefix(&name.to_string_lossy(), "F")?;
+        }
         tg.index_text_with_prefix(&self.title, "S")?;
+        for alias in &self.aliases {
+            tg.index_text_with_prefix(alias, "S")?;
+        }
         tg.index_text_with_prefix(&self.subtitle, "XS")?;
         for tag in &self.tags {
-            tg.index_text_with_prefix(tag, "K")?;
+            for term in tag_terms(tag, hierarchical_tags) {
+                // Nested tags are whole terms, so `project/apollo` isn't split into words
+                if indexed_whole(&term) {
+                    doc.add_boolean_term(&format!("K{}", term))?;
+                } else {
+                    tg.index_text_with_prefix(&term, "K")?;
+                }
+            }
+        }
+        if !self.tags.is_empty() {
+            doc.add_boolean_term(&has_term("K"))?;
+        }
+        for field in custom_fields {
+            for value in self.extra_values(&field.name) {
+                tg.index_text_with_prefix(&value, &field.prefix)?;
+            }
+        }
+        if let Some(term) = status_term(&self.status) {
+            doc.add_boolean_term(&term)?;
+        }
+        if let Some(term) = notebook_term(&self.notebook) {
+            doc.add_boolean_term(&term)?;
+        }
+        // Directory subtrees for `path:` searches
+        for term in path_terms(&self.fullpath) {
+            doc.add_boolean_term(&term)?;
         }
 
-        tg.index_text(&self.body)?;
-
-        // Convert the Document into JSON and set it in the DB for retrieval later
-        doc.set_data(&serde_json::to_string(&self).unwrap())?;
+        // Convert the Document into JSON and set it in the DB for retrieval later. Serializing for
+        // Disk would drop the body, but the full path and exact date with it.
+        if !metadata_only {
+            tg.index_text(&text.body_text(&self.body))?;
+            doc.set_data(&serde_json::to_string(&self).unwrap())?;
+        } else {
+            let stored = Document {
+                body: String::new(),
+                ..self.clone()
+            };
+            doc.set_data(&serde_json::to_string(&stored).unwrap())?;
+        }
 
+        // Unique on the full path, so same-named files in different directories don't collide
         let id = "Q".to_owned() + &self.fullpath;
         doc.add_boolean_term(&id)?;
-        db.replace_document(&id, &mut doc)?;
+        db.replace_document(&id, &mut doc)
+            .wrap_err("while writing the document to the index")?;
 
         Ok(())
     }
 }
 
+/// How a note's text is split into the words indexed for it. The body is stored and shown as
+/// written either way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextIndexing {
+    /// Index the text the body's markdown renders to rather than its source, see `plain_text`
+    pub strip_markdown: bool,
+    /// Index what's in fenced and indented code blocks, rather than only the prose around them
+    pub code_blocks: bool,
+    /// Index Chinese, Japanese and Korean text, which isn't split into words by spaces, as
+    /// overlapping runs of characters, so that any run of them is found. Queries need
+    /// `ParseOptions::cjk` to match.
+    pub cjk: bool,
+}
+
+impl Default for TextIndexing {
+    fn default() -> TextIndexing {
+        TextIndexing {
+            strip_markdown: false,
+            code_blocks: true,
+            cjk: false,
+        }
+    }
+}
+
+impl TextIndexing {
+    /// The text indexed for `body`
+    pub fn body_text<'b>(&self, body: &'b str) -> Cow<'b, str> {
+        match (self.strip_markdown, self.code_blocks) {
+            (true, code_blocks) => Cow::Owned(plain_text(body, code_blocks)),
+            (false, true) => Cow::Borrowed(body),
+            (false, false) => Cow::Owned(without_code_blocks(body)),
+        }
+    }
+}
+
+/// The text `markdown` renders to, without its syntax: no `#`s, `*`s or fences, links and images
+/// by their text alone rather than their URLs, and no HTML tags. Each block ends its line. Code
+/// blocks are left out unless `code_blocks`, inline code is kept.
+pub fn plain_text(markdown: &str, code_blocks: bool) -> String {
+    let mut text = String::new();
+    let mut in_code_block = false;
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::Text(_) if in_code_block && !code_blocks => {}
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak | Event::Rule => text.push('\n'),
+            // Inline elements carry on the line they're in
+            Event::End(
+                Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(..) | Tag::Image(..),
+            ) => {}
+            Event::End(tag) => {
+                in_code_block &= !matches!(tag, Tag::CodeBlock(_));
+                text.push('\n');
+            }
+            _ => {}
+        }
+    }
+    text
+}
+
+/// `markdown` as written, only without its fenced and indented code blocks
+fn without_code_blocks(markdown: &str) -> String {
+    let mut text = String::new();
+    let mut kept_from = 0;
+    for (event, range) in Parser::new(markdown).into_offset_iter() {
+        // A block's start event spans all of it, fences and all
+        if let Event::Start(Tag::CodeBlock(_)) = event {
+            if range.start >= kept_from {
+                text.push_str(&markdown[kept_from..range.start]);
+                text.push('\n');
+                kept_from = range.end;
+            }
+        }
+    }
+    text.push_str(&markdown[kept_from..]);
+    text
+}
+
+/// Modification time of the file at `path`
+fn file_modified(path: &std::path::Path) -> Result<Date, io::Error> {
+    let mtime = fs::metadata(path)?.modified()?;
+    Ok(Date::new(
+        mtime
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64),
+    ))
+}
+
+/// Hash of a file's contents, to tell whether it changed since it was indexed, or whether two
+/// notes have the same body. SHA-256, so that different contents can't be taken for the same.
+pub fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod content_hash_tests {
+    use super::*;
+
+    #[test]
+    fn sha256_hex() {
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            content_hash(b"")
+        );
+        assert_ne!(content_hash(b"note one"), content_hash(b"note two"));
+    }
+}
+
+// The line of `text` that `rest`, the end of it, starts on, counting from 1
+fn line_of(text: &str, rest: &str) -> usize {
+    if !text.ends_with(rest) {
+        return 1;
+    }
+    text[..text.len() - rest.len()].matches('\n').count() + 1
+}
+
+/// Problems with parsed `frontmatter` that would stop it loading into a `Document`, each with a
+/// hint on how to fix it. Empty if it's fine.
+pub fn validate_frontmatter(frontmatter: &serde_yaml::Value) -> Vec<String> {
+    use serde_yaml::Value;
+
+    let fields = match frontmatter {
+        Value::Mapping(fields) => fields,
+        other => {
+            return vec![format!(
+                "frontmatter must be `key: value` fields, got {}",
+                describe(other)
+            )]
+        }
+    };
+    let field = |name: &str| fields.get(&Value::from(name));
+    let is_string_list =
+        |v: &Value| matches!(v, Value::Sequence(items) if items.iter().all(Value::is_string));
+
+    let mut problems = Vec::new();
+    match field("title") {
+        None => problems.push(String::from("missing required field `title`")),
+        Some(Value::String(_)) => {}
+        Some(other) => problems.push(format!("`title` must be text, got {}", describe(other))),
+    }
+    match field("date") {
+        None => problems.push(String::from("missing required field `date`")),
+        Some(Value::String(s)) if s.parse::<Date>().is_ok() => {}
+        Some(Value::Number(n)) if n.is_i64() => {}
+        Some(other) => problems.push(format!(
+            "`date` must be RFC-3339 or epoch seconds, got {}",
+            describe(other)
+        )),
+    }
+    match field("modified") {
+        None => {}
+        Some(Value::String(s)) if s.parse::<Date>().is_ok() => {}
+        Some(Value::Number(n)) if n.is_i64() => {}
+        Some(other) => problems.push(format!(
+            "`modified` must be RFC-3339 or epoch seconds, got {}",
+            describe(other)
+        )),
+    }
+    for name in ["tags", "tag"] {
+        match field(name) {
+            None | Some(Value::Null) | Some(Value::String(_)) => {}
+            Some(v) if is_string_list(v) => {}
+            Some(other) => problems.push(format!(
+                "`{}` must be a tag or a list of tags, got {}",
+                name,
+                describe(other)
+            )),
+        }
+    }
+    match field("aliases") {
+        None | Some(Value::Null) | Some(Value::String(_)) => {}
+        Some(v) if is_string_list(v) => {}
+        Some(other) => problems.push(format!(
+            "`aliases` must be a name or a list of names, got {}",
+            describe(other)
+        )),
+    }
+    for name in ["authors", "author"] {
+        match field(name) {
+            None => {}
+            Some(v) if is_string_list(v) => {}
+            Some(other) => problems.push(format!(
+                "`{}` must be a list of names, e.g. `- {}`, got {}",
+                name,
+                name,
+                describe(other)
+            )),
+        }
+    }
+    for name in ["subtitle", "id", "status"] {
+        match field(name) {
+            None | Some(Value::String(_)) => {}
+            Some(other) => {
+                problems.push(format!("`{}` must be text, got {}", name, describe(other)))
+            }
+        }
+    }
+    for (name, min, max) in [
+        ("weight", i32::MIN as i64, i32::MAX as i64),
+        ("views", i32::MIN as i64, i32::MAX as i64),
+        ("writes", 0, u16::MAX as i64),
+    ] {
+        match field(name) {
+            None => {}
+            Some(Value::Number(n)) if n.as_i64().map_or(false, |n| (min..=max).contains(&n)) => {}
+            Some(other) => problems.push(format!(
+                "`{}` must be a whole number from {} to {}, got {}",
+                name,
+                min,
+                max,
+                describe(other)
+            )),
+        }
+    }
+    problems
+}
+
+/// A YAML value as it'd read in an error message
+fn describe(value: &serde_yaml::Value) -> String {
+    use serde_yaml::Value;
+
+    match value {
+        Value::Null => String::from("nothing"),
+        Value::Bool(b) => format!("`{}`", b),
+        Value::Number(n) => format!("`{}`", n),
+        Value::String(s) => format!("`{}`", s),
+        Value::Sequence(_) => String::from("a list"),
+        Value::Mapping(_) => String::from("nested fields"),
+    }
+}
+
+#[cfg(test)]
+mod validate_frontmatter_tests {
+    use super::*;
+
+    fn problems(yaml: &str) -> Vec<String> {
+        validate_frontmatter(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[test]
+    fn valid() {
+        assert!(problems(
+            "title: Note\ndate: 2021-06-22T12:48:16-0400\ntags: rust\nauthors:\n- Steve\nweight: 3\n"
+        )
+        .is_empty());
+        assert!(problems("title: Note\ndate: 1624380496\ntags:\n- a\n- b\n").is_empty());
+    }
+
+    #[test]
+    fn missing_fields() {
+        assert_eq!(
+            vec![
+                "missing required field `title`",
+                "missing required field `date`"
+            ],
+            problems("tags: rust\n")
+        );
+    }
+
+    #[test]
+    fn bad_date() {
+        assert_eq!(
+            vec!["`date` must be RFC-3339 or epoch seconds, got `last tuesday`"],
+            problems("title: Note\ndate: last tuesday\n")
+        );
+        assert_eq!(
+            vec!["`date` must be RFC-3339 or epoch seconds, got a list"],
+            problems("title: Note\ndate:\n- 1624380496\n")
+        );
+    }
+
+    #[test]
+    fn type_mismatches() {
+        assert_eq!(
+            vec![
+                "`title` must be text, got a list",
+                "`tags` must be a tag or a list of tags, got nested fields",
+                "`author` must be a list of names, e.g. `- author`, got `Steve`",
+                "`writes` must be a whole number from 0 to 65535, got `lots`",
+            ],
+            problems("title:\n- a\ndate: 1624380496\ntags:\n  a: b\nauthor: Steve\nwrites: lots\n")
+        );
+    }
+
+    #[test]
+    fn not_a_mapping() {
+        assert_eq!(
+            vec!["frontmatter must be `key: value` fields, got `just text`"],
+            problems("just text")
+        );
+    }
+
+    #[test]
+    fn parse_file_reports_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("bad.md");
+        fs::write(&path, "---\ntitle: Note\ndate: soon\n---\n\nbody\n").unwrap();
+        let e = Document::parse_file(&path, false).unwrap_err();
+        assert_eq!(
+            format!(
+                "{}: `date` must be RFC-3339 or epoch seconds, got `soon`",
+                path.display()
+            ),
+            e.to_string()
+        );
+    }
+}
+
+/// Value slot holding each document's `modified` time, in epoch seconds
+pub const MODIFIED_SLOT: u32 = 0;
+
+/// Value slot holding each document's `date`, in epoch seconds, for `--since`/`--until` and
+/// `date:` ranges
+pub const DATE_SLOT: u32 = 1;
+
+/// `D` terms for the year, month and day of `date` in the local timezone, e.g. `D2021`,
+/// `D202106` and `D20210622`. Fixed width so that they don't depend on how dates are displayed.
+pub fn date_terms(date: &Date) -> Vec<String> {
+    let day_format = DateFormat {
+        offset: None,
+        format: Some(String::from("%Y%m%d")),
+    };
+    let day = with_date_format(day_format, || date.to_string());
+    [4, 6, 8]
+        .iter()
+        .map(|len| format!("D{}", &day[..*len]))
+        .collect()
+}
+
+/// Prefix of the boolean terms marking which fields a note has any of
+pub const HAS_PREFIX: &str = "XHAS";
+
+/// The boolean term marking that a note has something indexed under `prefix`, e.g. any tags
+/// for `K`, so that `tag:none` can find the notes without it
+pub fn has_term(prefix: &str) -> String {
+    format!("{}{}", HAS_PREFIX, prefix)
+}
+
+/// The boolean term for a note's `status`, folded to lower case, or none without a status so
+/// that it matches no `status:` filter
+pub fn status_term(status: &str) -> Option<String> {
+    let status = status.trim();
+    if status.is_empty() {
+        None
+    } else {
+        Some(format!("XST{}", status.to_lowercase()))
+    }
+}
+
+/// The boolean term for the notebook a note is in, folded to lower case, or none outside of any
+pub fn notebook_term(notebook: &str) -> Option<String> {
+    if notebook.is_empty() {
+        None
+    } else {
+        Some(format!("XNB{}", notebook.to_lowercase()))
+    }
+}
+
+/// Fold `tag` so that tags differing only in case or accents match, e.g. `Café` and `cafe`. Used
+/// both when indexing tags and on `tag:` searches.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// `tags` tidied up: trimmed, with runs of whitespace inside a tag squeezed to one space, and
+/// without empty tags or any repeating an earlier one up to `normalize_tag`. With `lowercase`
+/// the tags kept are lowercased too.
+pub fn clean_tags(tags: Vec<String>, lowercase: bool) -> Vec<String> {
+    let mut seen = HashSet::new();
+    tags.into_iter()
+        .map(|tag| tag.split_whitespace().collect::<Vec<_>>().join(" "))
+        .map(|tag| if lowercase { tag.to_lowercase() } else { tag })
+        .filter(|tag| !tag.is_empty() && seen.insert(normalize_tag(tag)))
+        .collect()
+}
+
+/// The tags `tag` is indexed as: itself, normalized, and with `hierarchical` each of its
+/// ancestors too, e.g. `project`, `project/apollo` and `project/apollo/launch`
+pub fn tag_terms(tag: &str, hierarchical: bool) -> Vec<String> {
+    let tag = normalize_tag(tag);
+    let parts: Vec<&str> = tag.split('/').filter(|p| !p.is_empty()).collect();
+    if parts.is_empty() {
+        return vec![tag];
+    }
+    let first = if hierarchical { 1 } else { parts.len() };
+    (first..=parts.len())
+        .map(|end| parts[..end].join("/"))
+        .collect()
+}
+
+/// Whether `tag` is indexed as a single term rather than split into words: nested tags like
+/// `project/apollo`, and tags like emoji without a letter or digit for Xapian to pick out
+pub fn indexed_whole(tag: &str) -> bool {
+    tag.contains('/') || !tag.chars().any(char::is_alphanumeric)
+}
+
+/// `text`, a note's source, with `tag` added at the end of its frontmatter's tags and everything
+/// else, the body in particular, left as it was. `None` when the note is tagged `tag` already, up
+/// to `normalize_tag`.
+pub fn add_tag(text: &str, tag: &str) -> Result<Option<String>, Report> {
+    use serde_yaml::Value;
+
+    let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ");
+    if tag.is_empty() {
+        return Err(eyre::eyre!("No tag to add"));
+    }
+    let rest = text
+        .strip_prefix("---\n")
+        .ok_or_else(|| eyre::eyre!("The note has no frontmatter to add a tag to"))?;
+    let end = match rest.strip_prefix("---") {
+        Some(_) => 0,
+        None => rest
+            .find("\n---")
+            .map(|i| i + 1)
+            .ok_or_else(|| eyre::eyre!("The note's frontmatter is never closed"))?,
+    };
+    let (yaml, after) = rest.split_at(end);
+
+    let tags_of = |yaml: &str| -> Result<Vec<String>, Report> {
+        let value: Value = serde_yaml::from_str(yaml).wrap_err("while reading the frontmatter")?;
+        let tags = match (value.get("tags"), value.get("tag")) {
+            (Some(tags), _) | (None, Some(tags)) => tags.clone(),
+            (None, None) => Value::Null,
+        };
+        Ok(match tags {
+            Value::Sequence(items) => items
+                .iter()
+                .filter_map(|item| item.as_str().map(String::from))
+                .collect(),
+            Value::String(tag) => vec![tag],
+            _ => Vec::new(),
+        })
+    };
+    if tags_of(yaml)?
+        .iter()
+        .any(|t| normalize_tag(t.trim()) == normalize_tag(&tag))
+    {
+        return Ok(None);
+    }
+
+    // Quoted unless YAML would read it back as the same string, in a flow list too
+    let scalar = match serde_yaml::from_str::<Value>(&tag) {
+        Ok(Value::String(s)) if s == tag && !tag.contains(|c| ",[]{}#".contains(c)) => tag.clone(),
+        _ => format!("\"{}\"", tag.replace('\\', "\\\\").replace('"', "\\\"")),
+    };
+    let mut lines: Vec<String> = yaml.lines().map(String::from).collect();
+    let key = lines
+        .iter()
+        .position(|l| l.starts_with("tags:") || l.starts_with("tag:"));
+    match key {
+        None => {
+            lines.push(String::from("tags:"));
+            lines.push(format!("- {}", scalar));
+        }
+        Some(i) => {
+            let (name, value) = lines[i].split_once(':').unwrap();
+            let (name, value) = (name.to_string(), value.trim().to_string());
+            if value.is_empty() {
+                // A block list, possibly empty, add an item after the last one
+                let mut end = i + 1;
+                while end < lines.len() && lines[end].trim_start().starts_with('-') {
+                    end += 1;
+                }
+                let indent = if end > i + 1 {
+                    let last = &lines[end - 1];
+                    last[..last.len() - last.trim_start().len()].to_string()
+                } else {
+                    String::new()
+                };
+                lines.insert(end, format!("{}- {}", indent, scalar));
+            } else if let Some(items) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                lines[i] = match items.trim() {
+                    "" => format!("{}: [{}]", name, scalar),
+                    items => format!("{}: [{}, {}]", name, items, scalar),
+                };
+            } else {
+                lines[i] = format!("{}: [{}, {}]", name, value, scalar);
+            }
+        }
+    }
+    let yaml: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+
+    // Frontmatter laid out in some way not handled above is left alone rather than mangled
+    if !tags_of(&yaml).map_or(false, |tags| tags.contains(&tag)) {
+        return Err(eyre::eyre!(
+            "Couldn't add a tag to this note's frontmatter, edit it instead"
+        ));
+    }
+    Ok(Some(format!("---\n{}{}", yaml, after)))
+}
+
+#[cfg(test)]
+mod normalize_tag_tests {
+    use super::*;
+    #[test]
+    fn case_and_accents() {
+        assert_eq!("rust", normalize_tag("Rust"));
+        assert_eq!("rust", normalize_tag("RÚST"));
+        assert_eq!("cafe", normalize_tag("café"));
+        assert_eq!("project x", normalize_tag("Project X"));
+    }
+
+    #[test]
+    fn cleaned() {
+        let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let messy = tags(&[
+            "Rust",
+            "rust",
+            " rust ",
+            "",
+            "machine \t  learning",
+            "Café",
+            "cafe",
+        ]);
+        assert_eq!(
+            tags(&["Rust", "machine learning", "Café"]),
+            clean_tags(messy.clone(), false)
+        );
+        assert_eq!(
+            tags(&["rust", "machine learning", "café"]),
+            clean_tags(messy, true)
+        );
+    }
+
+    #[test]
+    fn nested() {
+        assert_eq!(vec!["a/b/c"], tag_terms("A/B/C", false));
+        assert_eq!(vec!["a", "a/b", "a/b/c"], tag_terms("A/B/C", true));
+        assert_eq!(vec!["a/b"], tag_terms("/a//b/", false));
+        assert_eq!(vec!["rust"], tag_terms("rust", true));
+    }
+}
+
+#[cfg(test)]
+mod add_tag_tests {
+    use super::*;
+
+    const BODY: &str = "---\n\n# Heading\n\ntags: [not, frontmatter]\n";
+
+    fn added(frontmatter: &str, tag: &str) -> String {
+        let text = format!("---\n{}---\n{}", frontmatter, BODY);
+        let out = add_tag(&text, tag).unwrap().unwrap();
+        let (front, body) = out[4..].split_once("---\n").unwrap();
+        assert_eq!(BODY, body);
+        front.to_string()
+    }
+
+    #[test]
+    fn each_layout() {
+        assert_eq!(
+            "title: Note\ntags:\n  - rust\n  - vim\ndate: 2021-06-22\n",
+            added("title: Note\ntags:\n  - rust\ndate: 2021-06-22\n", "vim")
+        );
+        assert_eq!(
+            "title: Note\ntags: [rust, vim]\n",
+            added("title: Note\ntags: [rust]\n", "vim")
+        );
+        assert_eq!("tags: [vim]\n", added("tags: []\n", "vim"));
+        assert_eq!("tag: [rust, vim]\n", added("tag: rust\n", "vim"));
+        assert_eq!("tags:\n- vim\n", added("tags:\n", "vim"));
+        assert_eq!("title: Note\ntags:\n- vim\n", added("title: Note\n", "vim"));
+        assert_eq!(
+            "tags: [rust, \"c#, .net\"]\n",
+            added("tags: [rust]\n", " c#,  .net ")
+        );
+    }
+
+    #[test]
+    fn already_tagged() {
+        let text = "---\ntitle: Note\ntags:\n- Café\n---\nbody\n";
+        assert_eq!(None, add_tag(text, "cafe").unwrap());
+        assert_eq!(None, add_tag("---\ntags: rust\n---\n", "Rust").unwrap());
+    }
+
+    #[test]
+    fn unusable() {
+        assert!(add_tag("# No frontmatter\n", "rust").is_err());
+        assert!(add_tag("---\ntitle: Never closed\n", "rust").is_err());
+        assert!(add_tag("---\ntags: []\n---\n", "  ").is_err());
+        // Flow lists over several lines aren't edited
+        assert!(add_tag("---\ntags: [rust,\n  vim]\n---\n", "go").is_err());
+    }
+}
+
+#[cfg(test)]
+mod snippet_tests {
+    use super::*;
+
+    fn doc(body: &str) -> Document {
+        Document {
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn terms(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn centered() {
+        let doc = doc("the quick brown fox jumps over the lazy dog");
+        assert_eq!("…rown fox jump…", doc.snippet(&terms(&["fox"]), 15));
+        // The earliest of several terms
+        assert_eq!("…rown fox jump…", doc.snippet(&terms(&["lazy", "fox"]), 15));
+    }
+
+    #[test]
+    fn start_of_doc() {
+        let doc = doc("Rust notes\n\nabout the borrow checker");
+        assert_eq!("Rust notes ab…", doc.snippet(&terms(&["rust"]), 14));
+        // Near the end the window stays full width
+        assert_eq!("…orrow checker", doc.snippet(&terms(&["checker"]), 14));
+        assert_eq!(
+            "Rust notes about the borrow checker",
+            doc.snippet(&terms(&["borrow"]), 80)
+        );
+    }
+
+    #[test]
+    fn no_match() {
+        let doc = doc("the quick brown fox");
+        assert_eq!("the quick…", doc.snippet(&terms(&["cat"]), 10));
+        assert_eq!("the quick…", doc.snippet(&[], 10));
+        assert_eq!("", doc.snippet(&[], 0));
+        assert_eq!("", Document::new().snippet(&terms(&["fox"]), 10));
+    }
+
+    #[test]
+    fn multibyte() {
+        let doc = doc("naïve café au lait, très bien");
+        assert_eq!("… au lait, tr…", doc.snippet(&terms(&["lait"]), 14));
+        assert_eq!("naïve café…", doc.snippet(&terms(&["nothing"]), 11));
+    }
+}
+
+#[cfg(test)]
+mod parse_file_tests {
+    use super::*;
+
+    #[test]
+    fn heading_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("plain.md");
+        let content = "Intro line\n\n# Plain note\n\nSome text\n## Section\n";
+        fs::write(&path, content).unwrap();
+
+        assert!(Document::parse_file(&path, false).is_err());
+
+        let doc = Document::parse_file(&path, true).expect("Failed to infer frontmatter");
+        assert_eq!("Plain note", doc.title);
+        assert_eq!(content, doc.body);
+        assert!(doc.tags.is_empty());
+        assert!(!doc.id.is_empty());
+        assert_eq!(path.to_string_lossy(), doc.fullpath);
+        assert_ne!(Date::default(), doc.date);
+    }
+
+    #[test]
+    fn body_line() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("note.md");
+        fs::write(
+            &path,
+            "---\ntitle: Lines\ndate: 2021-06-22T12:48:16-0400\ntags:\n- rust\n- vim\n---\nFirst body line\n\nSecond\n",
+        )
+        .unwrap();
+        let doc = Document::parse_file(&path, false).unwrap();
+        assert_eq!(8, doc.body_line);
+        assert!(doc.body.starts_with("First body line"));
+
+        fs::write(&path, "# Plain\n").unwrap();
+        assert_eq!(1, Document::parse_file(&path, true).unwrap().body_line);
+    }
+
+    #[test]
+    fn messy_tags() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("tagged.md");
+        fs::write(
+            &path,
+            "---\ntitle: Tagged\ndate: 2021-06-22T12:48:16-0400\ntags: [rust, Rust, \"rust \", \" vim  tips\"]\n---\n\nbody\n",
+        )
+        .unwrap();
+
+        let doc = Document::parse_file(&path, false).unwrap();
+        assert_eq!(vec!["rust", "vim tips"], doc.tags);
+    }
+
+    #[test]
+    fn empty_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("empty-note.md");
+        fs::write(&path, "").unwrap();
+
+        assert!(Document::parse_file(&path, false).is_err());
+
+        let doc = Document::parse_file(&path, true).expect("Failed to infer frontmatter");
+        assert_eq!("empty-note", doc.title);
+        assert_eq!("", doc.body);
+    }
+
+    #[test]
+    fn extra_frontmatter_round_trips() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("note.md");
+        fs::write(
+            &path,
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: Aliased\naliases:\n- other name\n- third name\npriority: high\n---\n\nbody\n",
+        )
+        .unwrap();
+
+        let mut doc = Document::parse_file(&path, false).unwrap();
+        // `aliases` has a field of its own
+        assert_eq!(vec!["other name", "third name"], doc.aliases);
+        assert_eq!(None, doc.extra.get("aliases"));
+        assert_eq!(
+            Some(&serde_yaml::Value::from("high")),
+            doc.extra.get("priority")
+        );
+
+        // Written back out to disk
+        doc.serialization_type = SerializationType::Disk;
+        fs::write(&path, doc.to_string()).unwrap();
+        let reparsed = Document::parse_file(&path, false).unwrap();
+        assert_eq!(doc.extra, reparsed.extra);
+        assert_eq!(doc.aliases, reparsed.aliases);
+        assert_eq!("Aliased", reparsed.title);
+
+        // And through the index's stored JSON
+        let stored: Document =
+            serde_json::from_str(&serde_json::to_string(&reparsed).unwrap()).unwrap();
+        assert_eq!(doc.extra, stored.extra);
+        assert_eq!(doc.aliases, stored.aliases);
+    }
+
+    #[test]
+    fn fixed_width_date_terms() {
+        use std::str::FromStr;
+        let date = Date::from_str("2021-06-22T12:00:00+00:00").unwrap();
+        let day = with_date_format(DateFormat::new("local", "%Y%m%d").unwrap(), || {
+            date.to_string()
+        });
+        assert_eq!(
+            vec![
+                format!("D{}", &day[..4]),
+                format!("D{}", &day[..6]),
+                format!("D{}", day)
+            ],
+            date_terms(&date)
+        );
+        assert_eq!(8, day.len());
+    }
+
+    #[test]
+    fn modified_from_mtime() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("note.md");
+        fs::write(
+            &path,
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: Note\n---\n\nbody\n",
+        )
+        .unwrap();
+
+        let doc = Document::parse_file(&path, false).unwrap();
+        assert_eq!(file_modified(&path).unwrap(), doc.modified);
+        assert_ne!(doc.date, doc.modified);
+
+        // Frontmatter wins over the file
+        fs::write(
+            &path,
+            "---\ndate: 2021-06-22T12:48:16-0400\nmodified: 1624380496\ntitle: Note\n---\n",
+        )
+        .unwrap();
+        let doc = Document::parse_file(&path, false).unwrap();
+        assert_eq!(Date::new(1624380496), doc.modified);
+    }
+
+    #[test]
+    fn frontmatter_wins() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("note.md");
+        fs::write(
+            &path,
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: From yaml\n---\n\n# From heading\n",
+        )
+        .unwrap();
+
+        let doc = Document::parse_file(&path, true).unwrap();
+        assert_eq!("From yaml", doc.title);
+    }
+}
+
+#[cfg(test)]
+mod serialization_tests {
+    use super::*;
+
+    // The JSON keys of `doc` serialized as `serialization_type`
+    fn keys(doc: &Document, serialization_type: SerializationType) -> Vec<String> {
+        let json =
+            serde_json::to_value(doc.clone().with_serialization(serialization_type)).unwrap();
+        json.as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn projections() {
+        let mut doc = Document::new();
+        doc.title = String::from("Note");
+        doc.fullpath = String::from("/notes/note.md");
+        doc.body = String::from("body");
+        doc.extra
+            .insert(String::from("priority"), serde_yaml::Value::from("high"));
+
+        let frontmatter = [
+            "authors", "date", "id", "modified", "priority", "tags", "title", "weight", "writes",
+        ];
+        let mut disk: Vec<&str> = frontmatter.to_vec();
+        disk.sort_unstable();
+        assert_eq!(disk, keys(&doc, SerializationType::Disk));
+
+        let mut human: Vec<&str> = [&frontmatter[..], &["body", "fullpath"]].concat();
+        human.sort_unstable();
+        assert_eq!(human, keys(&doc, SerializationType::Human));
+
+        let mut storage: Vec<&str> = [
+            &frontmatter[..],
+            &["body", "content_hash", "file_modified", "fullpath"],
+        ]
+        .concat();
+        storage.sort_unstable();
+        assert_eq!(storage, keys(&doc, SerializationType::Storage));
+
+        assert!(keys(&doc, SerializationType::Preview).is_empty());
+    }
+
+    #[test]
+    fn projection_names() {
+        assert_eq!(
+            SerializationType::Disk,
+            "disk".parse::<SerializationType>().unwrap()
+        );
+        assert_eq!(
+            SerializationType::Human,
+            "Human".parse::<SerializationType>().unwrap()
+        );
+        assert!("preview".parse::<SerializationType>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod update_index_tests {
+    use super::*;
+    use crate::interactive::xapian_utils::test_utils::{index_documents, note, titles};
+    use crate::interactive::xapian_utils::{all_documents, parse_user_query, query_db};
+    use xapian_rusty::{Database, Stem, DB_CREATE_OR_OPEN, GLASS};
+
+    #[test]
+    fn same_basename_different_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().to_string_lossy().to_string();
+        {
+            let mut db = WritableDatabase::new(&db_path, GLASS, DB_CREATE_OR_OPEN).unwrap();
+            let mut tg = TermGenerator::new().unwrap();
+            let mut stem = Stem::new("en").unwrap();
+            tg.set_stemmer(&mut stem).unwrap();
+            for dir in ["work", "home"] {
+                let mut doc = Document::new();
+                doc.fullpath = format!("/notes/{}/index.md", dir);
+                doc.title = format!("{} index", dir);
+                doc.update_index(&mut db, &mut tg).unwrap();
+            }
+            // Re-indexing a file replaces its entry rather than adding another
+            let mut doc = Document::new();
+            doc.fullpath = String::from("/notes/work/index.md");
+            doc.title = String::from("work index, updated");
+            doc.update_index(&mut db, &mut tg).unwrap();
+            db.commit().unwrap();
+        }
+
+        let mut db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN).unwrap();
+        let mut titles: Vec<String> = all_documents(&mut db)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.title)
+            .collect();
+        titles.sort();
+        assert_eq!(vec!["home index", "work index, updated"], titles);
+    }
+
+    #[test]
+    fn filename_and_fullpath() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().to_string_lossy().to_string();
+        {
+            let mut db = WritableDatabase::new(&db_path, GLASS, DB_CREATE_OR_OPEN).unwrap();
+            let mut tg = TermGenerator::new().unwrap();
+            let mut stem = Stem::new("en").unwrap();
+            tg.set_stemmer(&mut stem).unwrap();
+            let mut doc = Document::new();
+            doc.fullpath = String::from("/notes/projects/apollo-launch.md");
+            doc.title = String::from("Countdown");
+            doc.update_index(&mut db, &mut tg).unwrap();
+            db.commit().unwrap();
+        }
+
+        let mut db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN).unwrap();
+        let mut found = |query: &str| {
+            query_db(
+                db.new_enquire().unwrap(),
+                parse_user_query(&format!("{} ;", query)).unwrap(),
+                SerializationType::Preview,
+                10,
+                0,
+            )
+            .unwrap()
+            .len()
+        };
+        assert_eq!(1, found("filename:apollo"));
+        assert_eq!(1, found("fullpath:apollo"));
+        // The parent directory is only part of the full path
+        assert_eq!(1, found("fullpath:projects"));
+        assert_eq!(0, found("filename:projects"));
+    }
+
+    #[test]
+    fn each_author() {
+        let tmp = tempfile::tempdir().unwrap();
+        let note = tmp.path().join("note.md");
+        fs::write(
+            &note,
+            "---\nauthors: [Steve, Alice]\ndate: 2021-06-22T12:48:16-0400\ntitle: Shared\n---\n\nbody\n",
+        )
+        .unwrap();
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        let doc = Document::parse_file(&note, false).unwrap();
+        assert_eq!(2, doc.authors.iter().count());
+        index_documents(&db_path, &[doc]);
+
+        for author in ["steve", "alice", "Alice"] {
+            let query = format!("author:{}", author);
+            assert_eq!(vec!["Shared"], titles(&db_path, &query), "{}", query);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().to_string_lossy().to_string();
+        let mut doc = note(0, "", &[]);
+        doc.title = String::from("Kubernetes");
+        doc.aliases = vec![String::from("k8s"), String::from("container orchestration")];
+        index_documents(&db_path, &[doc]);
+
+        for query in ["k8s", "title:k8s", "title:orchestration", "kubernetes"] {
+            assert_eq!(vec!["Kubernetes"], titles(&db_path, query), "{}", query);
+        }
+    }
+
+    // Titles of the notes tagged `project/apollo/launch` and `project` matching each query, with
+    // tags indexed hierarchically or not
+    fn nested_tag_matches(hierarchical: bool, queries: &[&str]) -> Vec<Vec<String>> {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().to_string_lossy().to_string();
+        {
+            let mut db = WritableDatabase::new(&db_path, GLASS, DB_CREATE_OR_OPEN).unwrap();
+            let mut tg = TermGenerator::new().unwrap();
+            let mut stem = Stem::new("en").unwrap();
+            tg.set_stemmer(&mut stem).unwrap();
+            for (name, tag) in [("launch", "Project/Apollo/Launch"), ("project", "project")] {
+                let mut doc = Document::new();
+                doc.fullpath = format!("/notes/{}.md", name);
+                doc.title = String::from(name);
+                doc.tags = vec![String::from(tag)];
+                doc.update_index_with(
+                    &mut db,
+                    &mut tg,
+                    false,
+                    &[],
+                    hierarchical,
+                    TextIndexing::default(),
+                )
+                .unwrap();
+            }
+            db.commit().unwrap();
+        }
+
+        let mut db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN).unwrap();
+        queries
+            .iter()
+            .map(|query| {
+                let mut titles: Vec<String> = query_db(
+                    db.new_enquire().unwrap(),
+                    parse_user_query(&format!("{} ;", query)).unwrap(),
+                    SerializationType::Preview,
+                    10,
+                    0,
+                )
+                .unwrap()
+                .into_iter()
+                .map(|d| d.title)
+                .collect();
+                titles.sort();
+                titles
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hierarchical_tags() {
+        let found = nested_tag_matches(
+            true,
+            &[
+                "tag:project",
+                "tag:project/apollo",
+                "tag:project/apollo/launch",
+                "tag:apollo",
+            ],
+        );
+        assert_eq!(
+            vec![
+                vec!["launch", "project"],
+                vec!["launch"],
+                vec!["launch"],
+                vec![],
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn exact_tags() {
+        let found = nested_tag_matches(
+            false,
+            &[
+                "tag:project",
+                "tag:project/apollo",
+                "tag:project/apollo/launch",
+            ],
+        );
+        assert_eq!(vec![vec!["project"], vec![], vec!["launch"]], found);
+    }
+
+    #[test]
+    fn status() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().to_string_lossy().to_string();
+        let docs: Vec<Document> = [("shipped", "Active"), ("plan", "draft"), ("idea", "")]
+            .iter()
+            .enumerate()
+            .map(|(i, (name, status))| {
+                let mut doc = note(i, "", &[]);
+                doc.title = name.to_string();
+                doc.status = status.to_string();
+                doc
+            })
+            .collect();
+        index_documents(&db_path, &docs);
+
+        // Exact, case-insensitive matches, either of several statuses
+        assert_eq!(vec!["shipped"], titles(&db_path, "status:active"));
+        assert_eq!(
+            vec!["plan", "shipped"],
+            titles(&db_path, "status:active status:draft")
+        );
+        assert_eq!(vec!["shipped"], titles(&db_path, "shipped status:active"));
+        assert_eq!(Vec::<String>::new(), titles(&db_path, "plan status:active"));
+        assert_eq!(Vec::<String>::new(), titles(&db_path, "status:act"));
+        // A note without a status matches no status
+        assert_eq!(
+            Vec::<String>::new(),
+            titles(&db_path, "idea status:archived")
+        );
+        assert_eq!(
+            vec!["idea", "plan"],
+            titles(&db_path, "idea plan -status:active")
+        );
+    }
+
+    #[test]
+    fn missing_tags_and_authors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().to_string_lossy().to_string();
+        let docs: Vec<Document> = [
+            ("tagged", vec!["vim"], vec!["Steve"]),
+            ("untagged", vec![], vec!["Steve"]),
+            ("bare", vec![], vec![]),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, (name, tags, authors))| {
+            let mut doc = note(i, "rust", tags);
+            doc.title = name.to_string();
+            doc.authors = authors.iter().map(|a| a.to_string()).collect();
+            doc
+        })
+        .collect();
+        index_documents(&db_path, &docs);
+
+        assert_eq!(vec!["bare", "untagged"], titles(&db_path, "tag:none"));
+        assert_eq!(vec!["bare", "untagged"], titles(&db_path, "TAG:None"));
+        assert_eq!(vec!["bare"], titles(&db_path, "author:none"));
+        assert_eq!(vec!["bare"], titles(&db_path, "tag:none author:none"));
+        // Narrowing down other words rather than adding to them
+        assert_eq!(
+            vec!["untagged"],
+            titles(&db_path, "untagged tagged tag:none author:steve")
+        );
+        assert_eq!(vec!["tagged"], titles(&db_path, "rust -tag:none"));
+        assert_eq!(vec!["tagged"], titles(&db_path, "-tag:none"));
+    }
+}
+
 /// Support Deserializing a string into a list of string of length 1
 fn string_or_list_string<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
 where
@@ -250,35 +1667,58 @@ impl Serialize for Document {
     where
         S: Serializer,
     {
+        // A map rather than a struct, since the extra frontmatter keys aren't known up front
         let mut s = match self.serialization_type {
-            SerializationType::Storage => serializer.serialize_struct("Document", 14)?,
-            SerializationType::Disk => serializer.serialize_struct("Document", 12)?,
-            SerializationType::Human | SerializationType::Preview => {
+            SerializationType::Storage | SerializationType::Disk | SerializationType::Human => {
+                serializer.serialize_map(None)?
+            }
+            SerializationType::Preview => {
                 // The Display trait implementation above handles displaying just the
                 // document body, don't need to serialize any of the doc metadata
-                return serializer.serialize_struct("Document", 0)?.end();
+                return serializer.serialize_map(Some(0))?.end();
             }
         };
 
-        s.serialize_field("title", &self.title)?;
+        s.serialize_entry("title", &self.title)?;
         if self.subtitle.width() > 0 {
-            s.serialize_field("subtitle", &self.subtitle)?;
+            s.serialize_entry("subtitle", &self.subtitle)?;
         };
+        if !self.aliases.is_empty() {
+            s.serialize_entry("aliases", &self.aliases)?;
+        }
         if self.serialization_type == SerializationType::Storage {
-            s.serialize_field("date", &self.date)?;
+            s.serialize_entry("date", &self.date)?;
+            s.serialize_entry("modified", &self.modified)?;
         } else {
-            s.serialize_field("date", &format!("{}", &self.date))?;
+            s.serialize_entry("date", &format!("{}", &self.date))?;
+            s.serialize_entry("modified", &format!("{}", &self.modified))?;
         }
-        s.serialize_field("tags", &self.tags)?;
-        if self.serialization_type == SerializationType::Storage {
-            s.serialize_field("fullpath", &self.fullpath)?;
+        s.serialize_entry("tags", &self.tags)?;
+        if !self.status.is_empty() {
+            s.serialize_entry("status", &self.status)?;
+        }
+        if self.serialization_type != SerializationType::Disk {
+            s.serialize_entry("fullpath", &self.fullpath)?;
+            if !self.notebook.is_empty() {
+                s.serialize_entry("notebook", &self.notebook)?;
+            }
+            if self.duplicates > 0 {
+                s.serialize_entry("duplicates", &self.duplicates)?;
+            }
         };
-        s.serialize_field("authors", &self.authors)?;
-        s.serialize_field("id", &self.id)?;
-        s.serialize_field("weight", &self.weight)?;
-        s.serialize_field("writes", &self.writes)?;
+        s.serialize_entry("authors", &self.authors)?;
+        s.serialize_entry("id", &self.id)?;
+        s.serialize_entry("weight", &self.weight)?;
+        s.serialize_entry("writes", &self.writes)?;
+        for (key, value) in &self.extra {
+            s.serialize_entry(key, value)?;
+        }
         if self.serialization_type == SerializationType::Storage {
-            s.serialize_field("body", &self.body)?;
+            s.serialize_entry("file_modified", &self.file_modified)?;
+            s.serialize_entry("content_hash", &self.content_hash)?;
+        }
+        if self.serialization_type != SerializationType::Disk {
+            s.serialize_entry("body", &self.body)?;
         }
         s.end()
     }
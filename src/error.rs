@@ -0,0 +1,117 @@
+use std::{error, fmt, io};
+
+/// Why a call to the library failed, so that callers can tell a bad query from a broken index.
+/// The `mdq` binary turns these into `color_eyre::Report`s like any other error.
+#[derive(Debug)]
+pub enum MdqError {
+    /// The index couldn't be opened, e.g. it doesn't exist or another process is writing to it
+    DbOpen(String),
+    /// The query couldn't be parsed
+    QueryParse(String),
+    /// Reading from or writing to an open index failed
+    Index(String),
+    /// A file or directory couldn't be read
+    Io(io::Error),
+    /// A note's frontmatter is unusable, e.g. its id is already another note's
+    Frontmatter(String),
+}
+
+impl MdqError {
+    pub(crate) fn db_open(e: impl fmt::Display) -> MdqError {
+        MdqError::DbOpen(message(e))
+    }
+
+    pub(crate) fn query_parse(e: impl fmt::Display) -> MdqError {
+        MdqError::QueryParse(message(e))
+    }
+
+    pub(crate) fn index(e: impl fmt::Display) -> MdqError {
+        MdqError::Index(message(e))
+    }
+}
+
+// `e` along with whatever caused it, for errors which are `Report`s with context added
+fn message(e: impl fmt::Display) -> String {
+    format!("{:#}", e)
+}
+
+impl fmt::Display for MdqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MdqError::DbOpen(msg)
+            | MdqError::QueryParse(msg)
+            | MdqError::Index(msg)
+            | MdqError::Frontmatter(msg) => write!(f, "{}", msg),
+            MdqError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for MdqError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MdqError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MdqError {
+    fn from(e: io::Error) -> MdqError {
+        MdqError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod mdq_error_tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::search::{search, SearchOptions};
+    use crate::Indexer;
+    use std::fs;
+
+    #[test]
+    fn variants() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+
+        let e = search(&db_path, "rust", SearchOptions::default()).unwrap_err();
+        assert!(matches!(e, MdqError::DbOpen(_)), "{:?}", e);
+
+        let mut indexer = Indexer::open(&db_path).unwrap();
+        let e = indexer.index_path(&tmp.path().join("missing")).unwrap_err();
+        assert!(
+            matches!(&e, MdqError::Io(io) if io.kind() == io::ErrorKind::NotFound),
+            "{:?}",
+            e
+        );
+
+        // Xapian refuses terms this long, here the path's directory
+        let mut doc = Document::new();
+        doc.fullpath = format!("/{}/note.md", "a".repeat(300));
+        let e = indexer.index_document(&doc).unwrap_err();
+        assert!(matches!(e, MdqError::Index(_)), "{:?}", e);
+
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(&notes).unwrap();
+        for name in ["original.md", "copy.md"] {
+            fs::write(
+                notes.join(name),
+                "---\nid: abc123\ntitle: Copied\ndate: 2021-06-22T12:48:16-0400\n---\n\nrust\n",
+            )
+            .unwrap();
+        }
+        indexer.fail_on_duplicate = true;
+        let e = indexer.index_path(&notes).unwrap_err();
+        assert!(matches!(e, MdqError::Frontmatter(_)), "{:?}", e);
+        indexer.commit().unwrap();
+
+        let opts = SearchOptions {
+            lang: String::from("klingon"),
+            ..SearchOptions::default()
+        };
+        let e = search(&db_path, "rust", opts).unwrap_err();
+        assert!(matches!(e, MdqError::QueryParse(_)), "{:?}", e);
+        assert!(e.to_string().contains("klingon"), "{}", e);
+    }
+}
@@ -0,0 +1,1181 @@
+use crate::document::{clean_tags, Document, TextIndexing};
+use crate::error::MdqError;
+use crate::interactive::xapian_utils::CustomField;
+use color_eyre::Report;
+use eyre::{eyre, Result};
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+use xapian_rusty::{
+    Stem, TermGenerator, WritableDatabase, BRASS, DB_CREATE_OR_OPEN, DB_CREATE_OR_OVERWRITE, GLASS,
+    UNKNOWN,
+};
+
+/// Xapian storage format to create an index with. Reading an index detects its format, but
+/// only Xapian builds which have the format compiled in can open it, and brass isn't in Xapian
+/// 1.4 onwards, so an index should be written with whatever its readers support.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// The format of current Xapian releases
+    Glass,
+    /// Xapian 1.3's development format, which only Xapian 1.3 builds can read
+    Brass,
+    /// Whatever the linked Xapian creates by default
+    Default,
+}
+
+impl Default for Backend {
+    fn default() -> Backend {
+        Backend::Glass
+    }
+}
+
+impl Backend {
+    fn to_xapian(self) -> i8 {
+        match self {
+            Backend::Glass => GLASS,
+            Backend::Brass => BRASS,
+            Backend::Default => UNKNOWN,
+        }
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Backend, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "glass" => Ok(Backend::Glass),
+            "brass" => Ok(Backend::Brass),
+            "default" => Ok(Backend::Default),
+            _ => Err(eyre!(
+                "Unknown backend '{}', expected glass, brass or default",
+                s
+            )),
+        }
+    }
+}
+
+/// How an update tells that a file already indexed hasn't changed, and can be skipped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeDetection {
+    /// The file's modification time is the one it was indexed with
+    Mtime,
+    /// The file's contents hash the same as when it was indexed, for vaults synced by tools
+    /// that don't keep modification times
+    Hash,
+}
+
+impl std::str::FromStr for ChangeDetection {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<ChangeDetection, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mtime" => Ok(ChangeDetection::Mtime),
+            "hash" => Ok(ChangeDetection::Hash),
+            _ => Err(eyre!(
+                "Unknown change detection '{}', expected mtime or hash",
+                s
+            )),
+        }
+    }
+}
+
+/// A directory of notes searchable on its own as `notebook:name`, e.g. one of several vaults
+/// sharing an index
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notebook {
+    pub name: String,
+    pub root: PathBuf,
+}
+
+impl std::str::FromStr for Notebook {
+    type Err = Report;
+
+    /// `NAME=DIR`, e.g. `work=~/notes/work`
+    fn from_str(s: &str) -> Result<Notebook, Self::Err> {
+        match s.split_once('=') {
+            Some((name, root)) if !name.trim().is_empty() && !root.is_empty() => Ok(Notebook {
+                name: name.trim().to_string(),
+                root: PathBuf::from(shellexpand::tilde(root).as_ref()),
+            }),
+            _ => Err(eyre!(
+                "Invalid notebook '{}', expected NAME=DIR, e.g. work=~/notes/work",
+                s
+            )),
+        }
+    }
+}
+
+/// The name of the notebook `path` is in, the one with the deepest root above it, or empty if
+/// it's in none
+pub fn notebook_for(notebooks: &[Notebook], path: &Path) -> String {
+    // Roots and paths written differently, e.g. relative and absolute, still compare equal
+    let canonical = |p: &Path| p.canonicalize().unwrap_or_else(|_| p.to_path_buf());
+    let path = canonical(path);
+    notebooks
+        .iter()
+        .map(|notebook| (notebook, canonical(&notebook.root)))
+        .filter(|(_, root)| path.starts_with(root))
+        .max_by_key(|(_, root)| root.components().count())
+        .map(|(notebook, _)| notebook.name.clone())
+        .unwrap_or_default()
+}
+
+/// A stage of indexing timed by `Profile`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    /// Finding markdown files
+    Walk,
+    /// Reading and parsing them
+    Parse,
+    /// Handing them to Xapian
+    Index,
+    /// Writing the index to disk
+    Commit,
+}
+
+/// Wall-clock time an `Indexer` spent in each stage, see `Indexer::profile`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub walk: Duration,
+    pub parse: Duration,
+    pub index: Duration,
+    pub commit: Duration,
+    /// Notes indexed
+    pub docs: usize,
+}
+
+impl Profile {
+    fn add(&mut self, stage: Stage, elapsed: Duration) {
+        let total = match stage {
+            Stage::Walk => &mut self.walk,
+            Stage::Parse => &mut self.parse,
+            Stage::Index => &mut self.index,
+            Stage::Commit => &mut self.commit,
+        };
+        *total += elapsed;
+    }
+
+    pub fn total(&self) -> Duration {
+        self.walk + self.parse + self.index + self.commit
+    }
+
+    /// A table of the seconds spent in each stage, followed by how many notes were indexed a
+    /// second
+    pub fn summary(&self) -> String {
+        let mut out = format!("{:<8}{:>10}\n", "stage", "seconds");
+        for (name, elapsed) in [
+            ("walk", self.walk),
+            ("parse", self.parse),
+            ("index", self.index),
+            ("commit", self.commit),
+            ("total", self.total()),
+        ] {
+            out.push_str(&format!("{:<8}{:>10.3}\n", name, elapsed.as_secs_f64()));
+        }
+        let secs = self.total().as_secs_f64();
+        let rate = if secs > 0.0 {
+            self.docs as f64 / secs
+        } else {
+            0.0
+        };
+        out.push_str(&format!("{} notes, {:.1} notes/sec", self.docs, rate));
+        out
+    }
+}
+
+/// Recursively find markdown files under `path`, skipping hidden files and directories. With
+/// `follow_links` symlinked files and directories are walked into too, and each note is found
+/// once, by its canonical path, however many links lead to it.
+pub fn markdown_files<P: AsRef<Path>>(
+    path: P,
+    follow_links: bool,
+) -> impl Iterator<Item = PathBuf> {
+    let mut seen = HashSet::new();
+    // WalkDir reports a link back to one of its own ancestors as an error rather than looping
+    WalkDir::new(path)
+        .follow_links(follow_links)
+        .into_iter()
+        .filter_entry(|e| {
+            !e.file_name()
+                .to_str()
+                .map(|s| s.starts_with('.'))
+                .unwrap_or(false)
+        })
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.into_path()),
+            Err(e) => {
+                error!("❌ {:?}", e);
+                None
+            }
+        })
+        .filter(|path| path.extension().map_or(false, |ext| ext == "md"))
+        .filter_map(move |path| {
+            if !follow_links {
+                return Some(path);
+            }
+            match path.canonicalize() {
+                Ok(real) if seen.insert(real.clone()) => Some(real),
+                Ok(_) => None,
+                // Gone since the walk found it, which reading it will run into too
+                Err(_) => Some(path),
+            }
+        })
+}
+
+// Whether reading a file failed because it went away or was locked after the walk found it,
+// rather than because of what's in it
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+    )
+}
+
+/// Adds markdown notes to, and removes them from, a Xapian index. Nothing written is visible to
+/// readers of the index until `commit`.
+pub struct Indexer {
+    db: WritableDatabase,
+    tg: TermGenerator,
+    // Kept alongside the TermGenerator using it
+    _stemmer: Stem,
+    /// Index files without frontmatter too, see `Document::parse_file`
+    pub infer_frontmatter: bool,
+    /// Leave note bodies out of the index, see `Document::update_index_metadata_only`
+    pub metadata_only: bool,
+    /// Index the text of note bodies with their markdown syntax and link URLs stripped, see
+    /// `document::plain_text`
+    pub strip_markdown: bool,
+    /// Index what's in the code blocks of note bodies, on by default. Off, code heavy notes
+    /// don't drown out prose with language keywords.
+    pub index_code_blocks: bool,
+    /// Split Chinese, Japanese and Korean text into runs of characters, see `TextIndexing::cjk`.
+    /// Reindex after changing it.
+    pub cjk: bool,
+    /// Extra frontmatter fields to make searchable
+    pub custom_fields: Vec<CustomField>,
+    /// Index nested tags under each of their ancestors too, see `document::tag_terms`
+    pub hierarchical_tags: bool,
+    /// Lowercase the tags of notes `index_path` loads, so they're stored and listed that way.
+    /// Reindex after changing it.
+    pub lowercase_tags: bool,
+    /// Notebooks to file notes under by where they are, see `notebook_for`
+    pub notebooks: Vec<Notebook>,
+    /// Walk into symlinked directories, indexing notes by their canonical path, see
+    /// `markdown_files`
+    pub follow_symlinks: bool,
+    /// Why each file skipped by `index_path` couldn't be loaded
+    pub failures: Vec<String>,
+    /// Files `index_path` skipped because they were deleted or couldn't be opened between
+    /// being found and being read, e.g. while an editor was saving them
+    pub skipped: Vec<PathBuf>,
+    /// How long to wait before trying such a file once more, or `None` to skip it straight away
+    pub retry_delay: Option<Duration>,
+    /// Stop `index_path` with an error on a note whose id another note already has
+    pub fail_on_duplicate: bool,
+    /// A warning for each note found by `index_path` sharing its id with an earlier one
+    pub duplicates: Vec<String>,
+    // The first file each id was seen in
+    seen_ids: HashMap<String, PathBuf>,
+    /// Files `index_path` skipped as unchanged, see `skip_unchanged`
+    pub unchanged: Vec<PathBuf>,
+    // How to spot unchanged files, and what's already indexed to compare them with, by path
+    change_detection: Option<ChangeDetection>,
+    indexed: HashMap<String, Document>,
+    /// Time spent in each stage of `index_path` and `commit`, when set to a `Profile` to add
+    /// to. Left `None` nothing is timed.
+    pub profile: Option<Profile>,
+}
+
+impl Indexer {
+    /// Open the index at `db_path`, creating it if it doesn't exist yet
+    pub fn open(db_path: &Path) -> Result<Indexer, MdqError> {
+        Indexer::open_with(db_path, Backend::default())
+    }
+
+    /// Like `open`, creating a missing index with `backend`
+    pub fn open_with(db_path: &Path, backend: Backend) -> Result<Indexer, MdqError> {
+        Indexer::new(db_path, backend, false)
+    }
+
+    /// Start a new, empty index at `db_path`, replacing any index already there
+    pub fn create(db_path: &Path) -> Result<Indexer, MdqError> {
+        Indexer::create_with(db_path, Backend::default())
+    }
+
+    /// Like `create`, creating the index with `backend`
+    pub fn create_with(db_path: &Path, backend: Backend) -> Result<Indexer, MdqError> {
+        Indexer::new(db_path, backend, true)
+    }
+
+    fn new(db_path: &Path, backend: Backend, overwrite: bool) -> Result<Indexer, MdqError> {
+        let mode = if overwrite {
+            DB_CREATE_OR_OVERWRITE
+        } else {
+            DB_CREATE_OR_OPEN
+        };
+        let db = WritableDatabase::new(&db_path.to_string_lossy(), backend.to_xapian(), mode)
+            .map_err(|e| {
+                if is_lock_error(&e) {
+                    MdqError::DbOpen(format!(
+                        "database {} locked by another process; run `mdq repair` if no other \
+                         mdq is running",
+                        db_path.display()
+                    ))
+                } else {
+                    MdqError::db_open(e)
+                }
+            })?;
+        let mut tg = TermGenerator::new().map_err(MdqError::index)?;
+        let mut stemmer = Stem::new("en").map_err(MdqError::index)?;
+        tg.set_stemmer(&mut stemmer).map_err(MdqError::index)?;
+        Ok(Indexer {
+            db,
+            tg,
+            _stemmer: stemmer,
+            infer_frontmatter: false,
+            metadata_only: false,
+            strip_markdown: false,
+            index_code_blocks: true,
+            cjk: false,
+            custom_fields: Vec::new(),
+            hierarchical_tags: false,
+            lowercase_tags: false,
+            notebooks: Vec::new(),
+            follow_symlinks: false,
+            failures: Vec::new(),
+            skipped: Vec::new(),
+            retry_delay: Some(Duration::from_millis(50)),
+            fail_on_duplicate: false,
+            duplicates: Vec::new(),
+            seen_ids: HashMap::new(),
+            unchanged: Vec::new(),
+            change_detection: None,
+            indexed: HashMap::new(),
+            profile: None,
+        })
+    }
+
+    /// Index every markdown file under `path`, which may also be a single file, returning each
+    /// one found. Files which can't be parsed are logged, skipped and added to `failures`. Notes
+    /// sharing an id with one indexed earlier, e.g. a copied note, are still indexed but warned
+    /// about in `duplicates`, unless `fail_on_duplicate` is set.
+    pub fn index_path(&mut self, path: &Path) -> Result<Vec<PathBuf>, MdqError> {
+        // Rather than finding nothing to index under a typo'd path
+        if !path.exists() {
+            return Err(MdqError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found", path.display()),
+            )));
+        }
+        let mut found = Vec::new();
+        let mut files = markdown_files(path, self.follow_symlinks);
+        loop {
+            let started = self.start();
+            let path = match files.next() {
+                Some(path) => path,
+                None => break,
+            };
+            self.record(Stage::Walk, started);
+
+            let started = self.start();
+            let mut parsed = Document::parse_file(&path, self.infer_frontmatter);
+            if let (Err(e), Some(delay)) = (&parsed, self.retry_delay) {
+                if is_transient(e) {
+                    std::thread::sleep(delay);
+                    parsed = Document::parse_file(&path, self.infer_frontmatter);
+                }
+            }
+            self.record(Stage::Parse, started);
+            match parsed {
+                Ok(mut doc) => {
+                    doc.notebook = notebook_for(&self.notebooks, &path);
+                    if self.lowercase_tags {
+                        doc.tags = clean_tags(doc.tags, true);
+                    }
+                    self.check_duplicate(&doc.id, &path)?;
+                    if self.is_unchanged(&doc) {
+                        debug!("⏭ {}", path.display());
+                        self.unchanged.push(path.clone());
+                    } else {
+                        let started = self.start();
+                        self.index_document(&doc)?;
+                        self.record(Stage::Index, started);
+                        if let Some(profile) = self.profile.as_mut() {
+                            profile.docs += 1;
+                        }
+                        debug!("✅ {}", path.display());
+                    }
+                }
+                Err(e) if is_transient(&e) => {
+                    debug!("⏭ {} changed while indexing: {}", path.display(), e);
+                    self.skipped.push(path.clone());
+                    // A deleted file isn't there to be found, so is pruned like any other
+                    if !path.exists() {
+                        continue;
+                    }
+                }
+                Err(e) => {
+                    error!("❌ {}", e);
+                    self.failures.push(e.to_string());
+                }
+            }
+            found.push(path);
+        }
+        Ok(found)
+    }
+
+    /// Have `index_path` skip files which `detection` finds unchanged since they were indexed
+    /// as one of `indexed`, rather than indexing every file again
+    pub fn skip_unchanged(&mut self, detection: ChangeDetection, indexed: &[Document]) {
+        self.change_detection = Some(detection);
+        self.indexed = indexed
+            .iter()
+            .map(|doc| (doc.fullpath.clone(), doc.clone()))
+            .collect();
+    }
+
+    fn is_unchanged(&self, doc: &Document) -> bool {
+        let indexed = match self.indexed.get(&doc.fullpath) {
+            Some(indexed) => indexed,
+            None => return false,
+        };
+        // Moved to another notebook, which needs indexing whether or not the file changed
+        if doc.notebook != indexed.notebook {
+            return false;
+        }
+        match self.change_detection {
+            Some(ChangeDetection::Mtime) => doc.file_modified == indexed.file_modified,
+            Some(ChangeDetection::Hash) => {
+                !indexed.content_hash.is_empty() && doc.content_hash == indexed.content_hash
+            }
+            None => false,
+        }
+    }
+
+    // Note `id` as seen in `path`, warning if a different file had it already
+    fn check_duplicate(&mut self, id: &str, path: &Path) -> Result<(), MdqError> {
+        let first = match self.seen_ids.get(id) {
+            Some(first) if first != path => first,
+            Some(_) => return Ok(()),
+            None => {
+                self.seen_ids.insert(id.to_string(), path.to_path_buf());
+                return Ok(());
+            }
+        };
+        let msg = format!(
+            "Duplicate id {} in {} and {}",
+            id,
+            first.display(),
+            path.display()
+        );
+        if self.fail_on_duplicate {
+            return Err(MdqError::Frontmatter(msg));
+        }
+        warn!("⚠ {}", msg);
+        self.duplicates.push(msg);
+        Ok(())
+    }
+
+    /// Add `doc` to the index, replacing any document already indexed with the same full path
+    pub fn index_document(&mut self, doc: &Document) -> Result<(), MdqError> {
+        doc.update_index_with(
+            &mut self.db,
+            &mut self.tg,
+            self.metadata_only,
+            &self.custom_fields,
+            self.hierarchical_tags,
+            TextIndexing {
+                strip_markdown: self.strip_markdown,
+                code_blocks: self.index_code_blocks,
+                cjk: self.cjk,
+            },
+        )
+        .map_err(MdqError::index)
+    }
+
+    /// Remove the documents indexed for each of `paths`
+    pub fn prune(&mut self, paths: &[PathBuf]) -> Result<(), MdqError> {
+        for path in paths {
+            self.db
+                .delete_document(&format!("Q{}", path.to_string_lossy()))
+                .map_err(MdqError::index)?;
+            info!("🗑 {}", path.display());
+        }
+        Ok(())
+    }
+
+    /// Make everything indexed or pruned so far visible to readers
+    pub fn commit(&mut self) -> Result<(), MdqError> {
+        let started = self.start();
+        self.db.commit().map_err(MdqError::index)?;
+        self.record(Stage::Commit, started);
+        Ok(())
+    }
+
+    // When profiling, the time a stage starts at
+    fn start(&self) -> Option<Instant> {
+        self.profile.as_ref().map(|_| Instant::now())
+    }
+
+    // Add the time since `started` to `stage`, when profiling
+    fn record(&mut self, stage: Stage, started: Option<Instant>) {
+        if let (Some(profile), Some(started)) = (self.profile.as_mut(), started) {
+            profile.add(stage, started.elapsed());
+        }
+    }
+}
+
+// The file Xapian locks while the index is open for writing, whatever the backend. It's
+// created along with the index and stays there; only the lock on it comes and goes.
+const LOCK_FILE: &str = "flintlock";
+
+// Whether opening an index for writing failed because something else has it locked, going by
+// the Xapian exception it names rather than any mention of a lock, e.g. in a path
+fn is_lock_error(e: &impl std::fmt::Display) -> bool {
+    e.to_string()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| word == "DatabaseLockError")
+}
+
+/// Clear the lock on the index at `db_path`, e.g. one a network filesystem kept after the
+/// writer holding it went away, so that it can be updated again. Returns whether there was a
+/// lock to clear. A lock Xapian can't get is held by something, and clearing it under a live
+/// writer would let two write at once, so this fails unless `force` says it's stale.
+pub fn repair(db_path: &Path, force: bool) -> Result<bool, MdqError> {
+    let lock = db_path.join(LOCK_FILE);
+    if !lock.exists() {
+        return Ok(false);
+    }
+    // The kernel drops the lock of a writer which exits, however it exits, so an index which
+    // opens for writing has nothing to repair
+    let e = match WritableDatabase::new(&db_path.to_string_lossy(), UNKNOWN, DB_CREATE_OR_OPEN) {
+        Ok(_) => return Ok(false),
+        Err(e) if is_lock_error(&e) => e,
+        Err(e) => return Err(MdqError::db_open(e)),
+    };
+    // Only holders on this host which can be looked into are found, so finding none doesn't
+    // mean there aren't any
+    let holders = lock_holders(&lock).unwrap_or_default();
+    if !force {
+        return Err(MdqError::DbOpen(if holders.is_empty() {
+            format!(
+                "database {} is locked by a process which can't be seen from here, e.g. another \
+                 user's or one on another host. If none is still writing to it, `mdq repair \
+                 --force` clears the lock",
+                db_path.display()
+            )
+        } else {
+            let pids: Vec<String> = holders.iter().map(u32::to_string).collect();
+            format!(
+                "database {} is in use by running process {}, stop it rather than repairing",
+                db_path.display(),
+                pids.join(", ")
+            )
+        }));
+    }
+    warn!("Clearing the lock on {}: {}", db_path.display(), e);
+    fs::remove_file(&lock)?;
+    Ok(true)
+}
+
+// Processes on this host with `lock` open, found through /proc. Fails without a /proc to look
+// in, and other users' processes can't be looked into.
+fn lock_holders(lock: &Path) -> io::Result<Vec<u32>> {
+    let lock = lock.canonicalize()?;
+    let mut holders = Vec::new();
+    for entry in fs::read_dir("/proc")?.flatten() {
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // Other users' processes, or ones which have exited since, can't be looked into
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        if fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).map_or(false, |target| target == lock))
+        {
+            holders.push(pid);
+        }
+    }
+    Ok(holders)
+}
+
+#[cfg(test)]
+mod indexer_tests {
+    use super::*;
+    use crate::interactive::xapian_utils::test_utils::note;
+    use crate::search::{search, SearchOptions};
+    use std::fs;
+
+    fn paths(docs: Vec<Document>) -> Vec<String> {
+        let mut paths: Vec<String> = docs.into_iter().map(|d| d.fullpath).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn repair_stale_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.index_document(&note(0, "rust", &[])).unwrap();
+            indexer.commit().unwrap();
+        }
+        // The lock file stays once the writer is gone, but isn't locked, so there's nothing to
+        // repair. Nor is there without an index.
+        assert!(db_path.join(LOCK_FILE).exists());
+        assert!(!repair(&db_path, false).unwrap());
+        assert!(db_path.join(LOCK_FILE).exists());
+        assert!(!repair(&tmp.path().join("missing"), false).unwrap());
+
+        // A writer which never lets go, standing in for a lock a network filesystem kept
+        let stuck = Indexer::open(&db_path).unwrap();
+        let e = Indexer::open(&db_path)
+            .err()
+            .expect("Opened a locked index");
+        assert!(e.to_string().contains("run `mdq repair`"), "{}", e);
+        assert!(repair(&db_path, false).is_err());
+        assert!(db_path.join(LOCK_FILE).exists());
+        std::mem::forget(stuck);
+
+        assert!(repair(&db_path, true).unwrap());
+        assert!(!db_path.join(LOCK_FILE).exists());
+        let mut indexer = Indexer::open(&db_path).unwrap();
+        indexer.index_document(&note(1, "rust", &[])).unwrap();
+        indexer.commit().unwrap();
+        assert_eq!(
+            vec!["/notes/0.md", "/notes/1.md"],
+            paths(search(&db_path, "rust", SearchOptions::default()).unwrap())
+        );
+    }
+
+    #[test]
+    fn index_documents_then_prune() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.index_document(&note(0, "rust", &[])).unwrap();
+            indexer
+                .index_document(&note(1, "rust and vim", &[]))
+                .unwrap();
+            indexer.index_document(&note(2, "vim", &[])).unwrap();
+            indexer.commit().unwrap();
+        }
+        assert_eq!(
+            vec!["/notes/0.md", "/notes/1.md"],
+            paths(search(&db_path, "rust", SearchOptions::default()).unwrap())
+        );
+
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.prune(&[PathBuf::from("/notes/0.md")]).unwrap();
+            indexer.commit().unwrap();
+        }
+        assert_eq!(
+            vec!["/notes/1.md"],
+            paths(search(&db_path, "rust", SearchOptions::default()).unwrap())
+        );
+    }
+
+    #[test]
+    fn metadata_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        let mut doc = note(0, "borrow checker", &[]);
+        doc.tags = vec![String::from("rust")];
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.metadata_only = true;
+            indexer.index_document(&doc).unwrap();
+            indexer.commit().unwrap();
+        }
+
+        let found = search(&db_path, "tag:rust", SearchOptions::default()).unwrap();
+        assert_eq!(1, found.len());
+        assert_eq!(doc.title, found[0].title);
+        assert_eq!(doc.fullpath, found[0].fullpath);
+        assert_eq!(doc.tags, found[0].tags);
+        assert_eq!(doc.date, found[0].date);
+        assert_eq!("", found[0].body);
+        assert_eq!(
+            1,
+            search(&db_path, "title:note", SearchOptions::default())
+                .unwrap()
+                .len()
+        );
+        assert!(search(&db_path, "borrow", SearchOptions::default())
+            .unwrap()
+            .is_empty());
+        assert!(search(&db_path, "body:checker", SearchOptions::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn cjk_and_emoji() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        let mut japanese = note(0, "東京で会議", &[]);
+        japanese.title = String::from("日本語のノート");
+        let mut crab = note(1, "ferris", &[]);
+        crab.tags = vec![String::from("🦀")];
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.cjk = true;
+            indexer.index_document(&japanese).unwrap();
+            indexer.index_document(&crab).unwrap();
+            indexer.commit().unwrap();
+        }
+
+        let found = |query: &str| {
+            let opts = SearchOptions {
+                cjk: true,
+                ..SearchOptions::default()
+            };
+            paths(search(&db_path, query, opts).unwrap())
+        };
+        assert_eq!(vec![japanese.fullpath.clone()], found("日本語"));
+        assert_eq!(vec![japanese.fullpath.clone()], found("title:日本語"));
+        assert_eq!(vec![japanese.fullpath.clone()], found("会議"));
+        assert_eq!(vec![crab.fullpath.clone()], found("tag:🦀"));
+    }
+
+    #[test]
+    fn strip_markdown() {
+        let body = "# Setup guide\n\nSee the [install docs](https://example.com/reference) for \
+                    *details*.\n";
+        let found = |strip_markdown: bool, query: &str| {
+            let tmp = tempfile::tempdir().unwrap();
+            let db_path = tmp.path().join("db");
+            {
+                let mut indexer = Indexer::open(&db_path).unwrap();
+                indexer.strip_markdown = strip_markdown;
+                indexer.index_document(&note(0, body, &[])).unwrap();
+                indexer.commit().unwrap();
+            }
+            search(&db_path, query, SearchOptions::default()).unwrap()
+        };
+
+        for query in ["setup", "install", "docs", "details"] {
+            assert_eq!(1, found(false, query).len(), "{}", query);
+            assert_eq!(1, found(true, query).len(), "{}", query);
+        }
+        // Words only in the link's URL are left out, while the body is stored as written
+        for query in ["example", "reference"] {
+            assert_eq!(1, found(false, query).len(), "{}", query);
+            assert!(found(true, query).is_empty(), "{}", query);
+        }
+        assert_eq!(body, found(true, "setup")[0].body);
+    }
+
+    #[test]
+    fn without_code_blocks() {
+        let body =
+            "Parsing dates\n\n```rust\nlet parsed = chrono::NaiveDate::parse();\n```\n\n    \
+                    tokio::spawn(task);\n\nUse `strftime` formats.\n";
+        let found = |strip_markdown: bool, index_code_blocks: bool, query: &str| {
+            let tmp = tempfile::tempdir().unwrap();
+            let db_path = tmp.path().join("db");
+            {
+                let mut indexer = Indexer::open(&db_path).unwrap();
+                indexer.strip_markdown = strip_markdown;
+                indexer.index_code_blocks = index_code_blocks;
+                indexer.index_document(&note(0, body, &[])).unwrap();
+                indexer.commit().unwrap();
+            }
+            search(&db_path, query, SearchOptions::default())
+                .unwrap()
+                .len()
+        };
+
+        // Whether or not markdown is stripped too
+        for strip_markdown in [false, true] {
+            for query in ["chrono", "tokio"] {
+                assert_eq!(1, found(strip_markdown, true, query), "{}", query);
+                assert_eq!(0, found(strip_markdown, false, query), "{}", query);
+            }
+            // Prose and inline code are still indexed
+            for query in ["dates", "strftime"] {
+                assert_eq!(1, found(strip_markdown, false, query), "{}", query);
+            }
+        }
+    }
+
+    #[test]
+    fn backends() {
+        for backend in [Backend::Glass, Backend::Default] {
+            let tmp = tempfile::tempdir().unwrap();
+            let db_path = tmp.path().join("db");
+            {
+                let mut indexer = Indexer::create_with(&db_path, backend).unwrap();
+                indexer.index_document(&note(0, "rust", &[])).unwrap();
+                indexer.commit().unwrap();
+            }
+            // Reopening for writing again keeps what's there
+            {
+                let mut indexer = Indexer::open_with(&db_path, backend).unwrap();
+                indexer.index_document(&note(1, "rust", &[])).unwrap();
+                indexer.commit().unwrap();
+            }
+            assert_eq!(
+                vec!["/notes/0.md", "/notes/1.md"],
+                paths(search(&db_path, "rust", SearchOptions::default()).unwrap()),
+                "{:?}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    fn backend_names() {
+        assert_eq!(Backend::Glass, "glass".parse().unwrap());
+        assert_eq!(Backend::Brass, "Brass".parse().unwrap());
+        assert_eq!(Backend::Default, "default".parse().unwrap());
+        assert!("chert".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn change_detection() {
+        use crate::interactive::xapian_utils::all_documents;
+        use std::time::{Duration, SystemTime};
+        use xapian_rusty::Database;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(&notes).unwrap();
+        let note = notes.join("note.md");
+        fs::write(
+            &note,
+            "---\ntitle: Synced\ndate: 2021-06-22T12:48:16-0400\n---\n\nrust\n",
+        )
+        .unwrap();
+        let db_path = tmp.path().join("db");
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.index_path(&notes).unwrap();
+            indexer.commit().unwrap();
+        }
+        let indexed = all_documents(
+            &mut Database::new_with_path(&db_path.to_string_lossy(), DB_CREATE_OR_OPEN).unwrap(),
+        )
+        .unwrap();
+
+        // As if a sync tool had touched the file without changing it
+        fs::File::options()
+            .write(true)
+            .open(&note)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(3600))
+            .unwrap();
+
+        let unchanged = |detection| {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.skip_unchanged(detection, &indexed);
+            indexer.index_path(&notes).unwrap();
+            indexer.unchanged
+        };
+        assert_eq!(vec![note.clone()], unchanged(ChangeDetection::Hash));
+        assert!(unchanged(ChangeDetection::Mtime).is_empty());
+    }
+
+    #[test]
+    fn change_detection_names() {
+        assert_eq!(ChangeDetection::Mtime, "mtime".parse().unwrap());
+        assert_eq!(ChangeDetection::Hash, "Hash".parse().unwrap());
+        assert!("md5".parse::<ChangeDetection>().is_err());
+    }
+
+    #[test]
+    fn duplicate_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(&notes).unwrap();
+        for name in ["original.md", "copy.md"] {
+            fs::write(
+                notes.join(name),
+                "---\nid: abc123\ntitle: Copied\ndate: 2021-06-22T12:48:16-0400\n---\n\nrust\n",
+            )
+            .unwrap();
+        }
+        fs::write(
+            notes.join("other.md"),
+            "---\nid: def456\ntitle: Other\ndate: 2021-06-22T12:48:16-0400\n---\n\nrust\n",
+        )
+        .unwrap();
+
+        let mut indexer = Indexer::open(&tmp.path().join("db")).unwrap();
+        assert_eq!(3, indexer.index_path(&notes).unwrap().len());
+        assert_eq!(1, indexer.duplicates.len());
+        let warning = &indexer.duplicates[0];
+        assert!(warning.contains("abc123"), "{}", warning);
+        assert!(
+            warning.contains(&notes.join("original.md").display().to_string()),
+            "{}",
+            warning
+        );
+        assert!(
+            warning.contains(&notes.join("copy.md").display().to_string()),
+            "{}",
+            warning
+        );
+
+        let mut indexer = Indexer::open(&tmp.path().join("strict")).unwrap();
+        indexer.fail_on_duplicate = true;
+        let err = indexer.index_path(&notes).unwrap_err();
+        assert!(err.to_string().contains("abc123"), "{}", err);
+    }
+
+    #[test]
+    fn index_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(notes.join(".hidden")).unwrap();
+        let write = |name: &str, body: &str| {
+            fs::write(
+                notes.join(name),
+                format!(
+                    "---\ndate: 2021-06-22T12:48:16-0400\ntitle: {}\n---\n\n{}\n",
+                    name, body
+                ),
+            )
+            .unwrap();
+        };
+        write("one.md", "rust");
+        write("two.md", "vim");
+        write(".hidden/three.md", "rust");
+        fs::write(notes.join("plain.md"), "# Plain\n\nrust\n").unwrap();
+        fs::write(notes.join("other.txt"), "rust").unwrap();
+
+        let db_path = tmp.path().join("db");
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            let mut found = indexer.index_path(&notes).unwrap();
+            found.sort();
+            assert_eq!(
+                vec![
+                    notes.join("one.md"),
+                    notes.join("plain.md"),
+                    notes.join("two.md")
+                ],
+                found
+            );
+            assert_eq!(1, indexer.failures.len());
+            assert!(indexer.failures[0].contains("plain.md"));
+            indexer.commit().unwrap();
+        }
+        assert_eq!(
+            vec![notes.join("one.md").to_string_lossy().to_string()],
+            paths(search(&db_path, "rust", SearchOptions::default()).unwrap())
+        );
+
+        // Recreating starts over, and inferred frontmatter picks up the plain file
+        {
+            let mut indexer = Indexer::create(&db_path).unwrap();
+            indexer.infer_frontmatter = true;
+            indexer.index_path(&notes.join("plain.md")).unwrap();
+            indexer.commit().unwrap();
+        }
+        assert_eq!(
+            vec![notes.join("plain.md").to_string_lossy().to_string()],
+            paths(search(&db_path, "rust", SearchOptions::default()).unwrap())
+        );
+    }
+
+    #[test]
+    fn notebooks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let write = |dir: &Path, name: &str| {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(
+                dir.join(name),
+                format!(
+                    "---\ndate: 2021-06-22T12:48:16-0400\ntitle: {}\n---\n\nrust\n",
+                    name
+                ),
+            )
+            .unwrap();
+        };
+        let work = tmp.path().join("work");
+        let personal = tmp.path().join("personal");
+        write(&work, "standup.md");
+        write(&work.join("archive"), "retro.md");
+        write(&personal, "garden.md");
+
+        let db_path = tmp.path().join("db");
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.notebooks = vec![
+                format!("work={}", work.display()).parse().unwrap(),
+                format!("Personal={}", personal.display()).parse().unwrap(),
+            ];
+            indexer.index_path(&work).unwrap();
+            indexer.index_path(&personal).unwrap();
+            indexer.commit().unwrap();
+        }
+        let found = |query: &str| paths(search(&db_path, query, SearchOptions::default()).unwrap());
+        let path = |p: PathBuf| p.to_string_lossy().to_string();
+        assert_eq!(
+            vec![
+                path(work.join("archive/retro.md")),
+                path(work.join("standup.md"))
+            ],
+            found("rust notebook:work")
+        );
+        assert_eq!(
+            vec![path(personal.join("garden.md"))],
+            found("notebook:personal")
+        );
+        assert_eq!(3, found("notebook:work notebook:personal").len());
+        assert_eq!(
+            vec![path(personal.join("garden.md"))],
+            found("rust -notebook:work")
+        );
+        assert!(found("notebook:play").is_empty());
+    }
+
+    #[test]
+    fn notebook_for_deepest_root() {
+        let notebooks: Vec<Notebook> = ["notes=/notes", "work=/notes/work/", "play=/play"]
+            .iter()
+            .map(|n| n.parse().unwrap())
+            .collect();
+        assert_eq!(
+            "work",
+            notebook_for(&notebooks, Path::new("/notes/work/a.md"))
+        );
+        assert_eq!(
+            "notes",
+            notebook_for(&notebooks, Path::new("/notes/workshop.md"))
+        );
+        assert_eq!("", notebook_for(&notebooks, Path::new("/other/a.md")));
+
+        for invalid in ["work", "=/notes", "work="] {
+            assert!(invalid.parse::<Notebook>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn vanished_files_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(&notes).unwrap();
+        fs::write(
+            notes.join("kept.md"),
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: Kept\n---\n\nrust\n",
+        )
+        .unwrap();
+        // Found by the walk, but gone by the time it's read
+        std::os::unix::fs::symlink(notes.join("deleted.md"), notes.join("gone.md")).unwrap();
+
+        let db_path = tmp.path().join("db");
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            indexer.retry_delay = Some(Duration::from_millis(1));
+            let found = indexer.index_path(&notes).unwrap();
+            assert_eq!(vec![notes.join("kept.md")], found);
+            assert_eq!(vec![notes.join("gone.md")], indexer.skipped);
+            assert!(indexer.failures.is_empty(), "{:?}", indexer.failures);
+            indexer.commit().unwrap();
+        }
+        assert_eq!(
+            vec![notes.join("kept.md").to_string_lossy().to_string()],
+            paths(search(&db_path, "rust", SearchOptions::default()).unwrap())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let shared = tmp.path().join("shared");
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(&shared).unwrap();
+        fs::create_dir_all(&notes).unwrap();
+        fs::write(
+            shared.join("linked.md"),
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: Linked\n---\n\nrust\n",
+        )
+        .unwrap();
+        fs::write(
+            notes.join("own.md"),
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: Own\n---\n\nrust\n",
+        )
+        .unwrap();
+        // Two ways to the same notes, plus a loop back up the tree
+        std::os::unix::fs::symlink(&shared, notes.join("shared")).unwrap();
+        std::os::unix::fs::symlink(&shared, notes.join("also-shared")).unwrap();
+        std::os::unix::fs::symlink(&notes, notes.join("loop")).unwrap();
+
+        let linked = shared.join("linked.md").canonicalize().unwrap();
+        let db_path = tmp.path().join("db");
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            assert_eq!(
+                vec![notes.join("own.md")],
+                indexer.index_path(&notes).unwrap()
+            );
+
+            indexer.follow_symlinks = true;
+            let mut found = indexer.index_path(&notes).unwrap();
+            found.sort();
+            let mut expected = vec![linked.clone(), notes.join("own.md").canonicalize().unwrap()];
+            expected.sort();
+            assert_eq!(expected, found);
+            indexer.commit().unwrap();
+        }
+        assert_eq!(
+            vec![linked.to_string_lossy().to_string()],
+            paths(search(&db_path, "title:linked", SearchOptions::default()).unwrap())
+        );
+    }
+
+    #[test]
+    fn profile() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(&notes).unwrap();
+        for i in 0..3 {
+            fs::write(
+                notes.join(format!("{}.md", i)),
+                format!(
+                    "---\ndate: 2021-06-22T12:48:16-0400\ntitle: Note {}\n---\n\nrust\n",
+                    i
+                ),
+            )
+            .unwrap();
+        }
+
+        let mut indexer = Indexer::open(&tmp.path().join("db")).unwrap();
+        indexer.profile = Some(Profile::default());
+        indexer.index_path(&notes).unwrap();
+        indexer.commit().unwrap();
+        let profile = indexer.profile.unwrap();
+        assert_eq!(3, profile.docs);
+
+        let summary = profile.summary();
+        let lines: Vec<&str> = summary.lines().collect();
+        for (line, stage) in lines[1..].iter().zip(["walk", "parse", "index", "commit"]) {
+            let (name, secs) = line.split_once(' ').unwrap();
+            assert_eq!(stage, name);
+            assert!(secs.trim().parse::<f64>().unwrap() >= 0.0, "{}", summary);
+        }
+        assert!(lines[5].starts_with("total"), "{}", summary);
+        assert!(lines[6].starts_with("3 notes, "), "{}", summary);
+    }
+}
@@ -1,27 +1,40 @@
-mod xapian_utils;
+pub mod cache;
+pub mod keys;
+pub mod markdown;
+pub mod styles;
+pub mod xapian_utils;
+use crate::date::Recency;
 use crate::document;
-use ansi_to_tui::ansi_to_text;
+use crate::search::{sorted_matches, DedupeBy, SearchOptions, Sort, Tiebreak};
+use chrono::Utc;
+use clap::ArgEnum;
 use color_eyre::Report;
-use eyre::bail;
+use eyre::{bail, WrapErr};
 use log::{log_enabled, Level};
-use std::io::{stdout, Write};
+use std::fs;
+use std::io::{self, stdout, Stdout, Write};
+use std::path::{Path, PathBuf};
 
+use cache::{CacheKey, ResultCache};
 use std::process::Command;
-use syntect::easy::HighlightLines;
-use syntect::highlighting::{Style as hStyle, ThemeSet};
-use syntect::parsing::SyntaxSet;
-use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+use std::time::{Duration, Instant};
+use styles::Styles;
 use tempfile::Builder;
-use termion::{event::Key, raw::IntoRawMode, screen::AlternateScreen};
+use termion::{
+    event::{Key, MouseButton, MouseEvent},
+    input::MouseTerminal,
+    raw::{IntoRawMode, RawTerminal},
+    screen::AlternateScreen,
+};
 use tui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Modifier, Style},
-    text::{Span, Spans, Text},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::Style,
+    text::{Span, Spans},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
 };
-use unicode_width::UnicodeWidthStr;
-use xapian_rusty::Database;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use xapian_rusty::{Database, Query};
 
 /// TerminalApp holds the state of the application
 pub(crate) struct TerminalApp {
@@ -39,20 +52,59 @@ pub(crate) struct TerminalApp {
     pub(crate) error: String,
     /// Display the serialized payload to send to the server
     pub(crate) debug: String,
+    // Whether the debug pane is shown, which it is with debug logging on, so `debug` is needed
+    show_debug: bool,
     // TODO Add fields for sort expression
     inp_idx: usize,
-    // Length here should stay in sync with the number of editable areas
-    inp_widths: [i32; 2],
+    // Longest query or filter input accepted, in characters
+    max_input_len: usize,
+    // How many matches were asked for by the last query
+    fetch_limit: usize,
+    // Where the match list was last drawn and how far it was scrolled, used to map mouse clicks
+    // back to a match
+    list_area: Rect,
+    list_offset: usize,
+    // When the query was last edited, if it hasn't been run since
+    query_edited: Option<Instant>,
+    // Every tag in the index, sorted, for completing `tag:` tokens
+    tag_vocab: Vec<String>,
+    // The tag being typed to add to the selected note, while Alt-t's prompt is open
+    tag_input: Option<String>,
+    // What to report for each selected match on exit
+    output: Output,
+    // Spelling corrected query to offer when the current one finds little, empty if none
+    suggestion: String,
+    // How the query input gets parsed
+    parse_options: xapian_utils::ParseOptions,
+    // Order matches are listed in
+    sort: Sort,
+    // List matches in the opposite of `sort` order
+    reverse: bool,
+    // Order of matches `sort` ranks the same
+    tiebreak: Tiebreak,
+    // Every query is limited to notes dated within these
+    dates: xapian_utils::DateBounds,
+    // And to notes dated within this span back from now, if set
+    recent: Option<Recency>,
+    // Matches scoring below this percent are left out
+    min_score: i32,
+    // Matches which are the same note by this are listed once
+    dedupe_by: Option<DedupeBy>,
+    // Lines scrolled past at the top of the preview, along with the preview's height and the
+    // number of lines in it when it was last drawn
+    preview_scroll: u16,
+    preview_height: u16,
+    preview_lines: usize,
+    // Matches of recent queries, so running one again doesn't go back to the index
+    cache: ResultCache,
 }
 
 impl TerminalApp {
     // TODO make this work for multiple selections
     pub fn get_selected(&mut self) -> Vec<String> {
-        let ret: Vec<String> = Vec::new();
-        if let Some(i) = self.selected_state.selected() {
-            vec![self.matches[i].fullpath.clone()]
-        } else {
-            ret
+        match self.selected_document() {
+            Some(doc) => vec![self.output.of(doc)],
+            None => Vec::new(),
         }
     }
 
@@ -63,6 +115,12 @@ impl TerminalApp {
         }
     }
 
+    fn selected_document(&self) -> Option<&document::Document> {
+        self.selected_state
+            .selected()
+            .and_then(|i| self.matches.get(i))
+    }
+
     pub fn next(&mut self) {
         let i = match self.selected_state.selected() {
             Some(i) => {
@@ -91,8 +149,7 @@ impl TerminalApp {
         self.selected_state.select(Some(i));
     }
 
-    fn new(starting_query: String) -> TerminalApp {
-        let input_width = starting_query.width() as i32;
+    fn new(starting_query: String, options: &Options) -> TerminalApp {
         TerminalApp {
             query_input: starting_query,
             filter_input: String::new(),
@@ -101,105 +158,1618 @@ impl TerminalApp {
             selected_state: ListState::default(),
             error: String::new(),
             debug: String::new(),
+            show_debug: log_enabled!(Level::Debug),
             inp_idx: 0,
-            inp_widths: [input_width, 0],
+            max_input_len: options.max_input_len,
+            fetch_limit: 0,
+            list_area: Rect::default(),
+            list_offset: 0,
+            query_edited: None,
+            tag_vocab: Vec::new(),
+            tag_input: None,
+            output: options.output,
+            suggestion: String::new(),
+            parse_options: xapian_utils::ParseOptions {
+                default_fields: options.default_fields.clone(),
+                custom_fields: options.custom_fields.clone(),
+                cjk: options.cjk,
+                ..xapian_utils::ParseOptions::default()
+            },
+            sort: options.sort,
+            reverse: options.reverse,
+            tiebreak: options.tiebreak,
+            dates: options.dates.clone(),
+            recent: options.recent.clone(),
+            min_score: options.min_score,
+            dedupe_by: options.dedupe_by,
+            preview_scroll: 0,
+            preview_height: 0,
+            preview_lines: 0,
+            cache: ResultCache::new(options.cache_size),
+        }
+    }
+
+    /// Add `tag` to the frontmatter of the selected note's file and index the note again with
+    /// `reindex`, returning the index it reopened. `None` if the note didn't change, as when it's
+    /// tagged `tag` already.
+    fn tag_selected(&mut self, tag: &str, reindex: &Reindex) -> Result<Option<Database>, Report> {
+        let path = match self.selected_document() {
+            Some(doc) => PathBuf::from(&doc.fullpath),
+            None => return Ok(None),
+        };
+        let text = fs::read_to_string(&path)
+            .wrap_err_with(|| format!("while reading {}", path.display()))?;
+        let tagged = match document::add_tag(&text, tag)? {
+            Some(tagged) => tagged,
+            None => return Ok(None),
+        };
+        fs::write(&path, tagged).wrap_err_with(|| format!("while writing {}", path.display()))?;
+        let db = reindex(&path)?;
+        let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ");
+        if let Err(i) = self.tag_vocab.binary_search(&tag) {
+            self.tag_vocab.insert(i, tag);
         }
+        Ok(Some(db))
+    }
+
+    /// Preview the selected match from its top
+    fn show_selected(&mut self) {
+        self.preview = self.get_selected_contents();
+        self.preview_scroll = 0;
+    }
+
+    /// Scroll the preview by `delta` lines, negative to scroll up
+    fn scroll_preview(&mut self, delta: i32) {
+        self.preview_scroll = clamp_scroll(
+            self.preview_scroll,
+            delta,
+            self.preview_lines,
+            self.preview_height,
+        );
+    }
+
+    /// Tags completing the `tag:` token being typed into the query input, if any
+    fn tag_completions(&self) -> Vec<String> {
+        if self.inp_idx != 0 {
+            return Vec::new();
+        }
+        match tag_prefix_at(&self.query_input, self.query_input.len()) {
+            Some(prefix) => complete_tags(&self.tag_vocab, prefix)
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Replace the `tag:` token being typed with the first completion, returning false if there
+    /// was nothing to complete
+    fn accept_completion(&mut self) -> bool {
+        let completion = match self.tag_completions().into_iter().next() {
+            Some(completion) => completion,
+            None => return false,
+        };
+        let cursor = self.query_input.len();
+        let prefix_len = tag_prefix_at(&self.query_input, cursor).map_or(0, str::len);
+        self.query_input.truncate(cursor - prefix_len);
+        if completion.contains(char::is_whitespace) {
+            self.query_input.push_str(&format!("\"{}\"", completion));
+        } else {
+            self.query_input.push_str(&completion);
+        }
+        true
+    }
+
+    /// Dates queries are limited to, `dates` narrowed to the recency filter if there is one
+    fn date_bounds(&self) -> xapian_utils::DateBounds {
+        match &self.recent {
+            Some(recent) => self.dates.within(&recent.range(Utc::now())),
+            None => self.dates.clone(),
+        }
+    }
+
+    /// Number of matches visible at once inside the bordered list
+    fn list_height(&self) -> usize {
+        self.list_area.height.saturating_sub(2) as usize
+    }
+
+    /// Whether the selection has scrolled close enough to the end of the fetched matches that a
+    /// bigger window should be fetched
+    fn needs_more(&self) -> bool {
+        near_end(
+            self.selected_state.selected().unwrap_or(0),
+            self.list_height(),
+            self.matches.len(),
+            self.fetch_limit,
+        )
+    }
+
+    /// Replace the query with the suggested spelling correction, returning false if there was
+    /// nothing suggested
+    fn accept_suggestion(&mut self) -> bool {
+        if self.suggestion.is_empty() {
+            return false;
+        }
+        self.query_input = std::mem::take(&mut self.suggestion);
+        true
+    }
+
+    /// The input area currently being edited
+    fn current_input(&mut self) -> &mut String {
+        if self.inp_idx == 0 {
+            &mut self.query_input
+        } else {
+            &mut self.filter_input
+        }
+    }
+
+    /// Scroll wheel moves the selection, left click selects the row under the pointer
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        match mouse {
+            MouseEvent::Press(MouseButton::WheelDown, _, _) => self.next(),
+            MouseEvent::Press(MouseButton::WheelUp, _, _) => self.previous(),
+            MouseEvent::Press(MouseButton::Left, _, y) => {
+                match click_to_index(y, self.list_area, self.list_offset, self.matches.len()) {
+                    Some(i) => self.selected_state.select(Some(i)),
+                    None => return,
+                }
+            }
+            _ => return,
+        }
+        self.show_selected();
     }
 }
 
-pub fn setup_panic() {
-    std::panic::set_hook(Box::new(move |_x| {
-        stdout()
-            .into_raw_mode()
-            .unwrap()
-            .suspend_raw_mode()
-            .unwrap();
-        write!(
-            stdout().into_raw_mode().unwrap(),
+/// Preview scroll offset after moving `scroll` by `delta` lines, kept between the top of the
+/// `content_lines` long preview and where its last line reaches the bottom of the `viewport`
+fn clamp_scroll(scroll: u16, delta: i32, content_lines: usize, viewport: u16) -> u16 {
+    let max = content_lines.saturating_sub(viewport as usize) as i64;
+    (scroll as i64 + delta as i64).clamp(0, max.min(u16::MAX as i64)) as u16
+}
+
+#[cfg(test)]
+mod preview_scroll_tests {
+    use super::*;
+    #[test]
+    fn clamped() {
+        assert_eq!(5, clamp_scroll(0, 5, 100, 20));
+        assert_eq!(0, clamp_scroll(5, -10, 100, 20));
+        // The last line stays at the bottom
+        assert_eq!(80, clamp_scroll(70, 50, 100, 20));
+        // Content that fits never scrolls
+        assert_eq!(0, clamp_scroll(0, 5, 10, 20));
+        assert_eq!(0, clamp_scroll(0, 5, 0, 0));
+    }
+
+    #[test]
+    fn reset_on_selection_change() {
+        let mut app = TerminalApp::new(String::new(), &Options::default());
+        app.matches = vec![document::Document::new(), document::Document::new()];
+        app.selected_state.select(Some(0));
+        app.preview_lines = 100;
+        app.preview_height = 20;
+        app.scroll_preview(30);
+        assert_eq!(30, app.preview_scroll);
+
+        app.next();
+        app.show_selected();
+        assert_eq!(0, app.preview_scroll);
+    }
+}
+
+/// How the preview shows a note
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PreviewMode {
+    /// The markdown source, syntax highlighted
+    Source,
+    /// Formatted for reading, e.g. bold headings and bulleted lists
+    Rendered,
+}
+
+/// What `query` returns for each selected match
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Id,
+    /// Full path to the note, handy for e.g. `vim $(mdq query foo)`
+    Path,
+    Title,
+}
+
+impl Output {
+    /// What to report for `doc`
+    pub fn of(self, doc: &document::Document) -> String {
+        match self {
+            Output::Id => doc.id.clone(),
+            Output::Path => doc.fullpath.clone(),
+            Output::Title => doc.title.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod output_tests {
+    use super::*;
+    #[test]
+    fn each_mode() {
+        let mut doc = document::Document::new();
+        doc.id = String::from("abc123");
+        doc.fullpath = String::from("/notes/work/todo.md");
+        doc.title = String::from("Todo");
+
+        for (output, expected) in [
+            (Output::Id, "abc123"),
+            (Output::Path, "/notes/work/todo.md"),
+            (Output::Title, "Todo"),
+        ] {
+            let options = Options {
+                output,
+                ..Options::default()
+            };
+            let mut app = TerminalApp::new(String::new(), &options);
+            app.matches = vec![document::Document::new(), doc.clone()];
+            assert!(app.get_selected().is_empty());
+            app.selected_state.select(Some(1));
+            assert_eq!(vec![expected], app.get_selected());
+        }
+    }
+}
+
+/// Tunables for the interactive query interface
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Longest query or filter input accepted, in characters
+    pub max_input_len: usize,
+    /// Whether to draw with colors and syntax highlighting, or only the terminal's defaults
+    pub color: bool,
+    /// What to return for each selected match
+    pub output: Output,
+    /// Fields a query word without a `field:` prefix is searched in
+    pub default_fields: Vec<xapian_utils::XapianTag>,
+    /// Fields searchable as `name:value` besides the built-in ones
+    pub custom_fields: Vec<xapian_utils::CustomField>,
+    /// Split Chinese, Japanese and Korean query text into runs of characters, see
+    /// `xapian_utils::ParseOptions::cjk`
+    pub cjk: bool,
+    /// View notes in the pager even when they'd fit on screen
+    pub pager_always: bool,
+    /// Order matches are listed in
+    pub sort: Sort,
+    /// List matches in the opposite of `sort` order
+    pub reverse: bool,
+    /// Order of matches `sort` ranks the same
+    pub tiebreak: Tiebreak,
+    /// Only list notes dated within these
+    pub dates: xapian_utils::DateBounds,
+    /// Only list notes dated within this span back from now
+    pub recent: Option<Recency>,
+    /// Leave out matches scoring below this percent, 0 lists every match
+    pub min_score: i32,
+    /// List matches which are the same note by this once
+    pub dedupe_by: Option<DedupeBy>,
+    /// Drawn before the selected match
+    pub select_symbol: String,
+    /// How the selected match, and selected tag completion, are drawn
+    pub select_style: Style,
+    /// Keys which run a shell command on the selected match
+    pub key_bindings: Vec<keys::KeyBinding>,
+    /// How the preview shows the selected note
+    pub preview_mode: PreviewMode,
+    /// How many recent queries' matches to keep, 0 to run every query against the index
+    pub cache_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            max_input_len: 256,
+            color: true,
+            output: Output::Path,
+            default_fields: xapian_utils::DEFAULT_FIELDS.to_vec(),
+            custom_fields: Vec::new(),
+            cjk: false,
+            pager_always: false,
+            sort: Sort::Relevance,
+            reverse: false,
+            tiebreak: Tiebreak::Date,
+            dates: xapian_utils::DateBounds::default(),
+            recent: None,
+            min_score: 0,
+            dedupe_by: None,
+            select_symbol: String::from(styles::SELECT_SYMBOL),
+            select_style: styles::default_selected(),
+            key_bindings: Vec::new(),
+            preview_mode: PreviewMode::Source,
+            cache_size: 32,
+        }
+    }
+}
+
+/// Column to place the cursor at, just past the end of `input` drawn inside the left border of
+/// `area`, without running past the right border
+fn cursor_x(area: Rect, input: &str) -> u16 {
+    let max_width = area.width.saturating_sub(2) as usize;
+    area.x + 1 + input.width().min(max_width) as u16
+}
+
+#[cfg(test)]
+mod input_width_tests {
+    use super::*;
+    #[test]
+    fn ascii() {
+        assert_eq!(11, cursor_x(Rect::new(5, 0, 40, 3), "hello"));
+        assert_eq!(6, cursor_x(Rect::new(5, 0, 40, 3), ""));
+    }
+
+    #[test]
+    fn wide_characters() {
+        // Each CJK character takes up two columns
+        assert_eq!(5, cursor_x(Rect::new(0, 0, 40, 3), "日本"));
+    }
+
+    #[test]
+    fn combining_characters() {
+        // `e` followed by a combining acute accent is a single column
+        assert_eq!(5, cursor_x(Rect::new(0, 0, 40, 3), "cafe\u{301}"));
+    }
+
+    #[test]
+    fn clamped_to_area() {
+        assert_eq!(9, cursor_x(Rect::new(0, 0, 10, 3), "a much longer query"));
+    }
+}
+
+/// Most completions shown at once
+const MAX_COMPLETIONS: usize = 5;
+
+/// If the whitespace separated token ending at `cursor` is a (possibly love/hate marked) `tag:`
+/// token, return what has been typed after `tag:`
+fn tag_prefix_at(input: &str, cursor: usize) -> Option<&str> {
+    let before = &input[..cursor];
+    let start = before.rfind(char::is_whitespace).map_or(0, |i| {
+        i + before[i..].chars().next().map_or(1, char::len_utf8)
+    });
+    let token = before[start..].trim_start_matches(|c| c == '+' || c == '-');
+    if token.len() >= 4 && token[..4].eq_ignore_ascii_case("tag:") {
+        Some(&token[4..])
+    } else {
+        None
+    }
+}
+
+/// Tags from the sorted `vocab` which extend `prefix`, ignoring case and any opening quote
+fn complete_tags<'a>(vocab: &'a [String], prefix: &str) -> Vec<&'a str> {
+    let prefix = prefix
+        .trim_start_matches(|c| c == '"' || c == '\'')
+        .to_lowercase();
+    vocab
+        .iter()
+        .filter(|tag| {
+            let tag = tag.to_lowercase();
+            tag.len() > prefix.len() && tag.starts_with(&prefix)
+        })
+        .map(String::as_str)
+        .collect()
+}
+
+#[cfg(test)]
+mod completion_tests {
+    use super::*;
+    #[test]
+    fn cursor_in_tag_token() {
+        assert_eq!(Some("rus"), tag_prefix_at("foo tag:rus", 11));
+        assert_eq!(Some(""), tag_prefix_at("foo tag:", 8));
+        assert_eq!(Some("rus"), tag_prefix_at("foo -TAG:rus", 12));
+        assert_eq!(Some("rus"), tag_prefix_at("tag:rus", 7));
+    }
+
+    #[test]
+    fn cursor_outside_tag_token() {
+        assert_eq!(None, tag_prefix_at("tag:rust ", 9));
+        assert_eq!(None, tag_prefix_at("foo title:rus", 13));
+        assert_eq!(None, tag_prefix_at("tag:rust foo", 12));
+        assert_eq!(None, tag_prefix_at("", 0));
+    }
+
+    #[test]
+    fn cursor_mid_input() {
+        assert_eq!(Some("ru"), tag_prefix_at("tag:rust foo", 6));
+    }
+
+    #[test]
+    fn prefix_matching() {
+        let vocab: Vec<String> = ["python", "Rust", "rustc", "shell scripting", "vim"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        assert_eq!(vec!["Rust", "rustc"], complete_tags(&vocab, "ru"));
+        assert_eq!(vec!["rustc"], complete_tags(&vocab, "rust"));
+        assert_eq!(vec!["shell scripting"], complete_tags(&vocab, "\"sh"));
+        assert_eq!(5, complete_tags(&vocab, "").len());
+        assert!(complete_tags(&vocab, "go").is_empty());
+    }
+}
+
+/// Fewest matches to fetch, used before the list has been drawn
+const MIN_FETCH: usize = 20;
+
+/// How many matches to fetch for a list `height` rows tall with `selected` selected: every row
+/// up to and including the selection, then two more screens to scroll into
+fn fetch_count(height: usize, selected: usize) -> usize {
+    (selected + 1 + 2 * height).max(MIN_FETCH)
+}
+
+/// Whether `selected` is within a screen of the end of `fetched` matches, and the last fetch of
+/// `limit` matches was full so there may be more
+fn near_end(selected: usize, height: usize, fetched: usize, limit: usize) -> bool {
+    fetched >= limit && selected + height >= fetched
+}
+
+#[cfg(test)]
+mod fetch_tests {
+    use super::*;
+    #[test]
+    fn fetch_count_tracks_height() {
+        assert_eq!(MIN_FETCH, fetch_count(0, 0));
+        assert_eq!(81, fetch_count(40, 0));
+        assert_eq!(201, fetch_count(100, 0));
+    }
+
+    #[test]
+    fn fetch_count_tracks_selection() {
+        assert_eq!(131, fetch_count(40, 50));
+    }
+
+    #[test]
+    fn fetching_settles() {
+        // A full fetch sized for the current selection must not immediately ask for more
+        for (height, selected) in [(0, 0), (0, 30), (1, 30), (40, 0), (40, 500)] {
+            let limit = fetch_count(height, selected);
+            assert!(!near_end(selected, height, limit, limit));
+        }
+    }
+
+    #[test]
+    fn near_end_of_full_window() {
+        assert!(!near_end(0, 40, 80, 80));
+        assert!(!near_end(39, 40, 80, 80));
+        assert!(near_end(40, 40, 80, 80));
+        assert!(near_end(79, 40, 80, 80));
+    }
+
+    #[test]
+    fn all_matches_fetched() {
+        // Fewer matches than were asked for means there aren't any more
+        assert!(!near_end(50, 40, 60, 80));
+    }
+}
+
+/// How long the query input must be left alone before the query is run
+const QUERY_DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// Whether a query last edited at `edited` has settled long enough by `now` to be run
+fn query_due(edited: Option<Instant>, now: Instant) -> bool {
+    match edited {
+        Some(edited) => now.saturating_duration_since(edited) >= QUERY_DEBOUNCE,
+        None => false,
+    }
+}
+
+/// Run the current query input against the index, replacing the displayed matches
+fn run_query(app: &mut TerminalApp, db: &mut Database) -> Result<(), Report> {
+    app.query_edited = None;
+
+    let limit = fetch_count(
+        app.list_height(),
+        app.selected_state.selected().unwrap_or(0),
+    );
+    app.fetch_limit = limit;
+    let key = CacheKey::new(&app.query_input, &app.filter_input, app.sort);
+    let dates = app.date_bounds();
+    // The debug pane describes the query whether or not its matches are cached, so while it's
+    // shown the query is built up front, once for both. Otherwise only a query whose matches
+    // aren't cached is built.
+    let mut built = if app.show_debug {
+        Some(build_query(
+            &app.query_input,
+            &app.filter_input,
+            db,
+            &app.parse_options,
+            &dates,
+        ))
+    } else {
+        None
+    };
+    let described = match &mut built {
+        Some(Ok((query, _))) => Some(describe_query(query, db).map_err(|e| e.to_string())),
+        Some(Err(e)) => Some(Err(e.to_string())),
+        None => None,
+    };
+    let fetched = app.cache.get_or_fetch(key, limit, || {
+        let (mut query, corrected) = match built {
+            Some(built) => built?,
+            None => build_query(
+                &app.query_input,
+                &app.filter_input,
+                db,
+                &app.parse_options,
+                &dates,
+            )?,
+        };
+        let mut enq = db.new_enquire()?;
+        enq.set_query(&mut query)?;
+        let opts = SearchOptions {
+            limit,
+            sort: app.sort,
+            reverse: app.reverse,
+            tiebreak: app.tiebreak,
+            min_score: app.min_score,
+            dedupe_by: app.dedupe_by,
+            ..SearchOptions::default()
+        };
+        let matches = sorted_matches(&mut enq, &opts, &document::SerializationType::Preview)?;
+        Ok((matches, corrected))
+    });
+    match fetched {
+        Ok((matches, corrected)) => {
+            app.matches = matches;
+            app.suggestion = suggestion(&app.query_input, corrected, app.matches.len());
+        }
+        Err(e) => {
+            app.error = e.to_string();
+            app.suggestion.clear();
+        }
+    };
+    if let Some(described) = described {
+        app.debug = match described {
+            Ok(described) => query_debug(app, &described),
+            Err(e) => e,
+        };
+    }
+
+    Ok(())
+}
+
+// How `query` was parsed, and about how many notes match it
+fn describe_query(query: &mut Query, db: &mut Database) -> Result<String, Report> {
+    let mut enq = db.new_enquire()?;
+    enq.set_query(query)?;
+    let estimated = enq.get_mset(0, 0)?.get_matches_estimated()?;
+    Ok(format!(
+        "Query: {}\nAbout {} matches",
+        query.get_description(),
+        estimated
+    ))
+}
+
+/// What the debug pane shows about the query just run: `described`, how it was parsed and
+/// about how many notes match it, then how the matches are sorted and filtered
+fn query_debug(app: &TerminalApp, described: &str) -> String {
+    format!(
+        "{}, {} listed\nSorted by {}{}, filter '{}', {}, scoring {}%+",
+        described,
+        app.matches.len(),
+        format!("{:?}", app.sort).to_lowercase(),
+        if app.reverse { " reversed" } else { "" },
+        app.filter_input,
+        match &app.recent {
+            Some(recent) => format!("last {}", recent),
+            None => String::from("any date"),
+        },
+        app.min_score,
+    )
+}
+
+/// The query for the `query_input` and `filter_input` boxes, along with its spelling correction.
+/// An empty query browses every note, narrowed down by the filter.
+fn build_query(
+    query_input: &str,
+    filter_input: &str,
+    db: &mut Database,
+    parse_options: &xapian_utils::ParseOptions,
+    dates: &xapian_utils::DateBounds,
+) -> Result<(Query, String), Report> {
+    let (query, corrected) = if query_input.trim().is_empty() {
+        (xapian_utils::match_all_query()?, String::new())
+    } else {
+        // Add a trailing ` ;` to the query to hint to Nom that it has a "full" string
+        let inp = format!("{} ;", query_input);
+        xapian_utils::parse_user_query_with_correction(&inp, db, parse_options)?
+    };
+    let query = xapian_utils::apply_filter(query, filter_input)?;
+    Ok((xapian_utils::apply_date_bounds(query, dates)?, corrected))
+}
+
+#[cfg(test)]
+mod build_query_tests {
+    use super::xapian_utils::test_utils::*;
+    use super::*;
+    use xapian_rusty::DB_CREATE_OR_OPEN;
+
+    fn titles(db: &mut Database, query_input: &str, filter_input: &str) -> Vec<String> {
+        let (query, _) = build_query(
+            query_input,
+            filter_input,
+            db,
+            &xapian_utils::ParseOptions::default(),
+            &xapian_utils::DateBounds::default(),
+        )
+        .expect("Failed to build query");
+        let mut titles: Vec<String> = xapian_utils::query_db(
+            db.new_enquire().unwrap(),
+            query,
+            document::SerializationType::Preview,
+            100,
+            0,
+        )
+        .expect("Failed to query")
+        .into_iter()
+        .map(|d| d.title)
+        .collect();
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn empty_query_browses_everything() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().to_string_lossy().to_string();
+        index_documents(
+            &db_path,
+            &[
+                note(0, "one", &["rust"]),
+                note(1, "two", &["vim"]),
+                note(2, "three", &[]),
+            ],
+        );
+        let mut db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN).unwrap();
+
+        assert_eq!(vec!["Note 0", "Note 1", "Note 2"], titles(&mut db, "", ""));
+        assert_eq!(
+            vec!["Note 0", "Note 1", "Note 2"],
+            titles(&mut db, "   ", "")
+        );
+        assert_eq!(vec!["Note 1"], titles(&mut db, "", "tag:vim"));
+    }
+
+    #[test]
+    fn debug_after_requery() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().to_string_lossy().to_string();
+        index_documents(
+            &db_path,
+            &[note(0, "one", &["rust"]), note(1, "two", &["vim"])],
+        );
+        let mut db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN).unwrap();
+        let mut app = TerminalApp::new(String::from("one"), &Options::default());
+        app.filter_input = String::from("tag:rust");
+        // Not worked out while the debug pane is hidden
+        app.show_debug = false;
+        run_query(&mut app, &mut db).unwrap();
+        assert!(app.debug.is_empty());
+        assert_eq!(1, app.matches.len());
+
+        app.show_debug = true;
+        run_query(&mut app, &mut db).unwrap();
+        assert!(app.debug.contains("Query: Query("), "{}", app.debug);
+        assert!(app.debug.contains("matches, 1 listed"), "{}", app.debug);
+        assert!(
+            app.debug.contains("Sorted by relevance, filter 'tag:rust'"),
+            "{}",
+            app.debug
+        );
+
+        // Every requery updates it
+        app.sort = Sort::Newest;
+        app.filter_input.clear();
+        run_query(&mut app, &mut db).unwrap();
+        assert!(app.debug.contains("matches, 1 listed"), "{}", app.debug);
+        assert!(
+            app.debug.contains("Sorted by newest, filter ''"),
             "{}",
-            termion::screen::ToMainScreen
+            app.debug
+        );
+    }
+}
+
+/// The list of `matches` titled `title`, the selected one drawn with `symbol` before it
+fn match_list<'a>(
+    matches: Vec<ListItem<'a>>,
+    title: String,
+    styles: &Styles,
+    symbol: &'a str,
+) -> List<'a> {
+    List::new(matches)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(styles.selected())
+        .highlight_symbol(symbol)
+}
+
+#[cfg(test)]
+mod match_list_tests {
+    use super::*;
+    use tui::{backend::TestBackend, buffer::Buffer, style::Modifier, Terminal};
+
+    #[test]
+    fn configured_selection() {
+        let draw = |styles: &Styles, symbol: &str| -> Buffer {
+            let mut terminal = Terminal::new(TestBackend::new(12, 4)).unwrap();
+            let mut state = ListState::default();
+            state.select(Some(1));
+            terminal
+                .draw(|f| {
+                    let items = vec![ListItem::new("rust"), ListItem::new("vim")];
+                    let list = match_list(items, String::from("Matches"), styles, symbol);
+                    f.render_stateful_widget(list, f.size(), &mut state);
+                })
+                .unwrap();
+            terminal.backend().buffer().clone()
+        };
+
+        let buffer = draw(&Styles::new(false), styles::SELECT_SYMBOL);
+        assert_eq!(">", buffer.get(1, 2).symbol);
+        assert!(buffer.get(3, 2).modifier.contains(Modifier::REVERSED));
+        assert!(!buffer.get(3, 1).modifier.contains(Modifier::REVERSED));
+
+        let bold = styles::parse_style("bold,bg:blue").unwrap();
+        let buffer = draw(&Styles::new(false).with_selected(bold), "▶ ");
+        assert_eq!("▶", buffer.get(1, 2).symbol);
+        assert_eq!("v", buffer.get(3, 2).symbol);
+        assert!(buffer.get(3, 2).modifier.contains(Modifier::BOLD));
+        assert!(!buffer.get(3, 2).modifier.contains(Modifier::REVERSED));
+        assert_eq!(tui::style::Color::Blue, buffer.get(3, 2).bg);
+    }
+}
+
+/// Spans the recency filter cycles through, after none
+const RECENCY_PRESETS: [&str; 4] = ["7d", "30d", "90d", "1y"];
+
+/// Recency filter to switch to from `recent` when cycling through them, back to none after the
+/// longest
+fn next_recency(recent: &Option<Recency>) -> Option<Recency> {
+    let next = match recent {
+        None => 0,
+        Some(recent) => match RECENCY_PRESETS
+            .iter()
+            .position(|p| *p == recent.to_string())
+        {
+            Some(i) => i + 1,
+            None => RECENCY_PRESETS.len(),
+        },
+    };
+    RECENCY_PRESETS.get(next).map(|p| p.parse().unwrap())
+}
+
+/// Percent each Alt-+ or Alt-- press raises or lowers the minimum score by
+const MIN_SCORE_STEP: i32 = 10;
+
+/// Minimum score to switch to from `min_score`, one step up or down, kept within 0-100
+fn step_min_score(min_score: i32, up: bool) -> i32 {
+    let step = if up { MIN_SCORE_STEP } else { -MIN_SCORE_STEP };
+    (min_score + step).clamp(0, 100)
+}
+
+#[cfg(test)]
+mod step_min_score_tests {
+    use super::*;
+    #[test]
+    fn stays_within_percent() {
+        assert_eq!(10, step_min_score(0, true));
+        assert_eq!(0, step_min_score(0, false));
+        assert_eq!(100, step_min_score(95, true));
+        assert_eq!(85, step_min_score(95, false));
+    }
+}
+
+/// Sort to switch to from `sort` when cycling through them
+fn next_sort(sort: Sort) -> Sort {
+    match sort {
+        Sort::Relevance => Sort::Newest,
+        Sort::Newest => Sort::Oldest,
+        Sort::Oldest => Sort::Modified,
+        Sort::Modified => Sort::Relevance,
+    }
+}
+
+#[cfg(test)]
+mod next_sort_tests {
+    use super::*;
+    #[test]
+    fn cycles_through_all() {
+        let mut seen = vec![Sort::Relevance];
+        let mut sort = next_sort(Sort::Relevance);
+        while sort != Sort::Relevance {
+            assert!(!seen.contains(&sort));
+            seen.push(sort);
+            sort = next_sort(sort);
+        }
+        assert_eq!(4, seen.len());
+    }
+}
+
+#[cfg(test)]
+mod next_recency_tests {
+    use super::*;
+    #[test]
+    fn cycles_through_presets() {
+        let mut spans = Vec::new();
+        let mut recent = next_recency(&None);
+        while let Some(span) = recent {
+            spans.push(span.to_string());
+            recent = next_recency(&Some(span));
+        }
+        assert_eq!(RECENCY_PRESETS.to_vec(), spans);
+        // A span from --recent that isn't a preset goes back to none
+        assert_eq!(None, next_recency(&Some("14d".parse().unwrap())));
+    }
+}
+
+/// Queries finding fewer matches than this get a spelling suggestion, if there is one
+const FEW_MATCHES: usize = 3;
+
+/// The spelling `corrected` version of `query` worth offering, given it found `found` matches
+fn suggestion(query: &str, corrected: String, found: usize) -> String {
+    if found >= FEW_MATCHES || corrected.trim() == query.trim() {
+        return String::new();
+    }
+    corrected
+}
+
+#[cfg(test)]
+mod suggestion_tests {
+    use super::*;
+    #[test]
+    fn only_for_few_matches() {
+        assert_eq!("kernel", suggestion("kernal", String::from("kernel"), 0));
+        assert_eq!(
+            "kernel",
+            suggestion("kernal", String::from("kernel"), FEW_MATCHES - 1)
+        );
+        assert!(suggestion("kernal", String::from("kernel"), FEW_MATCHES).is_empty());
+    }
+
+    #[test]
+    fn nothing_to_correct() {
+        assert!(suggestion("kernel", String::new(), 0).is_empty());
+        assert!(suggestion("kernel ", String::from("kernel"), 0).is_empty());
+    }
+
+    #[test]
+    fn accepting() {
+        let mut app = TerminalApp::new(String::from("kernal"), &Options::default());
+        assert!(!app.accept_suggestion());
+        assert_eq!("kernal", app.query_input);
+
+        app.suggestion = String::from("kernel");
+        assert!(app.accept_suggestion());
+        assert_eq!("kernel", app.query_input);
+        assert!(app.suggestion.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod debounce_tests {
+    use super::*;
+    #[test]
+    fn nothing_pending() {
+        assert!(!query_due(None, Instant::now()));
+    }
+
+    #[test]
+    fn waits_for_quiet_period() {
+        let edited = Instant::now();
+        assert!(!query_due(Some(edited), edited));
+        assert!(!query_due(Some(edited), edited + QUERY_DEBOUNCE / 2));
+        assert!(query_due(Some(edited), edited + QUERY_DEBOUNCE));
+    }
+
+    #[test]
+    fn later_keystroke_restarts_wait() {
+        let first = Instant::now();
+        let second = first + QUERY_DEBOUNCE / 2;
+        // Only the latest edit time is tracked, so the earlier keystroke never triggers a query
+        assert!(!query_due(Some(second), first + QUERY_DEBOUNCE));
+        assert!(query_due(Some(second), second + QUERY_DEBOUNCE));
+    }
+}
+
+/// Mirror the scrolling done by the List widget so that the first visible row is known: the
+/// offset only moves when needed to keep `selected` within `height` rows
+fn list_offset(offset: usize, selected: Option<usize>, height: usize) -> usize {
+    match selected {
+        Some(i) if i < offset => i,
+        Some(i) if height > 0 && i >= offset + height => i + 1 - height,
+        _ => offset,
+    }
+}
+
+/// Map the (1-based, as reported by termion) row of a mouse click onto the index of the match
+/// drawn there, given the bordered list `area` scrolled by `offset`
+fn click_to_index(y: u16, area: Rect, offset: usize, len: usize) -> Option<usize> {
+    // Skip the top border
+    let first_row = area.y + 1;
+    let last_row = (area.y + area.height).saturating_sub(1);
+    let row = y.checked_sub(1)?;
+    if row < first_row || row >= last_row {
+        return None;
+    }
+    let idx = offset + (row - first_row) as usize;
+    if idx < len {
+        Some(idx)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod mouse_tests {
+    use super::*;
+    #[test]
+    fn click_on_first_row() {
+        let area = Rect::new(0, 2, 40, 10);
+        // Row 3 (0-based) is just below the top border at row 2
+        assert_eq!(Some(0), click_to_index(4, area, 0, 5));
+        assert_eq!(Some(3), click_to_index(7, area, 0, 5));
+    }
+
+    #[test]
+    fn click_on_borders_or_past_end() {
+        let area = Rect::new(0, 2, 40, 10);
+        assert_eq!(None, click_to_index(3, area, 0, 5));
+        assert_eq!(None, click_to_index(12, area, 0, 20));
+        assert_eq!(None, click_to_index(9, area, 0, 5));
+        assert_eq!(None, click_to_index(0, area, 0, 5));
+    }
+
+    #[test]
+    fn click_with_scrolled_list() {
+        let area = Rect::new(0, 0, 40, 10);
+        assert_eq!(Some(12), click_to_index(4, area, 10, 20));
+    }
+
+    #[test]
+    fn offset_follows_selection() {
+        assert_eq!(0, list_offset(0, None, 8));
+        assert_eq!(0, list_offset(0, Some(7), 8));
+        assert_eq!(1, list_offset(0, Some(8), 8));
+        assert_eq!(1, list_offset(1, Some(4), 8));
+        assert_eq!(3, list_offset(5, Some(3), 8));
+    }
+}
+
+/// Each of `tags` as a chip, each preceded by a space to follow on from other text
+fn tag_chips(tags: &[String], styles: &Styles) -> Vec<Span<'static>> {
+    tags.iter()
+        .flat_map(|tag| {
+            [
+                Span::raw(" "),
+                Span::styled(format!(" {} ", tag), styles.tag(tag)),
+            ]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tag_chips_tests {
+    use super::*;
+    #[test]
+    fn one_chip_per_tag() {
+        let styles = Styles::new(false);
+        let chips = tag_chips(&[String::from("rust"), String::from("vim")], &styles);
+        let text: String = chips.iter().map(|s| s.content.as_ref()).collect();
+        assert_eq!("  rust   vim ", text);
+        assert!(tag_chips(&[], &styles).is_empty());
+    }
+}
+
+/// Width of the match list's date column, a `YYYY-MM-DD` day, and what separates it from the
+/// title
+const DATE_COLUMN: usize = 10;
+const COLUMN_GAP: &str = " ";
+/// Between a match's title and its body excerpt
+const SNIPPET_GAP: &str = "  ";
+/// Narrowest body excerpt worth showing
+const MIN_SNIPPET_WIDTH: usize = 10;
+/// Between the columns of a `list_row`
+const LIST_GAP: &str = "  ";
+
+/// `text` cut down to at most `width` columns, ending in `…` where it's cut
+fn truncate(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let mut out = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w + 1 > width {
+            break;
+        }
+        out.push(c);
+        used += w;
+    }
+    if width > 0 {
+        out.push('…');
+    }
+    out
+}
+
+/// `text` fitted to a column exactly `width` wide, truncated or padded with spaces
+fn fit_column(text: &str, width: usize) -> String {
+    let text = truncate(text, width);
+    let pad = width.saturating_sub(text.width());
+    format!("{}{}", text, " ".repeat(pad))
+}
+
+/// A match as a line of plain text, for listing outside of the TUI: its day in the date column,
+/// its title and then its tags, e.g. `2021-06-22  Grep buffers  (vim, search)`
+pub fn list_row(doc: &document::Document) -> String {
+    let mut row = format!(
+        "{}{}{}",
+        fit_column(&doc.date.day(), DATE_COLUMN),
+        LIST_GAP,
+        doc.title
+    );
+    if !doc.tags.is_empty() {
+        row.push_str(&format!("{}({})", LIST_GAP, doc.tags.join(", ")));
+    }
+    row
+}
+
+#[cfg(test)]
+mod column_tests {
+    use super::*;
+    use crate::date::{with_date_format, Date, DateFormat};
+
+    #[test]
+    fn list_rows() {
+        let doc = |title: &str, date: i64, tags: &[&str]| {
+            let mut doc = document::Document::new();
+            doc.title = title.to_string();
+            doc.date = Date::new(date);
+            doc.tags = tags.iter().map(|t| t.to_string()).collect();
+            doc
+        };
+        let docs = [
+            doc("Grep buffers", 1624380496, &["vim", "search"]),
+            doc("Traits", 1577836800, &["rust"]),
+            doc("Untagged", 0, &[]),
+        ];
+        let rows: Vec<String> = with_date_format(DateFormat::new("utc", "%c").unwrap(), || {
+            docs.iter().map(list_row).collect()
+        });
+        assert_eq!(
+            vec![
+                "2021-06-22  Grep buffers  (vim, search)",
+                "2020-01-01  Traits  (rust)",
+                "1970-01-01  Untagged",
+            ],
+            rows
+        );
+    }
+
+    #[test]
+    fn pad_and_truncate() {
+        assert_eq!("2021-06-22", fit_column("2021-06-22", DATE_COLUMN));
+        assert_eq!("rust      ", fit_column("rust", DATE_COLUMN));
+        assert_eq!("", fit_column("rust", 0));
+        assert_eq!("Grep open…", truncate("Grep open buffers", 10));
+        assert_eq!("Grep open buffers", truncate("Grep open buffers", 17));
+        assert_eq!("…", truncate("Grep", 1));
+        // Wide characters aren't split, the column is padded out instead
+        assert_eq!("日本… ", fit_column("日本語のノート", 6));
+        assert_eq!(6, fit_column("日本語のノート", 6).width());
+    }
+}
+
+/// Split `line` into styled segments, where every case-insensitive occurrence of one of the
+/// (lowercase) `terms` gets `mark` patched over `base`
+pub(crate) fn highlight_terms(
+    line: &str,
+    terms: &[String],
+    base: Style,
+    mark: Style,
+) -> Vec<Span<'static>> {
+    // ASCII lowercasing keeps byte offsets identical to `line`
+    let lower = line.to_ascii_lowercase();
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut idx = 0;
+    while idx < line.len() {
+        // Prefer the longest term matching at this position
+        let matched = terms
+            .iter()
+            .filter(|t| !t.is_empty() && lower[idx..].starts_with(t.as_str()))
+            .map(|t| t.len())
+            .max();
+        match matched {
+            Some(len) => {
+                if start < idx {
+                    spans.push(Span::styled(line[start..idx].to_string(), base));
+                }
+                spans.push(Span::styled(
+                    line[idx..idx + len].to_string(),
+                    base.patch(mark),
+                ));
+                idx += len;
+                start = idx;
+            }
+            None => idx += line[idx..].chars().next().map_or(1, |c| c.len_utf8()),
+        }
+    }
+    if start < line.len() || spans.is_empty() {
+        spans.push(Span::styled(line[start..].to_string(), base));
+    }
+    spans
+}
+
+#[cfg(test)]
+mod highlight_tests {
+    use super::*;
+    use tui::style::{Color, Modifier};
+
+    fn mark() -> Style {
+        Style::default().add_modifier(Modifier::BOLD)
+    }
+
+    #[test]
+    fn no_terms() {
+        let spans = highlight_terms("foo bar", &[], Style::default(), mark());
+        assert_eq!(1, spans.len());
+        assert_eq!("foo bar", spans[0].content);
+        assert_eq!(Style::default(), spans[0].style);
+    }
+
+    #[test]
+    fn multiple_matches() {
+        let spans = highlight_terms(
+            "Foo bar foo",
+            &[String::from("foo")],
+            Style::default(),
+            mark(),
+        );
+        let content: Vec<&str> = spans.iter().map(|s| s.content.as_ref()).collect();
+        assert_eq!(vec!["Foo", " bar ", "foo"], content);
+        assert_eq!(mark(), spans[0].style);
+        assert_eq!(Style::default(), spans[1].style);
+        assert_eq!(mark(), spans[2].style);
+    }
+
+    #[test]
+    fn keeps_base_style() {
+        let base = Style::default().fg(Color::Red);
+        let spans = highlight_terms("a rust note", &[String::from("rust")], base, mark());
+        assert_eq!(3, spans.len());
+        assert_eq!(base, spans[0].style);
+        assert_eq!(base.patch(mark()), spans[1].style);
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let spans = highlight_terms(
+            "café rust",
+            &[String::from("rust")],
+            Style::default(),
+            mark(),
+        );
+        assert_eq!("café ", spans[0].content);
+        assert_eq!("rust", spans[1].content);
+    }
+}
+
+pub fn setup_panic() {
+    std::panic::set_hook(Box::new(move |_x| restore_screen()));
+}
+
+/// Leave the alternate screen and show the cursor again. Errors are ignored, this runs while
+/// already on the way out.
+fn restore_screen() {
+    let mut out = stdout();
+    let _ = write!(
+        out,
+        "{}{}",
+        termion::screen::ToMainScreen,
+        termion::cursor::Show
+    );
+    let _ = out.flush();
+}
+
+/// Runs `restore` when dropped, so that the terminal gets put back however `query` exits:
+/// returning, `?`, `bail!` or panicking
+struct TerminalGuard<F: FnMut()> {
+    restore: F,
+}
+
+impl<F: FnMut()> Drop for TerminalGuard<F> {
+    fn drop(&mut self) {
+        (self.restore)();
+    }
+}
+
+#[cfg(test)]
+mod terminal_guard_tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[test]
+    fn restores_on_drop() {
+        let restored = Cell::new(0);
+        {
+            let _guard = TerminalGuard {
+                restore: || restored.set(restored.get() + 1),
+            };
+            assert_eq!(0, restored.get());
+        }
+        assert_eq!(1, restored.get());
+    }
+
+    #[test]
+    fn restores_on_early_return_and_panic() {
+        let restored = Cell::new(0);
+        let fails = || -> Result<(), Report> {
+            let _guard = TerminalGuard {
+                restore: || restored.set(restored.get() + 1),
+            };
+            bail!("Failed to draw");
+        };
+        assert!(fails().is_err());
+        assert_eq!(1, restored.get());
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _guard = TerminalGuard {
+                restore: || restored.set(restored.get() + 1),
+            };
+            panic!("Failed to handle input");
+        }));
+        assert!(result.is_err());
+        assert_eq!(2, restored.get());
+    }
+}
+
+type Tui = tui::Terminal<CrosstermBackend<AlternateScreen<MouseTerminal<RawTerminal<Stdout>>>>>;
+
+/// The TUI along with its input handling, both of which have to make way while another program
+/// runs in the terminal
+struct Screen {
+    tui: Option<Tui>,
+    events: Option<event::Events>,
+}
+
+impl Screen {
+    fn new() -> Result<Screen, Report> {
+        let tui = tui::Terminal::new(CrosstermBackend::new(AlternateScreen::from(
+            MouseTerminal::from(stdout().into_raw_mode()?),
+        )))?;
+        Ok(Screen {
+            tui: Some(tui),
+            events: Some(event::Events::new()),
+        })
+    }
+
+    fn tui(&mut self) -> &mut Tui {
+        self.tui.as_mut().expect("TUI used while suspended")
+    }
+
+    fn events(&self) -> &event::Events {
+        self.events.as_ref().expect("Events used while suspended")
+    }
+
+    /// Hand the terminal back for `f` to run, e.g. an editor, then rebuild the TUI and event loop.
+    /// The outer error is from rebuilding, the inner one is whatever `f` returned.
+    fn with_suspended_tui(
+        &mut self,
+        f: impl FnOnce() -> io::Result<()>,
+    ) -> Result<io::Result<()>, Report> {
+        self.events = None;
+        if let Some(mut tui) = self.tui.take() {
+            tui.clear()?;
+        }
+        let result = f();
+        *self = Screen::new()?;
+        Ok(result)
+    }
+}
+
+/// Name for the temporary copy of the note with `id` handed to the editor or pager, keeping only
+/// characters which are safe in a file name
+fn temp_file_name(id: &str) -> String {
+    let id: String = id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    if id.is_empty() {
+        String::from("mdq.md")
+    } else {
+        format!("mdq-{}.md", id)
+    }
+}
+
+#[cfg(test)]
+mod temp_file_name_tests {
+    use super::*;
+    #[test]
+    fn from_id() {
+        assert_eq!("mdq-3Rg2Zn_W-kqB8.md", temp_file_name("3Rg2Zn_W-kqB8"));
+    }
+
+    #[test]
+    fn unsafe_characters() {
+        assert_eq!("mdq-etcpasswd.md", temp_file_name("../etc/passwd"));
+        assert_eq!("mdq.md", temp_file_name(""));
+        assert_eq!("mdq.md", temp_file_name("/ ."));
+    }
+}
+
+/// The `main` areas of a terminal sized `area`, with debug and error areas below the first when
+/// `debug` is on, and the match list and preview side by side within the first of them
+fn layout(area: Rect, debug: bool) -> (Vec<Rect>, Vec<Rect>) {
+    let main = if debug {
+        // Enable debug and error areas
+        Layout::default()
+            .direction(Direction::Vertical)
+            .margin(1)
+            .constraints(
+                [
+                    // Content Preview Area
+                    Constraint::Percentage(80),
+                    // Debug Message Area
+                    Constraint::Percentage(10),
+                    // Error Message Area
+                    Constraint::Percentage(10),
+                ]
+                .as_ref(),
+            )
+            .split(area)
+    } else {
+        Layout::default()
+            .direction(Direction::Vertical)
+            .margin(1)
+            .constraints([Constraint::Percentage(100)].as_ref())
+            .split(area)
+    };
+
+    let screen = Layout::default()
+        .direction(Direction::Horizontal)
+        .margin(1)
+        .constraints(
+            [
+                // Match results area
+                Constraint::Percentage(50),
+                // Document Preview area
+                Constraint::Percentage(50),
+            ]
+            .as_ref(),
         )
-        .unwrap();
-        print!("");
-    }));
+        .split(main[0]);
+    (main, screen)
+}
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+
+    #[test]
+    fn follows_terminal_size() {
+        let small = Rect::new(0, 0, 80, 24);
+        let large = Rect::new(0, 0, 120, 40);
+        let (_, before) = layout(small, false);
+        let (_, after) = layout(large, false);
+        assert_eq!((38, 38), (before[0].width, before[1].width));
+        assert_eq!((58, 58), (after[0].width, after[1].width));
+        assert_eq!(20, before[0].height);
+        assert_eq!(36, after[0].height);
+        for (area, panes) in [(small, before), (large, after)] {
+            for pane in panes {
+                assert_eq!(pane, pane.intersection(area));
+            }
+        }
+    }
+
+    #[test]
+    fn debug_areas() {
+        let (main, _) = layout(Rect::new(0, 0, 80, 42), true);
+        assert_eq!(3, main.len());
+        assert!(main[0].height > main[1].height);
+    }
+}
+
+/// Run `command`, which may include arguments, on a temporary copy of the selected note with the
+/// TUI suspended
+fn open_selected(
+    app: &mut TerminalApp,
+    screen: &mut Screen,
+    command: &str,
+) -> Result<io::Result<()>, Report> {
+    let id = app
+        .selected_document()
+        .map_or_else(String::new, |doc| doc.id.clone());
+    let dir = Builder::new().prefix("mdq-").tempdir()?;
+    let path = dir.path().join(temp_file_name(&id));
+    std::fs::write(&path, app.get_selected_contents())?;
+    screen.with_suspended_tui(|| run_on_file(command, &path))
+}
+
+/// Run `command`, which may include arguments, e.g. `code --wait`, with `path` as its last one.
+/// A command that can't be found or that fails is an error rather than a panic, so that the
+/// interactive query can report it and carry on.
+pub fn run_on_file(command: &str, path: &Path) -> io::Result<()> {
+    run_on_file_at(command, path, None)
+}
+
+/// Like `run_on_file`, starting at `line` of `path` for commands known to take `+LINE`
+pub fn run_on_file_at(command: &str, path: &Path, line: Option<usize>) -> io::Result<()> {
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command given"))?;
+    let status = Command::new(program)
+        .args(words)
+        .args(line.and_then(|line| line_arg(program, line)))
+        .arg(path)
+        .status()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                e.kind(),
+                format!(
+                    "`{}` not found, set $EDITOR or $PAGER to one that's installed",
+                    program
+                ),
+            ),
+            _ => e,
+        })?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("`{}` exited with {}", program, status),
+        ));
+    }
+    Ok(())
 }
 
+/// Editors and pagers which open a file at a line given before it as `+LINE`
+const LINE_COMMANDS: [&str; 6] = ["vi", "vim", "nvim", "nano", "emacs", "less"];
+
+// The argument for `program` to start at `line`, if it's known to take one
+fn line_arg(program: &str, line: usize) -> Option<String> {
+    let name = Path::new(program).file_name()?.to_str()?;
+    LINE_COMMANDS.contains(&name).then(|| format!("+{}", line))
+}
+
+#[cfg(test)]
+mod run_on_file_tests {
+    use super::*;
+
+    #[test]
+    fn line_args() {
+        assert_eq!(Some(String::from("+8")), line_arg("vim", 8));
+        assert_eq!(Some(String::from("+8")), line_arg("/usr/bin/nvim", 8));
+        assert_eq!(Some(String::from("+1")), line_arg("less", 1));
+        assert_eq!(None, line_arg("code", 8));
+        assert!(run_on_file_at("true", Path::new("note.md"), Some(3)).is_ok());
+    }
+
+    #[test]
+    fn missing_command() {
+        let e = run_on_file("mdq-no-such-editor --wait", Path::new("note.md")).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, e.kind());
+        assert!(e.to_string().contains("mdq-no-such-editor"), "{}", e);
+        assert!(run_on_file("  ", Path::new("note.md")).is_err());
+    }
+
+    #[test]
+    fn exit_status() {
+        assert!(run_on_file("true", Path::new("note.md")).is_ok());
+        let e = run_on_file("false", Path::new("note.md")).unwrap_err();
+        assert!(e.to_string().contains("`false` exited"), "{}", e);
+    }
+}
+
+/// Whether viewing `content_lines` lines needs the pager, rather than printing them straight to a
+/// terminal `term_height` lines tall. A line is kept free for the prompt to return.
+fn should_page(content_lines: usize, term_height: u16, always: bool) -> bool {
+    always || content_lines >= term_height as usize
+}
+
+#[cfg(test)]
+mod should_page_tests {
+    use super::*;
+    #[test]
+    fn fits_on_screen() {
+        assert!(!should_page(0, 24, false));
+        assert!(!should_page(23, 24, false));
+    }
+
+    #[test]
+    fn too_long() {
+        assert!(should_page(24, 24, false));
+        assert!(should_page(100, 24, false));
+        // Unknown terminal size
+        assert!(should_page(1, 0, false));
+    }
+
+    #[test]
+    fn always() {
+        assert!(should_page(0, 24, true));
+    }
+}
+
+/// Indexes the note at a path again after it's changed from the interactive interface, returning
+/// the index opened afresh so that it sees the change
+pub type Reindex<'a> = dyn Fn(&Path) -> Result<Database, Report> + 'a;
+
 /// Interactive query interface
 pub fn query(
     mut db: Database,
     pager: String,
     editor: String,
     starting_query: String,
+    options: Options,
+    reindex: &Reindex,
 ) -> Result<Vec<String>, Report> {
-    let mut tui = tui::Terminal::new(CrosstermBackend::new(AlternateScreen::from(
-        stdout().into_raw_mode().unwrap(),
-    )))
-    .unwrap();
+    // Capture the terminal's original mode and go straight back to it; the TUI below enters raw
+    // mode itself, and drops back out of it when shelling out to the editor or pager
+    let original = stdout().into_raw_mode()?;
+    original.suspend_raw_mode()?;
+    // Declared before the TUI so it's dropped, and restores the terminal, after it
+    let _guard = TerminalGuard {
+        restore: move || {
+            let _ = original.suspend_raw_mode();
+            restore_screen();
+        },
+    };
 
-    let ps = SyntaxSet::load_defaults_newlines();
-    let ts = ThemeSet::load_defaults();
+    let mut screen = Screen::new()?;
 
-    let syntax = ps.find_syntax_by_extension("md").unwrap();
-    // TODO make themes configurable
-    // TODO usr HighlightFile here instead of lines? https://docs.rs/syntect/latest/syntect/easy/struct.HighlightFile.html
-    let mut highlighter = HighlightLines::new(syntax, &ts.themes["Solarized (dark)"]);
-
-    // Setup event handlers
-    let mut events = event::Events::new();
+    let styles = Styles::new(options.color).with_selected(options.select_style);
 
     // Create default app state
-    let mut app = TerminalApp::new(starting_query);
+    let mut app = TerminalApp::new(starting_query, &options);
+    app.tag_vocab = xapian_utils::tag_counts(&mut db)?
+        .into_iter()
+        .map(|(tag, _count)| tag)
+        .collect();
+    app.tag_vocab.sort();
+    run_query(&mut app, &mut db)?;
 
     loop {
+        // Words from the current query, highlighted in the match list and preview
+        let terms = xapian_utils::query_terms(&app.query_input);
+
         // Draw UI
-        if let Err(e) = tui.draw(|f| {
-            let main = if log_enabled!(Level::Debug) {
-                // Enable debug and error areas
-                Layout::default()
-                    .direction(Direction::Vertical)
-                    .margin(1)
-                    .constraints(
-                        [
-                            // Content Preview Area
-                            Constraint::Percentage(80),
-                            // Debug Message Area
-                            Constraint::Percentage(10),
-                            // Error Message Area
-                            Constraint::Percentage(10),
-                        ]
-                        .as_ref(),
-                    )
-                    .split(f.size())
-            } else {
-                Layout::default()
-                    .direction(Direction::Vertical)
-                    .margin(1)
-                    .constraints([Constraint::Percentage(100)].as_ref())
-                    .split(f.size())
-            };
+        if let Err(e) = screen.tui().draw(|f| {
+            // Split up afresh on every draw, so a resized terminal gets laid out to fit
+            let (main, screen) = layout(f.size(), app.show_debug);
 
-            let screen = Layout::default()
-                .direction(Direction::Horizontal)
-                .margin(1)
-                .constraints(
-                    [
-                        // Match results area
-                        Constraint::Percentage(50),
-                        // Document Preview area
-                        Constraint::Percentage(50),
-                    ]
-                    .as_ref(),
-                )
-                .split(main[0]);
-
-            // Preview area where content is displayed
-            let mut preview_text = String::from("");
-            for line in LinesWithEndings::from(app.preview.as_ref()) {
-                let ranges: Vec<(hStyle, &str)> = highlighter.highlight(line, &ps);
-                let escaped = as_24_bit_terminal_escaped(&ranges[..], true);
-                preview_text.push_str(&escaped);
+            // Preview area where content is displayed, headed by the note's date and tags
+            let mut preview_text = match options.preview_mode {
+                PreviewMode::Source => styles.preview(&app.preview),
+                PreviewMode::Rendered => markdown::render(&app.preview, &styles),
+            };
+            if let Some(doc) = app.selected_document() {
+                let mut header = vec![Span::raw(doc.date.to_string())];
+                header.extend(tag_chips(&doc.tags, &styles));
+                preview_text
+                    .lines
+                    .splice(0..0, [Spans::from(header), Spans::from(Vec::<Span>::new())]);
+            }
+            if !terms.is_empty() {
+                for line in preview_text.lines.iter_mut() {
+                    line.0 = line
+                        .0
+                        .iter()
+                        .flat_map(|s| {
+                            highlight_terms(&s.content, &terms, s.style, styles.term_match())
+                        })
+                        .collect();
+                }
             }
-            let preview_text = Paragraph::new::<Text>(ansi_to_text(preview_text.bytes()).unwrap());
+            app.preview_height = screen[1].height;
+            app.preview_lines = preview_text.lines.len();
+            app.preview_scroll =
+                clamp_scroll(app.preview_scroll, 0, app.preview_lines, app.preview_height);
+            let preview_text = Paragraph::new(preview_text)
+                .wrap(Wrap { trim: false })
+                .scroll((app.preview_scroll, 0));
             f.render_widget(preview_text, screen[1]);
 
             // Output area where match titles are displayed
@@ -220,51 +1790,163 @@ pub fn query(
                 )
                 .split(screen[0]);
 
-            let selected_style = Style::default().add_modifier(Modifier::REVERSED);
+            let selected_style = styles.selected();
+            // Inside the borders and past the highlight symbol
+            let list_width = interactive[0].width.saturating_sub(4) as usize;
             let matches: Vec<ListItem> = app
                 .matches
                 .iter()
-                .map(|m| ListItem::new(vec![Spans::from(Span::raw(m.title.to_string()))]))
+                .map(|m| {
+                    // The day in a column of its own, so matches can be scanned by date, then
+                    // the title cut short to fit what's left
+                    let mut line = vec![Span::styled(
+                        format!("{}{}", fit_column(&m.date.day(), DATE_COLUMN), COLUMN_GAP),
+                        styles.snippet(),
+                    )];
+                    let title = truncate(
+                        &m.title,
+                        list_width.saturating_sub(DATE_COLUMN + COLUMN_GAP.len()),
+                    );
+                    line.extend(highlight_terms(
+                        &title,
+                        &terms,
+                        Style::default(),
+                        styles.term_match(),
+                    ));
+                    let chips = tag_chips(&m.tags, &styles);
+                    let used = DATE_COLUMN
+                        + COLUMN_GAP.len()
+                        + title.width()
+                        + chips.iter().map(|s| s.width()).sum::<usize>();
+                    line.extend(chips);
+                    // Whatever room the title and tags leave goes to an excerpt of the body
+                    let room = list_width.saturating_sub(used + SNIPPET_GAP.len());
+                    if room >= MIN_SNIPPET_WIDTH {
+                        line.push(Span::raw(SNIPPET_GAP));
+                        line.extend(highlight_terms(
+                            &m.snippet(&terms, room),
+                            &terms,
+                            styles.snippet(),
+                            styles.term_match(),
+                        ));
+                    }
+                    ListItem::new(vec![Spans::from(line)])
+                })
                 .collect();
-            let matches = List::new(matches)
-                .block(Block::default().borders(Borders::ALL))
-                .highlight_style(selected_style)
-                .highlight_symbol("> ");
+            let title = format!(
+                "Sorted by {}{}{} (Ctrl-o, Alt-+/-), {} (Ctrl-t)",
+                format!("{:?}", app.sort).to_lowercase(),
+                if app.reverse { ", reversed" } else { "" },
+                match app.min_score {
+                    0 => String::new(),
+                    score => format!(", scoring {}%+", score),
+                },
+                match &app.recent {
+                    Some(recent) => format!("last {}", recent),
+                    None => String::from("any date"),
+                }
+            );
+            let matches = match_list(matches, title, &styles, &options.select_symbol);
+            app.list_area = interactive[0];
+            app.list_offset = list_offset(
+                app.list_offset,
+                app.selected_state.selected(),
+                interactive[0].height.saturating_sub(2) as usize,
+            );
             f.render_stateful_widget(matches, interactive[0], &mut app.selected_state);
 
+            // Tag completions pop up over the bottom of the match list, above the query input
+            let completions = app.tag_completions();
+            if !completions.is_empty() {
+                let shown = completions.len().min(MAX_COMPLETIONS);
+                let height = (shown as u16 + 2).min(interactive[0].height);
+                let area = Rect::new(
+                    interactive[1].x,
+                    interactive[1].y.saturating_sub(height),
+                    interactive[1].width.min(40),
+                    height,
+                );
+                let items: Vec<ListItem> = completions
+                    .iter()
+                    .take(shown)
+                    .map(|c| ListItem::new(c.as_str()))
+                    .collect();
+                let completions = List::new(items)
+                    .block(
+                        Block::default()
+                            .title("Tab to complete")
+                            .borders(Borders::ALL),
+                    )
+                    .highlight_style(selected_style);
+                let mut state = ListState::default();
+                state.select(Some(0));
+                f.render_widget(Clear, area);
+                f.render_stateful_widget(completions, area, &mut state);
+            }
+
+            // The tag being added pops up in the same place
+            if let Some(tag) = &app.tag_input {
+                let area = Rect::new(
+                    interactive[1].x,
+                    interactive[1].y.saturating_sub(3),
+                    interactive[1].width.min(40),
+                    3,
+                );
+                let prompt = Paragraph::new(tag.as_str()).style(styles.input()).block(
+                    Block::default()
+                        .title("Tag to add (Enter, Esc)")
+                        .borders(Borders::ALL),
+                );
+                f.render_widget(Clear, area);
+                f.render_widget(prompt, area);
+            }
+
             // Input area where queries are entered
+            let query_title = if app.query_edited.is_some() {
+                String::from("Query input (searching…)")
+            } else if !app.suggestion.is_empty() {
+                format!("Query input (did you mean '{}'? Ctrl-s)", app.suggestion)
+            } else {
+                String::from("Query input")
+            };
             let query_input = Paragraph::new(app.query_input.as_ref())
-                .style(Style::default().fg(Color::Yellow))
+                .style(styles.input())
                 .block(
                     Block::default()
-                        .title("Query input")
+                        .title(query_title)
                         .borders(Borders::TOP | Borders::LEFT | Borders::RIGHT),
                 );
             f.render_widget(query_input, interactive[1]);
 
             // Input area where filters are entered
             let filter_input = Paragraph::new(app.filter_input.as_ref())
-                .style(Style::default().fg(Color::Yellow))
+                .style(styles.input())
                 .block(
                     Block::default()
-                        .title("Filter input (e.g. 'vim | !bash')")
+                        .title("Filter input (e.g. 'tag:project AND author:steve')")
                         .borders(Borders::TOP | Borders::LEFT | Borders::RIGHT),
                 );
             f.render_widget(filter_input, interactive[2]);
 
             // Make the cursor visible and ask tui-rs to put it at the specified
             // coordinates after rendering
+            let input_area = interactive[app.inp_idx + 1];
             f.set_cursor(
                 // Put cursor past the end of the input text
-                // TODO refactor input area switching
-                interactive[app.inp_idx + 1].x + 1 + app.inp_widths[app.inp_idx] as u16,
-                interactive[app.inp_idx + 1].y + 1,
+                cursor_x(input_area, app.current_input()),
+                input_area.y + 1,
             );
 
-            if log_enabled!(Level::Debug) {
-                // Area to display debug messages
-                let debug = Paragraph::new(app.debug.as_ref())
-                    .style(Style::default().fg(Color::Green).bg(Color::Black))
+            if app.show_debug {
+                // Area to display debug messages, along with the selected match's relevance
+                let debug_text = match app.selected_document() {
+                    Some(doc) => {
+                        format!("Score: {:.3} ({}%)\n{}", doc.score, doc.percent, app.debug)
+                    }
+                    None => app.debug.clone(),
+                };
+                let debug = Paragraph::new(debug_text)
+                    .style(styles.debug())
                     .block(
                         Block::default()
                             .title("Debug messages")
@@ -275,7 +1957,7 @@ pub fn query(
 
                 // Area to display Error messages
                 let error = Paragraph::new(app.error.as_ref())
-                    .style(Style::default().fg(Color::Red).bg(Color::Black))
+                    .style(styles.error())
                     .block(
                         Block::default()
                             .title("Error messages")
@@ -285,19 +1967,42 @@ pub fn query(
                 f.render_widget(error, main[2]);
             }
         }) {
-            tui.clear().unwrap();
-            drop(tui);
             bail!("Failed to draw TUI App {}", e.to_string());
         }
 
-        // Handle input
-        match events.next() {
+        // Now that the list size is known, fill it or extend it as the selection nears the end
+        if app.needs_more() {
+            run_query(&mut app, &mut db)?;
+            continue;
+        }
+
+        // Handle input, ticks carry no state change so wait for a real event before redrawing.
+        // While an edited query is pending, wake up once it's due and run it instead
+        let ev = loop {
+            let ev = match app.query_edited {
+                Some(edited) => screen
+                    .events()
+                    .next_timeout(QUERY_DEBOUNCE.saturating_sub(edited.elapsed())),
+                None => screen.events().next().map(Some),
+            };
+            match ev {
+                Ok(Some(event::Event::Tick)) => continue,
+                Ok(None) if query_due(app.query_edited, Instant::now()) => {
+                    run_query(&mut app, &mut db)?;
+                    break Ok(None);
+                }
+                Ok(None) => continue,
+                ev => break ev,
+            }
+        };
+        match ev {
             Err(e) => {
-                tui.clear().unwrap();
-                drop(tui);
                 bail!("Failed to handle input {}", e.to_string());
             }
-            Ok(ev) => {
+            // The debounced query ran, or the terminal was resized, just redraw
+            Ok(None) | Ok(Some(event::Event::Resize(..))) => {}
+            Ok(Some(event::Event::Mouse(mouse))) => app.handle_mouse(mouse),
+            Ok(Some(ev)) => {
                 if let event::Event::Input(input) = ev {
                     // TODO add support for:
                     //  - ctrl-e to open selected in $EDITOR, then submit on file close
@@ -305,8 +2010,44 @@ pub fn query(
                     //  - ctrl-jkdu for navigating displayed selection
                     //  - ctrl-hl for navigating between links
                     //  - Limit query and filter input box length
-                    //  - +/- (and return) to modify weight
+                    //  - +/- (and return) to modify weight, Alt-+/- being the minimum score
                     //  - ctrl-m to toggle displaying frontmatter metadata (off by default)
+                    if let Some(tag) = app.tag_input.as_mut() {
+                        match input {
+                            Key::Char('\n') => {
+                                let tag = app.tag_input.take().unwrap_or_default();
+                                match app.tag_selected(&tag, reindex) {
+                                    Ok(Some(reopened)) => {
+                                        // The note's old matches are stale now
+                                        db = reopened;
+                                        app.cache.invalidate();
+                                        run_query(&mut app, &mut db)?;
+                                        app.show_selected();
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => app.error = format!("Failed to add tag: {:#}", e),
+                                }
+                            }
+                            Key::Esc | Key::Ctrl('c') => app.tag_input = None,
+                            Key::Char(c) => tag.push(c),
+                            Key::Backspace => {
+                                tag.pop();
+                            }
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    if let Some(binding) = options.key_bindings.iter().find(|b| b.key == input) {
+                        if let Some(doc) = app.selected_document() {
+                            let command = keys::expand(&binding.command, &doc.fullpath, &doc.id)?;
+                            if let Err(e) =
+                                screen.with_suspended_tui(|| keys::run_shell(&command))?
+                            {
+                                app.error = format!("Failed to run {}: {}", command, e);
+                            }
+                        }
+                        continue;
+                    }
                     match input {
                         Key::Char('\n') => {
                             // Select choice
@@ -316,6 +2057,11 @@ pub fn query(
                         Key::Ctrl('c') => {
                             break;
                         }
+                        // Tab completes a tag when there's a suggestion, otherwise it switches
+                        // input areas
+                        Key::Char('\t') if app.accept_completion() => {
+                            app.query_edited = Some(Instant::now());
+                        }
                         Key::Left | Key::Right | Key::Char('\t') => {
                             app.inp_idx = match app.inp_idx {
                                 1 => 0,
@@ -323,135 +2069,126 @@ pub fn query(
                             };
                         }
                         Key::Char(c) => {
-                            if app.inp_idx == 0 {
-                                app.query_input.push(c);
-                            } else {
-                                app.filter_input.push(c);
+                            let max_input_len = app.max_input_len;
+                            let input = app.current_input();
+                            if input.chars().count() < max_input_len {
+                                input.push(c);
+                                app.query_edited = Some(Instant::now());
                             }
-                            app.inp_widths[app.inp_idx] += 1;
                         }
                         Key::Backspace => {
-                            // TODO prevent this from going to far back
-                            if app.inp_idx == 0 {
-                                app.query_input.pop();
-                            } else {
-                                app.filter_input.pop();
+                            if app.current_input().pop().is_some() {
+                                app.query_edited = Some(Instant::now());
                             }
-                            app.inp_widths[app.inp_idx] -= 1;
                         }
                         Key::Ctrl('e') => {
-                            // Temporarily drop the TUI app and event handling while
-                            // we shell out to EDITOR, restore these on return
-                            //events.tx.send("q");
-                            drop(events);
-                            tui.clear().unwrap();
-                            drop(tui);
-                            // TODO get rid of the random bytes here and use the doc id as part of
-                            // the prefix
-                            let mut tf = Builder::new()
-                                .prefix("mdq-")
-                                .suffix(".md")
-                                .rand_bytes(5)
-                                .tempfile()?;
-                            tf.write_all(app.get_selected_contents().as_bytes())?;
-                            let editor = editor.clone();
-                            let mut editor = editor.split_whitespace();
-                            let mut cmd = Command::new(editor.next().unwrap());
-                            for arg in editor {
-                                cmd.arg(arg);
+                            if let Err(e) = open_selected(&mut app, &mut screen, &editor)? {
+                                app.error = format!("Failed to run {}: {}", editor, e);
+                            }
+                        }
+                        Key::Ctrl('v')
+                            if !should_page(
+                                app.get_selected_contents().lines().count(),
+                                termion::terminal_size().map_or(0, |(_, height)| height),
+                                options.pager_always,
+                            ) =>
+                        {
+                            // Short enough to print on the main screen, keeping the TUI and event
+                            // handling around to wait for a key before switching back
+                            let contents = app.get_selected_contents();
+                            let mut out = stdout();
+                            write!(out, "{}", termion::screen::ToMainScreen)?;
+                            write!(
+                                out,
+                                "{}{}",
+                                termion::clear::All,
+                                termion::cursor::Goto(1, 1)
+                            )?;
+                            // Still in raw mode, so every line needs its carriage return
+                            for line in contents.lines() {
+                                write!(out, "{}\r\n", line)?;
                             }
-                            cmd.arg(tf.path())
-                                .status()
-                                .expect("failed to execute process");
-                            events = event::Events::new();
-                            tui = tui::Terminal::new(CrosstermBackend::new(AlternateScreen::from(
-                                stdout().into_raw_mode().unwrap(),
-                            )))
-                            .unwrap();
+                            write!(out, "-- Press any key to return --")?;
+                            out.flush()?;
+                            while !matches!(
+                                screen.events().next(),
+                                Ok(event::Event::Input(_)) | Err(_)
+                            ) {}
+                            write!(out, "{}", termion::screen::ToAlternateScreen)?;
+                            out.flush()?;
+                            screen.tui().clear()?;
                         }
                         Key::Ctrl('v') => {
-                            // Temporarily drop the TUI app and event handling while
-                            // we shell out to less, restore these on return
-                            //events.tx.send("q");
-                            drop(events);
-                            tui.clear().unwrap();
-                            drop(tui);
-                            // TODO get rid of the random bytes here and use the doc id as part of
-                            // the prefix
-                            let mut tf = Builder::new()
-                                .prefix("mdq-")
-                                .suffix(".md")
-                                .rand_bytes(5)
-                                .tempfile()?;
-                            tf.write_all(app.get_selected_contents().as_bytes())?;
-                            let viewer = pager.clone();
-                            // Support setting PAGER="bat --paging always"
-                            let mut viewer = viewer.split_whitespace();
-                            let mut cmd = Command::new(viewer.next().unwrap());
-                            for arg in viewer {
-                                cmd.arg(arg);
+                            // Supports setting PAGER="bat --paging always"
+                            if let Err(e) = open_selected(&mut app, &mut screen, &pager)? {
+                                app.error = format!("Failed to run {}: {}", pager, e);
                             }
-                            cmd.arg(tf.path())
-                                .status()
-                                .expect("failed to execute process");
-                            events = event::Events::new();
-                            tui = tui::Terminal::new(CrosstermBackend::new(AlternateScreen::from(
-                                stdout().into_raw_mode().unwrap(),
-                            )))
-                            .unwrap();
+                        }
+                        // Accept the spelling suggestion
+                        Key::Ctrl('s') => {
+                            if app.accept_suggestion() {
+                                run_query(&mut app, &mut db)?;
+                            }
+                        }
+                        // Scroll the preview by half its height
+                        Key::Ctrl('d') => app.scroll_preview(app.preview_height as i32 / 2),
+                        Key::Ctrl('u') => app.scroll_preview(-(app.preview_height as i32 / 2)),
+                        // Cycle through the sort orders
+                        Key::Ctrl('o') => {
+                            app.sort = next_sort(app.sort);
+                            run_query(&mut app, &mut db)?;
+                        }
+                        // Cycle through the recency filters
+                        Key::Ctrl('t') => {
+                            app.recent = next_recency(&app.recent);
+                            // Cached matches don't know which filter they were found with
+                            app.cache.invalidate();
+                            run_query(&mut app, &mut db)?;
+                        }
+                        // Prompt for a tag to add to the selected note, plain t being typed
+                        Key::Alt('t') if app.selected_document().is_some() => {
+                            app.tag_input = Some(String::new());
+                        }
+                        // Plain + and - are typed into the query, e.g. to exclude a word
+                        Key::Alt('+') | Key::Alt('=') | Key::Alt('-') => {
+                            app.min_score = step_min_score(app.min_score, input != Key::Alt('-'));
+                            app.cache.invalidate();
+                            run_query(&mut app, &mut db)?;
                         }
                         Key::Down | Key::Ctrl('n') => {
                             app.next();
-                            app.preview = app.get_selected_contents();
+                            app.show_selected();
                         }
                         Key::Up | Key::Ctrl('p') => {
                             app.previous();
-                            app.preview = app.get_selected_contents();
+                            app.show_selected();
                         }
                         _ => {}
                     }
-
-                    let mut inp: String = app.query_input.to_owned();
-                    // Add a trailing ` ;` to the query to hint to Nom that it has a "full" string
-                    inp.push_str(" ;");
-
-                    let enq = db.new_enquire()?;
-                    match xapian_utils::parse_user_query(&inp) {
-                        Ok(query) => {
-                            //app.query = query.get_description();
-                            app.matches = xapian_utils::query_db(
-                                enq,
-                                query,
-                                document::SerializationType::Preview,
-                            )?;
-                        }
-                        Err(e) => {
-                            app.error = e.to_string();
-                        }
-                    };
                 }
             }
         }
     }
 
-    tui.clear().unwrap();
-
     Ok(app.get_selected())
 }
 
 pub mod event {
 
-    use std::io;
+    use std::io::{self, Read};
     use std::sync::mpsc;
     use std::thread;
     use std::time::Duration;
 
-    use termion::event::Key;
+    use termion::event::{Event as TermEvent, Key, MouseEvent};
     use termion::input::TermRead;
 
     pub enum Event<I> {
         Input(I),
+        Mouse(MouseEvent),
         Tick,
+        /// The terminal is now this many columns wide and rows tall
+        Resize(u16, u16),
     }
 
     /// A small event handler that wrap termion input and tick events. Each event
@@ -461,18 +2198,26 @@ pub mod event {
         #[allow(dead_code)]
         input_handle: thread::JoinHandle<()>,
         #[allow(dead_code)]
-        tick_handle: thread::JoinHandle<()>,
+        tick_handle: Option<thread::JoinHandle<()>>,
+        #[allow(dead_code)]
+        resize_handle: Option<thread::JoinHandle<()>>,
     }
 
     #[derive(Debug, Clone, Copy)]
     pub struct Config {
-        pub tick_rate: Duration,
+        /// How often to emit `Event::Tick`, `None` disables ticks so that `next` only returns
+        /// on real input
+        pub tick_rate: Option<Duration>,
+        /// How often to check the terminal's size, sending `Event::Resize` when it changes.
+        /// termion has no resize event, and polling avoids handling SIGWINCH ourselves.
+        pub resize_poll: Option<Duration>,
     }
 
     impl Default for Config {
         fn default() -> Config {
             Config {
-                tick_rate: Duration::from_millis(250),
+                tick_rate: None,
+                resize_poll: Some(Duration::from_millis(100)),
             }
         }
     }
@@ -489,37 +2234,119 @@ pub mod event {
         }
 
         pub fn with_config(config: Config) -> Events {
+            Events::from_reader(io::stdin(), config)
+        }
+
+        /// Read input events from any reader rather than stdin
+        pub fn from_reader<R: Read + Send + 'static>(reader: R, config: Config) -> Events {
             let (tx, rx) = mpsc::channel();
             let input_handle = {
                 let tx = tx.clone();
                 thread::spawn(move || {
-                    let stdin = io::stdin();
-                    for evt in stdin.keys().flatten() {
-                        if let Err(err) = tx.send(Event::Input(evt)) {
+                    for evt in reader.events().flatten() {
+                        let evt = match evt {
+                            TermEvent::Key(key) => Event::Input(key),
+                            TermEvent::Mouse(mouse) => Event::Mouse(mouse),
+                            TermEvent::Unsupported(_) => continue,
+                        };
+                        if let Err(err) = tx.send(evt) {
                             dbg!(err);
                             return;
                         }
                     }
                 })
             };
-            let tick_handle = {
+            let resize_handle = config.resize_poll.map(|poll| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let mut last = termion::terminal_size().ok();
+                    loop {
+                        thread::sleep(poll);
+                        let size = termion::terminal_size().ok();
+                        if size == last {
+                            continue;
+                        }
+                        last = size;
+                        if let Some((width, height)) = size {
+                            if tx.send(Event::Resize(width, height)).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            });
+            let tick_handle = config.tick_rate.map(|tick_rate| {
                 thread::spawn(move || loop {
                     if let Err(err) = tx.send(Event::Tick) {
                         dbg!(err);
                         break;
                     }
-                    thread::sleep(config.tick_rate);
+                    thread::sleep(tick_rate);
                 })
-            };
+            });
             Events {
                 rx,
                 input_handle,
                 tick_handle,
+                resize_handle,
             }
         }
 
         pub fn next(&self) -> Result<Event<Key>, mpsc::RecvError> {
             self.rx.recv()
         }
+
+        /// Like `next`, but give up and return `None` once `timeout` passes without an event
+        pub fn next_timeout(
+            &self,
+            timeout: Duration,
+        ) -> Result<Option<Event<Key>>, mpsc::RecvError> {
+            match self.rx.recv_timeout(timeout) {
+                Ok(ev) => Ok(Some(ev)),
+                Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+                Err(mpsc::RecvTimeoutError::Disconnected) => Err(mpsc::RecvError),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod events_tests {
+        use super::*;
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+
+        #[test]
+        fn no_ticks_blocks_until_input() {
+            let (reader, mut writer) = UnixStream::pair().expect("Failed to create socket pair");
+            let events = Events::from_reader(
+                reader,
+                Config {
+                    tick_rate: None,
+                    resize_poll: None,
+                },
+            );
+
+            // Nothing should arrive while there is no input
+            assert!(matches!(
+                events.rx.recv_timeout(Duration::from_millis(300)),
+                Err(mpsc::RecvTimeoutError::Timeout)
+            ));
+
+            writer.write_all(b"q").expect("Failed to write input");
+            assert!(matches!(events.next(), Ok(Event::Input(Key::Char('q')))));
+        }
+
+        #[test]
+        fn ticks_when_enabled() {
+            let (reader, _writer) = UnixStream::pair().expect("Failed to create socket pair");
+            let events = Events::from_reader(
+                reader,
+                Config {
+                    tick_rate: Some(Duration::from_millis(10)),
+                    resize_poll: None,
+                },
+            );
+            assert!(matches!(events.next(), Ok(Event::Tick)));
+        }
     }
 }
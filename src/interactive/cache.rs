@@ -0,0 +1,199 @@
+use crate::document::Document;
+use crate::search::Sort;
+use color_eyre::Report;
+use std::collections::VecDeque;
+
+/// What a set of matches was fetched for. Queries differing only in whitespace are the same.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheKey {
+    query: String,
+    filter: String,
+    sort: Sort,
+}
+
+impl CacheKey {
+    pub fn new(query: &str, filter: &str, sort: Sort) -> CacheKey {
+        CacheKey {
+            query: normalize(query),
+            filter: normalize(filter),
+            sort,
+        }
+    }
+}
+
+fn normalize(input: &str) -> String {
+    input.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+struct Entry {
+    key: CacheKey,
+    // How many matches were asked for
+    limit: usize,
+    matches: Vec<Document>,
+    suggestion: String,
+}
+
+impl Entry {
+    // Whether this holds the first `limit` matches, either because as many were asked for or
+    // because there weren't any more
+    fn covers(&self, limit: usize) -> bool {
+        self.limit >= limit || self.matches.len() < self.limit
+    }
+}
+
+/// The matches of recently run queries, so that running one again, e.g. after deleting a
+/// character and typing it back, doesn't go back to the index. Past `capacity` queries the
+/// least recently used is dropped, a capacity of 0 caches nothing.
+pub struct ResultCache {
+    capacity: usize,
+    // Least recently used first
+    entries: VecDeque<Entry>,
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize) -> ResultCache {
+        ResultCache {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// The first `limit` matches for `key` along with the spelling suggestion for it, from
+    /// `fetch` unless they're cached already. Failures aren't cached.
+    pub fn get_or_fetch<F>(
+        &mut self,
+        key: CacheKey,
+        limit: usize,
+        fetch: F,
+    ) -> Result<(Vec<Document>, String), Report>
+    where
+        F: FnOnce() -> Result<(Vec<Document>, String), Report>,
+    {
+        if let Some(i) = self
+            .entries
+            .iter()
+            .position(|e| e.key == key && e.covers(limit))
+        {
+            let entry = self.entries.remove(i).unwrap();
+            let found = (
+                entry.matches.iter().take(limit).cloned().collect(),
+                entry.suggestion.clone(),
+            );
+            self.entries.push_back(entry);
+            return Ok(found);
+        }
+
+        let (matches, suggestion) = fetch()?;
+        if self.capacity > 0 {
+            self.entries.retain(|e| e.key != key);
+            if self.entries.len() >= self.capacity {
+                self.entries.pop_front();
+            }
+            self.entries.push_back(Entry {
+                key,
+                limit,
+                matches: matches.clone(),
+                suggestion: suggestion.clone(),
+            });
+        }
+        Ok((matches, suggestion))
+    }
+
+    /// Forget every cached result, for after the index has been written to or the matches
+    /// have been filtered differently
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod result_cache_tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // `count` matches titled by number, counting each fetch in `calls`
+    fn fetch(calls: &Cell<usize>, count: usize) -> Result<(Vec<Document>, String), Report> {
+        calls.set(calls.get() + 1);
+        let matches = (0..count)
+            .map(|i| {
+                let mut doc = Document::new();
+                doc.title = i.to_string();
+                doc
+            })
+            .collect();
+        Ok((matches, String::new()))
+    }
+
+    #[test]
+    fn identical_queries_fetch_once() {
+        let calls = Cell::new(0);
+        let mut cache = ResultCache::new(8);
+        let first = cache
+            .get_or_fetch(CacheKey::new("rust ", "", Sort::Relevance), 10, || {
+                fetch(&calls, 10)
+            })
+            .unwrap();
+        let second = cache
+            .get_or_fetch(CacheKey::new(" rust", "", Sort::Relevance), 10, || {
+                fetch(&calls, 10)
+            })
+            .unwrap();
+        assert_eq!(1, calls.get());
+        assert_eq!(first.0, second.0);
+
+        // Fewer matches come out of the cache, more need fetching unless there aren't any
+        let key = CacheKey::new("rust", "", Sort::Relevance);
+        let fewer = cache
+            .get_or_fetch(key.clone(), 5, || fetch(&calls, 5))
+            .unwrap();
+        assert_eq!(5, fewer.0.len());
+        assert_eq!(1, calls.get());
+        cache
+            .get_or_fetch(key.clone(), 20, || fetch(&calls, 12))
+            .unwrap();
+        assert_eq!(2, calls.get());
+        cache.get_or_fetch(key, 40, || fetch(&calls, 12)).unwrap();
+        assert_eq!(2, calls.get());
+
+        // A different sort is a different query
+        cache
+            .get_or_fetch(CacheKey::new("rust", "", Sort::Newest), 10, || {
+                fetch(&calls, 10)
+            })
+            .unwrap();
+        assert_eq!(3, calls.get());
+    }
+
+    #[test]
+    fn write_invalidates() {
+        let calls = Cell::new(0);
+        let mut cache = ResultCache::new(8);
+        let key = CacheKey::new("rust", "tag:vim", Sort::Relevance);
+        cache
+            .get_or_fetch(key.clone(), 10, || fetch(&calls, 3))
+            .unwrap();
+        cache.invalidate();
+        cache.get_or_fetch(key, 10, || fetch(&calls, 3)).unwrap();
+        assert_eq!(2, calls.get());
+    }
+
+    #[test]
+    fn least_recently_used_dropped() {
+        let calls = Cell::new(0);
+        let mut cache = ResultCache::new(2);
+        let key = |q: &str| CacheKey::new(q, "", Sort::Relevance);
+        for q in ["a", "b", "a", "c", "a", "b"] {
+            cache.get_or_fetch(key(q), 10, || fetch(&calls, 1)).unwrap();
+        }
+        // `b` was dropped for `c`, having been used less recently than `a`
+        assert_eq!(4, calls.get());
+
+        let mut uncached = ResultCache::new(0);
+        for _ in 0..2 {
+            uncached
+                .get_or_fetch(key("a"), 10, || fetch(&calls, 1))
+                .unwrap();
+        }
+        assert_eq!(6, calls.get());
+    }
+}
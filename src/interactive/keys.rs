@@ -0,0 +1,193 @@
+use color_eyre::Report;
+use eyre::{eyre, WrapErr};
+use std::collections::BTreeMap;
+use std::io;
+use std::process::Command;
+use termion::event::Key;
+
+/// A key which runs a shell command on the selected note, set up in the config's `[keys]` table
+/// like `"ctrl-g" = "todo add {path}"`. Checked before the built-in keys, so it can take over
+/// one of them, e.g. Ctrl-e for a different editor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyBinding {
+    pub key: Key,
+    /// Run by `sh -c` once `{path}` and `{id}` are filled in, see `expand`
+    pub command: String,
+}
+
+/// Parse the config's `[keys]` table, failing on any key or command that couldn't be run so that
+/// mistakes show up at startup rather than on a keypress
+pub fn parse_bindings(keys: &BTreeMap<String, String>) -> Result<Vec<KeyBinding>, Report> {
+    keys.iter()
+        .map(|(key, command)| {
+            parse_binding(key, command).wrap_err_with(|| format!("in key binding '{}'", key))
+        })
+        .collect()
+}
+
+fn parse_binding(key: &str, command: &str) -> Result<KeyBinding, Report> {
+    let key = parse_key(key)?;
+    if command.trim().is_empty() {
+        return Err(eyre!("No command given"));
+    }
+    expand(command, "", "")?;
+    Ok(KeyBinding {
+        key,
+        command: command.to_string(),
+    })
+}
+
+/// Parse a key like `ctrl-g`, `alt-x` or `f5`. Plain characters are typed into the query, so
+/// they can't be bound.
+pub fn parse_key(s: &str) -> Result<Key, Report> {
+    let lower = s.to_lowercase();
+    let single = |rest: &str| -> Option<char> {
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+    let key = if let Some(rest) = lower.strip_prefix("ctrl-") {
+        single(rest).map(Key::Ctrl)
+    } else if let Some(rest) = lower.strip_prefix("alt-") {
+        single(rest).map(Key::Alt)
+    } else if let Some(n) = lower.strip_prefix('f') {
+        n.parse::<u8>()
+            .ok()
+            .filter(|n| (1..=12).contains(n))
+            .map(Key::F)
+    } else {
+        None
+    };
+    key.ok_or_else(|| {
+        eyre!(
+            "Unknown key '{}', expected one like ctrl-g, alt-x or f5; plain keys are typed into \
+             the query",
+            s
+        )
+    })
+}
+
+/// `template` with `{path}` and `{id}` replaced by the note's, quoted for the shell. Other
+/// `{name}`s are an error, while braces not around a name, or following `$` as in `${HOME}`,
+/// are left for the shell.
+pub fn expand(template: &str, path: &str, id: &str) -> Result<String, Report> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let (before, after) = rest.split_at(open);
+        out.push_str(before);
+        let name = after[1..]
+            .find('}')
+            .map(|close| &after[1..close + 1])
+            .filter(|name| {
+                !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+            });
+        match name {
+            Some(name) if !before.ends_with('$') => {
+                let value = match name {
+                    "path" => path,
+                    "id" => id,
+                    _ => {
+                        return Err(eyre!(
+                            "Unknown placeholder `{{{}}}` in '{}', expected {{path}} or {{id}}",
+                            name,
+                            template
+                        ))
+                    }
+                };
+                out.push_str(&shell_quote(value));
+                rest = &after[name.len() + 2..];
+            }
+            _ => {
+                out.push('{');
+                rest = &after[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// `s` single quoted, so the shell takes it as one word whatever's in it
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Run `command` with `sh -c`, failing if it can't be started or exits unsuccessfully
+pub fn run_shell(command: &str) -> io::Result<()> {
+    let status = Command::new("sh").arg("-c").arg(command).status()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("`{}` exited with {}", command, status),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod keys_tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(Key::Ctrl('g'), parse_key("ctrl-g").unwrap());
+        assert_eq!(Key::Alt('x'), parse_key("Alt-X").unwrap());
+        assert_eq!(Key::F(5), parse_key("f5").unwrap());
+        for bad in ["g", "ctrl-", "ctrl-gg", "f13", "shift-g", ""] {
+            assert!(parse_key(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bindings() {
+        let keys = BTreeMap::from([
+            (String::from("ctrl-g"), String::from("todo add {path}")),
+            (String::from("f2"), String::from("markdownlint {path}")),
+        ]);
+        assert_eq!(
+            vec![
+                KeyBinding {
+                    key: Key::Ctrl('g'),
+                    command: String::from("todo add {path}"),
+                },
+                KeyBinding {
+                    key: Key::F(2),
+                    command: String::from("markdownlint {path}"),
+                },
+            ],
+            parse_bindings(&keys).unwrap()
+        );
+
+        let bad_key = BTreeMap::from([(String::from("q"), String::from("true"))]);
+        let e = parse_bindings(&bad_key).unwrap_err();
+        assert!(format!("{:#}", e).contains("'q'"), "{:#}", e);
+        let bad_field = BTreeMap::from([(String::from("ctrl-g"), String::from("todo {title}"))]);
+        let e = parse_bindings(&bad_field).unwrap_err();
+        assert!(format!("{:#}", e).contains("{title}"), "{:#}", e);
+        let empty = BTreeMap::from([(String::from("ctrl-g"), String::from(" "))]);
+        assert!(parse_bindings(&empty).is_err());
+    }
+
+    #[test]
+    fn templates() {
+        assert_eq!(
+            "todo add '/notes/vim.md' --ref 'abc123'",
+            expand("todo add {path} --ref {id}", "/notes/vim.md", "abc123").unwrap()
+        );
+        assert_eq!(
+            "cat '/notes/it'\\''s here.md'",
+            expand("cat {path}", "/notes/it's here.md", "").unwrap()
+        );
+        // Braces which aren't placeholders are the shell's
+        assert_eq!(
+            "awk '{print $1}' '/n.md' > ${HOME}/out",
+            expand("awk '{print $1}' {path} > ${HOME}/out", "/n.md", "").unwrap()
+        );
+        assert!(expand("todo {title}", "/n.md", "").is_err());
+        assert!(run_shell("true").is_ok());
+        assert!(run_shell("exit 3").is_err());
+    }
+}
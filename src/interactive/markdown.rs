@@ -0,0 +1,279 @@
+use super::styles::Styles;
+use pulldown_cmark::{Event, HeadingLevel, Parser, Tag};
+use tui::{
+    style::Style,
+    text::{Span, Spans, Text},
+};
+
+/// Render `markdown` for reading rather than as source: headings bold, code dimmed, lists
+/// bulleted and indented, links underlined
+pub fn render(markdown: &str, styles: &Styles) -> Text<'static> {
+    let mut renderer = Renderer {
+        styles,
+        lines: Vec::new(),
+        line: Vec::new(),
+        stack: Vec::new(),
+        lists: Vec::new(),
+        quotes: 0,
+        in_code_block: false,
+    };
+    for event in Parser::new(markdown) {
+        renderer.event(event);
+    }
+    renderer.flush();
+    while renderer.lines.last().map_or(false, |l| l.0.is_empty()) {
+        renderer.lines.pop();
+    }
+    Text::from(renderer.lines)
+}
+
+struct Renderer<'s> {
+    styles: &'s Styles,
+    lines: Vec<Spans<'static>>,
+    // Spans of the line being built
+    line: Vec<Span<'static>>,
+    // Styles of the enclosing elements, innermost last
+    stack: Vec<Style>,
+    // Next number for each enclosing ordered list, None for bulleted ones
+    lists: Vec<Option<u64>>,
+    quotes: usize,
+    in_code_block: bool,
+}
+
+impl<'s> Renderer<'s> {
+    fn style(&self) -> Style {
+        self.stack
+            .iter()
+            .fold(Style::default(), |style, s| style.patch(*s))
+    }
+
+    fn push(&mut self, text: String) {
+        let style = self.style();
+        self.line.push(Span::styled(text, style));
+    }
+
+    // End the current line, if anything is on it
+    fn flush(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        let mut spans = Vec::new();
+        if self.quotes > 0 {
+            spans.push(Span::styled("│ ".repeat(self.quotes), self.styles.quote()));
+        }
+        spans.append(&mut self.line);
+        self.lines.push(Spans::from(spans));
+    }
+
+    // A blank line between blocks, but never two in a row or one up top
+    fn blank(&mut self) {
+        self.flush();
+        if self.lines.last().map_or(false, |l| !l.0.is_empty()) {
+            self.lines.push(Spans::default());
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) if self.in_code_block => {
+                for line in text.lines() {
+                    self.push(format!("    {}", line));
+                    self.flush();
+                }
+            }
+            Event::Text(text) | Event::Html(text) => self.push(text.to_string()),
+            Event::Code(code) => {
+                let style = self.style().patch(self.styles.code());
+                self.line.push(Span::styled(code.to_string(), style));
+            }
+            Event::SoftBreak => self.push(String::from(" ")),
+            Event::HardBreak => self.flush(),
+            Event::Rule => {
+                self.blank();
+                self.line
+                    .push(Span::styled("─".repeat(20), self.styles.quote()));
+                self.blank();
+            }
+            Event::TaskListMarker(done) => {
+                self.push(String::from(if done { "[x] " } else { "[ ] " }))
+            }
+            Event::FootnoteReference(name) => self.push(format!("[^{}]", name)),
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Heading(level, _, _) => {
+                self.blank();
+                self.stack
+                    .push(self.styles.heading(level != HeadingLevel::H1));
+            }
+            Tag::BlockQuote => {
+                self.blank();
+                self.quotes += 1;
+            }
+            Tag::CodeBlock(_) => {
+                self.blank();
+                self.in_code_block = true;
+                self.stack.push(self.styles.code());
+            }
+            Tag::List(start) => {
+                // A nested list starts on a line of its own
+                self.flush();
+                if self.lists.is_empty() {
+                    self.blank();
+                }
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                self.flush();
+                let depth = self.lists.len().saturating_sub(1);
+                let marker = match self.lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        format!("{}. ", *n - 1)
+                    }
+                    _ => String::from("• "),
+                };
+                self.line
+                    .push(Span::raw(format!("{}{}", "  ".repeat(depth), marker)));
+            }
+            Tag::Emphasis => self.stack.push(self.styles.emphasis()),
+            Tag::Strong => self.stack.push(self.styles.strong()),
+            Tag::Strikethrough => self.stack.push(self.styles.strikethrough()),
+            Tag::Link(..) | Tag::Image(..) => self.stack.push(self.styles.link()),
+            Tag::Paragraph
+            | Tag::FootnoteDefinition(_)
+            | Tag::Table(_)
+            | Tag::TableHead
+            | Tag::TableRow
+            | Tag::TableCell => {}
+        }
+    }
+
+    fn end(&mut self, tag: Tag) {
+        match tag {
+            Tag::Heading(..) => {
+                self.stack.pop();
+                self.blank();
+            }
+            Tag::Paragraph => {
+                // Paragraphs inside list items would otherwise space out the list
+                if self.lists.is_empty() {
+                    self.blank();
+                } else {
+                    self.flush();
+                }
+            }
+            Tag::BlockQuote => {
+                self.flush();
+                self.quotes -= 1;
+                self.blank();
+            }
+            Tag::CodeBlock(_) => {
+                self.in_code_block = false;
+                self.stack.pop();
+                self.blank();
+            }
+            Tag::List(_) => {
+                self.flush();
+                self.lists.pop();
+                if self.lists.is_empty() {
+                    self.blank();
+                }
+            }
+            Tag::Item | Tag::TableRow | Tag::TableHead => self.flush(),
+            Tag::TableCell => self.push(String::from("  ")),
+            Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(..) | Tag::Image(..) => {
+                self.stack.pop();
+            }
+            Tag::FootnoteDefinition(_) | Tag::Table(_) => self.blank(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod render_tests {
+    use super::*;
+    use tui::style::Modifier;
+
+    // Each line's text
+    fn lines(text: &Text) -> Vec<String> {
+        text.lines
+            .iter()
+            .map(|l| l.0.iter().map(|s| s.content.as_ref()).collect())
+            .collect()
+    }
+
+    fn span<'a>(text: &'a Text, content: &str) -> &'a Span<'a> {
+        text.lines
+            .iter()
+            .flat_map(|l| l.0.iter())
+            .find(|s| s.content == content)
+            .unwrap_or_else(|| panic!("No span {:?} in {:?}", content, lines(text)))
+    }
+
+    #[test]
+    fn headings() {
+        let styles = Styles::new(false);
+        let text = render("# Title\n\nSome text\n\n## Section\n", &styles);
+        assert_eq!(vec!["Title", "", "Some text", "", "Section"], lines(&text));
+        assert!(span(&text, "Title")
+            .style
+            .add_modifier
+            .contains(Modifier::BOLD));
+        assert!(span(&text, "Section")
+            .style
+            .add_modifier
+            .contains(Modifier::BOLD));
+        assert!(!span(&text, "Some text")
+            .style
+            .add_modifier
+            .contains(Modifier::BOLD));
+    }
+
+    #[test]
+    fn bold_and_code() {
+        let styles = Styles::new(false);
+        let text = render("Run **this** with `cargo`\n", &styles);
+        assert_eq!(vec!["Run this with cargo"], lines(&text));
+        assert!(span(&text, "this")
+            .style
+            .add_modifier
+            .contains(Modifier::BOLD));
+        assert!(span(&text, "cargo")
+            .style
+            .add_modifier
+            .contains(Modifier::DIM));
+    }
+
+    #[test]
+    fn lists() {
+        let styles = Styles::new(false);
+        let text = render(
+            "Steps:\n\n1. first\n2. second\n   - nested\n\n- done\n",
+            &styles,
+        );
+        assert_eq!(
+            vec![
+                "Steps:",
+                "",
+                "1. first",
+                "2. second",
+                "  • nested",
+                "",
+                "• done"
+            ],
+            lines(&text)
+        );
+    }
+
+    #[test]
+    fn code_block_and_quote() {
+        let styles = Styles::new(false);
+        let text = render("> quoted\n\n```\nlet x = 1;\n```\n", &styles);
+        assert_eq!(vec!["│ quoted", "", "    let x = 1;"], lines(&text));
+    }
+}
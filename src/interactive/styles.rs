@@ -0,0 +1,413 @@
+use crate::document::normalize_tag;
+use ansi_to_tui::ansi_to_text;
+use color_eyre::Report;
+use eyre::eyre;
+use once_cell::unsync::OnceCell;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Style as hStyle, Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+use tui::{
+    style::{Color, Modifier, Style},
+    text::Text,
+};
+
+/// Every style the interface draws with comes from here, so that color can be switched off in
+/// one place
+pub struct Styles {
+    color: bool,
+    /// Markdown syntax highlighting for the preview, loaded the first time a preview is
+    /// highlighted so that starting up doesn't wait on it, and never without color
+    syntax: OnceCell<(SyntaxSet, Theme)>,
+    load_syntax: fn() -> (SyntaxSet, Theme),
+    selected: Style,
+}
+
+/// Drawn before the selected entry in the list of matches, unless configured otherwise
+pub const SELECT_SYMBOL: &str = "> ";
+
+/// How the selected entry in a list is drawn, unless configured otherwise
+pub fn default_selected() -> Style {
+    Style::default().add_modifier(Modifier::REVERSED)
+}
+
+/// The syntax definitions and theme previews are highlighted with
+fn load_syntax() -> (SyntaxSet, Theme) {
+    let ps = SyntaxSet::load_defaults_newlines();
+    // TODO make themes configurable
+    let theme = ThemeSet::load_defaults()
+        .themes
+        .remove("Solarized (dark)")
+        .unwrap();
+    (ps, theme)
+}
+
+impl Styles {
+    pub fn new(color: bool) -> Styles {
+        Styles::with_loader(color, load_syntax)
+    }
+
+    // Like `new`, loading syntax highlighting with `load_syntax`
+    fn with_loader(color: bool, load_syntax: fn() -> (SyntaxSet, Theme)) -> Styles {
+        Styles {
+            color,
+            syntax: OnceCell::new(),
+            load_syntax,
+            selected: default_selected(),
+        }
+    }
+
+    /// Draw the selected entry in lists with `selected`, e.g. bold for terminals where reversed
+    /// colors are hard to read
+    pub fn with_selected(mut self, selected: Style) -> Styles {
+        self.selected = selected;
+        self
+    }
+
+    fn color(&self) -> bool {
+        self.color
+    }
+
+    /// Query and filter input boxes
+    pub fn input(&self) -> Style {
+        if self.color() {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default()
+        }
+    }
+
+    /// Debug message pane
+    pub fn debug(&self) -> Style {
+        if self.color() {
+            Style::default().fg(Color::Green).bg(Color::Black)
+        } else {
+            Style::default()
+        }
+    }
+
+    /// Error message pane
+    pub fn error(&self) -> Style {
+        if self.color() {
+            Style::default().fg(Color::Red).bg(Color::Black)
+        } else {
+            Style::default()
+        }
+    }
+
+    /// Selected entry in a list
+    pub fn selected(&self) -> Style {
+        self.selected
+    }
+
+    /// Body excerpt shown after each match's title
+    pub fn snippet(&self) -> Style {
+        if self.color() {
+            Style::default().fg(Color::DarkGray)
+        } else {
+            Style::default()
+        }
+    }
+
+    /// Chip showing `tag`, colored the same wherever the tag appears
+    pub fn tag(&self, tag: &str) -> Style {
+        if self.color() {
+            Style::default().fg(Color::Black).bg(tag_color(tag))
+        } else {
+            Style::default().add_modifier(Modifier::UNDERLINED)
+        }
+    }
+
+    /// Headings in the rendered preview, `sub` for those below the top level
+    pub fn heading(&self, sub: bool) -> Style {
+        let style = Style::default().add_modifier(Modifier::BOLD);
+        match (self.color(), sub) {
+            (true, false) => style.fg(Color::LightCyan),
+            (true, true) => style.fg(Color::Cyan),
+            (false, _) => style,
+        }
+    }
+
+    /// Inline code and code blocks in the rendered preview
+    pub fn code(&self) -> Style {
+        Style::default().add_modifier(Modifier::DIM)
+    }
+
+    /// Block quote bars and rules in the rendered preview
+    pub fn quote(&self) -> Style {
+        if self.color() {
+            Style::default().fg(Color::DarkGray)
+        } else {
+            Style::default()
+        }
+    }
+
+    pub fn emphasis(&self) -> Style {
+        Style::default().add_modifier(Modifier::ITALIC)
+    }
+
+    pub fn strong(&self) -> Style {
+        Style::default().add_modifier(Modifier::BOLD)
+    }
+
+    pub fn strikethrough(&self) -> Style {
+        Style::default().add_modifier(Modifier::CROSSED_OUT)
+    }
+
+    /// Links in the rendered preview
+    pub fn link(&self) -> Style {
+        let style = Style::default().add_modifier(Modifier::UNDERLINED);
+        if self.color() {
+            style.fg(Color::Blue)
+        } else {
+            style
+        }
+    }
+
+    /// Patched over existing styling for text matching a query term
+    pub fn term_match(&self) -> Style {
+        if self.color() {
+            Style::default()
+                .fg(Color::Black)
+                .bg(Color::Yellow)
+                .add_modifier(Modifier::BOLD)
+        } else {
+            Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED)
+        }
+    }
+
+    /// Syntax highlight the markdown `preview` into styled text, falling back to the plain text
+    /// if color is disabled or the highlighted terminal escapes can't be converted
+    pub fn preview(&self, preview: &str) -> Text<'static> {
+        if !self.color {
+            return Text::raw(preview.to_string());
+        }
+        let (ps, theme) = self.syntax.get_or_init(self.load_syntax);
+
+        // TODO use HighlightFile here instead of lines? https://docs.rs/syntect/latest/syntect/easy/struct.HighlightFile.html
+        let syntax = ps.find_syntax_by_extension("md").unwrap();
+        let mut highlighter = HighlightLines::new(syntax, theme);
+        let mut escaped = String::new();
+        for line in LinesWithEndings::from(preview) {
+            let ranges: Vec<(hStyle, &str)> = highlighter.highlight(line, ps);
+            escaped.push_str(&as_24_bit_terminal_escaped(&ranges[..], true));
+        }
+        match ansi_to_text(escaped.bytes()) {
+            Ok(text) => text,
+            Err(_) => Text::raw(preview.to_string()),
+        }
+    }
+}
+
+/// Parse a style like `bold,fg:white,bg:blue`: any of the modifiers bold, dim, italic,
+/// underlined and reversed, along with `fg:` and `bg:` colors, each either named like `red` or
+/// `lightblue`, or given as `#rrggbb`
+pub fn parse_style(s: &str) -> Result<Style, Report> {
+    let mut style = Style::default();
+    for part in s.split(|c: char| c == ',' || c.is_whitespace()) {
+        style = match part.to_lowercase().as_str() {
+            "" => style,
+            "bold" => style.add_modifier(Modifier::BOLD),
+            "dim" => style.add_modifier(Modifier::DIM),
+            "italic" => style.add_modifier(Modifier::ITALIC),
+            "underlined" => style.add_modifier(Modifier::UNDERLINED),
+            "reversed" => style.add_modifier(Modifier::REVERSED),
+            part => match part.split_once(':') {
+                Some(("fg", color)) => style.fg(parse_color(color)?),
+                Some(("bg", color)) => style.bg(parse_color(color)?),
+                _ => {
+                    return Err(eyre!(
+                        "Unknown style '{}' in '{}', expected bold, dim, italic, underlined, \
+                         reversed, fg:COLOR or bg:COLOR",
+                        part,
+                        s
+                    ))
+                }
+            },
+        };
+    }
+    Ok(style)
+}
+
+// A color named like `red` or `lightblue`, or given as `#rrggbb`
+fn parse_color(s: &str) -> Result<Color, Report> {
+    let color = match s {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" => Color::Gray,
+        "darkgray" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        _ => match s.strip_prefix('#').map(|hex| u32::from_str_radix(hex, 16)) {
+            Some(Ok(rgb)) if s.len() == 7 => {
+                Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+            }
+            _ => return Err(eyre!("Unknown color '{}'", s)),
+        },
+    };
+    Ok(color)
+}
+
+#[cfg(test)]
+mod parse_style_tests {
+    use super::*;
+    #[test]
+    fn modifiers_and_colors() {
+        assert_eq!(default_selected(), parse_style("reversed").unwrap());
+        assert_eq!(
+            Style::default()
+                .add_modifier(Modifier::BOLD)
+                .fg(Color::White)
+                .bg(Color::Rgb(0x12, 0x34, 0xab)),
+            parse_style("Bold, fg:white,bg:#1234ab").unwrap()
+        );
+        assert_eq!(Style::default(), parse_style("").unwrap());
+        assert!(parse_style("blinking").is_err());
+        assert!(parse_style("bg:ultraviolet").is_err());
+        assert!(parse_style("fg:#12345").is_err());
+    }
+}
+
+/// Colors tag chips are drawn in
+const TAG_COLORS: [Color; 12] = [
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::LightYellow,
+    Color::LightBlue,
+    Color::LightMagenta,
+    Color::LightCyan,
+];
+
+/// Color for `tag`, picked by hashing it so it's the same from one run to the next. FNV-1a
+/// rather than std's hasher, which doesn't promise stable output across releases.
+pub fn tag_color(tag: &str) -> Color {
+    let hash = normalize_tag(tag)
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    TAG_COLORS[(hash % TAG_COLORS.len() as u64) as usize]
+}
+
+#[cfg(test)]
+mod tag_color_tests {
+    use super::*;
+    #[test]
+    fn stable() {
+        assert_eq!(Color::LightGreen, tag_color("rust"));
+        assert_eq!(Color::Blue, tag_color("vim"));
+        assert_eq!(Color::LightYellow, tag_color("draft"));
+        assert_eq!(Color::Green, tag_color("zettelkasten"));
+    }
+
+    #[test]
+    fn same_for_matching_tags() {
+        assert_eq!(tag_color("rust"), tag_color("Rust"));
+        assert_eq!(tag_color("cafe"), tag_color("Café"));
+    }
+}
+
+#[cfg(test)]
+mod styles_tests {
+    use super::*;
+
+    fn line_content(text: &Text, i: usize) -> String {
+        text.lines[i].0.iter().map(|s| s.content.as_ref()).collect()
+    }
+
+    #[test]
+    fn escapes_become_styles() {
+        let text = Styles::new(true).preview("# Title\nsome *text*\n");
+        assert!(text.lines.len() >= 2);
+        assert_eq!("# Title", line_content(&text, 0));
+        assert_eq!("some *text*", line_content(&text, 1));
+
+        let spans = text.lines.iter().flat_map(|l| l.0.iter());
+        for span in spans.clone() {
+            assert!(
+                !span.content.contains('\x1b'),
+                "Unconverted escape in {:?}",
+                span
+            );
+        }
+        assert!(spans
+            .clone()
+            .any(|s| matches!(s.style.fg, Some(Color::Rgb(_, _, _)))));
+    }
+
+    #[test]
+    fn no_color_preview_is_raw_body() {
+        let body = "# Title\nsome *text*\n\n```\ncode\n```";
+        let text = Styles::new(false).preview(body);
+        let lines: Vec<String> = (0..text.lines.len())
+            .map(|i| line_content(&text, i))
+            .collect();
+        assert_eq!(body, lines.join("\n"));
+        assert!(text
+            .lines
+            .iter()
+            .flat_map(|l| l.0.iter())
+            .all(|s| s.style == Style::default()));
+    }
+
+    #[test]
+    fn syntax_loaded_lazily() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COLOR_LOADS: AtomicUsize = AtomicUsize::new(0);
+        static NO_COLOR_LOADS: AtomicUsize = AtomicUsize::new(0);
+        fn color_loader() -> (SyntaxSet, Theme) {
+            COLOR_LOADS.fetch_add(1, Ordering::SeqCst);
+            load_syntax()
+        }
+        fn no_color_loader() -> (SyntaxSet, Theme) {
+            NO_COLOR_LOADS.fetch_add(1, Ordering::SeqCst);
+            load_syntax()
+        }
+
+        let styles = Styles::with_loader(false, no_color_loader);
+        styles.preview("# Title\n");
+        styles.input();
+        assert_eq!(0, NO_COLOR_LOADS.load(Ordering::SeqCst));
+
+        // Only on the first preview, and only the once
+        let styles = Styles::with_loader(true, color_loader);
+        styles.input();
+        assert_eq!(0, COLOR_LOADS.load(Ordering::SeqCst));
+        styles.preview("# Title\n");
+        styles.preview("# Other\n");
+        assert_eq!(1, COLOR_LOADS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn no_color_styles() {
+        let styles = Styles::new(false);
+        for style in [
+            styles.input(),
+            styles.debug(),
+            styles.error(),
+            styles.snippet(),
+            styles.tag("rust"),
+            styles.term_match(),
+        ] {
+            assert_eq!(None, style.fg);
+            assert_eq!(None, style.bg);
+        }
+    }
+}
@@ -1,3 +1,13 @@
+pub mod config;
 pub mod date;
 pub mod document;
+pub mod error;
+pub mod indexer;
 pub mod interactive;
+pub mod search;
+pub mod server;
+pub mod template;
+
+pub use error::MdqError;
+pub use indexer::{Backend, Indexer};
+pub use search::{search, SearchOptions, Sort, Tiebreak};
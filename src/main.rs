@@ -1,12 +1,30 @@
-mod interactive;
+use chrono::Utc;
 use clap::{Parser, Subcommand};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use color_eyre::Report;
-use log::{debug, error};
-use markdown_query::document;
-use std::ffi::OsStr;
-use walkdir::WalkDir;
-use xapian_rusty::{Database, Stem, TermGenerator, WritableDatabase, BRASS, DB_CREATE_OR_OPEN};
+use eyre::{eyre, WrapErr};
+use log::{debug, warn};
+use markdown_query::config::Config;
+use markdown_query::date::{set_date_format, Date, DateFormat, Recency};
+use markdown_query::document::{self, Document, SerializationType};
+use markdown_query::indexer::{
+    markdown_files, notebook_for, repair, Backend, ChangeDetection, Indexer, Notebook, Profile,
+};
+use markdown_query::interactive;
+use markdown_query::interactive::styles::{default_selected, parse_style, SELECT_SYMBOL};
+use markdown_query::interactive::xapian_utils::{
+    explain_query, parse_custom_fields, CustomField, DateBounds, ParseOptions,
+};
+use markdown_query::search::DedupeBy;
+use markdown_query::server::Server;
+use markdown_query::template::Template;
+use markdown_query::{MdqError, SearchOptions};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use xapian_rusty::{Database, DB_CREATE_OR_OPEN};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -23,15 +41,132 @@ struct Cli {
     #[clap(long, env = "EDITOR", default_value = "vi")]
     editor: String,
 
-    /// Specify where to write the DB to
+    /// Always view notes in the pager, by default notes that fit on screen are printed instead
+    #[clap(long)]
+    pager_always: bool,
+
+    /// Longest query or filter input accepted in interactive mode, in characters
+    #[clap(long, default_value = "256")]
+    max_input_len: usize,
+
+    /// Draw the interactive interface without colors, also set by a non-empty NO_COLOR
+    #[clap(long)]
+    no_color: bool,
+
+    /// What to print for the selected note on exit from interactive mode
+    #[clap(long, arg_enum, default_value = "path")]
+    output: interactive::Output,
+
+    /// End each note printed on exit from interactive mode with a NUL rather than a newline,
+    /// like `find -print0`, for `xargs -0`
+    #[clap(short = '0', long)]
+    output_null: bool,
+
+    /// Order to list matches in
+    #[clap(long, arg_enum, default_value = "relevance")]
+    sort: markdown_query::Sort,
+
+    /// List matches in the opposite of the `--sort` order, e.g. least relevant first
+    #[clap(long)]
+    reverse: bool,
+
+    /// Order of matches `--sort` ranks the same, e.g. scoring exactly alike: newest first then
+    /// by title then id with `date`, by title then id with `title`, or as the index returns
+    /// them with `none`
+    #[clap(long, arg_enum, default_value = "date")]
+    tiebreak: markdown_query::Tiebreak,
+
+    /// Leave out matches scoring below this percent of the best possible, e.g. the long tail of
+    /// weakly related notes; 0 keeps every match
     #[clap(
-        short,
         long,
-        parse(from_os_str),
-        value_name = "XAPIAN DB DIR",
-        default_value = "~/.mdq-data"
+        value_name = "PERCENT",
+        default_value = "0",
+        parse(try_from_str = parse_percent)
     )]
-    db_path: Box<OsStr>,
+    min_score: i32,
+
+    /// List matches which are the same note by this once, e.g. copies kept in two places,
+    /// keeping the best scoring
+    #[clap(long, arg_enum, value_name = "title|id|body-hash")]
+    dedupe_by: Option<DedupeBy>,
+
+    /// How many recent queries' matches the interactive query keeps, so that running one again
+    /// doesn't go back to the index; 0 turns this off
+    #[clap(long, default_value = "32")]
+    cache_size: usize,
+
+    /// Show the selected note's markdown source in the preview, or render it for reading
+    #[clap(long, arg_enum, default_value = "source")]
+    preview_mode: interactive::PreviewMode,
+
+    /// Comma separated fields a query word without a `field:` prefix is searched in, from
+    /// title, subtitle, body, tags, author, fullpath and date [default: title,body,tags]
+    #[clap(long)]
+    default_fields: Option<String>,
+
+    /// YAML file mapping extra frontmatter fields to index onto Xapian prefixes, e.g.
+    /// `project: XPROJECT` to search `project:apollo`. Reindex after changing it, and pass it
+    /// to every command using the index.
+    #[clap(long, global = true, value_name = "FILE")]
+    field_config: Option<String>,
+
+    /// Index nested tags like `project/apollo/launch` under each ancestor too, so that
+    /// `tag:project` also finds notes tagged `project/apollo`. Reindex after changing it, and
+    /// pass it to every command indexing notes.
+    #[clap(long, global = true)]
+    hierarchical_tags: bool,
+
+    /// Index and search Chinese, Japanese and Korean text, which isn't split into words by
+    /// spaces, as overlapping runs of characters. Reindex after changing it, and pass it to
+    /// every command using the index.
+    #[clap(long, global = true)]
+    cjk: bool,
+
+    /// Only index titles, tags and other frontmatter, leaving note bodies out of the index to
+    /// keep it small and quick to update. Words in bodies won't be found. Pass it to every
+    /// command indexing notes, so that `add`, `edit` and `reindex` leave bodies out too.
+    #[clap(long, global = true)]
+    metadata_only: bool,
+
+    /// Index the text note bodies render to, leaving out markdown syntax and link URLs, so that
+    /// searches only match words a reader sees. Bodies are still shown as written. Pass it to
+    /// every command indexing notes, so that notes are all indexed alike.
+    #[clap(long, global = true)]
+    strip_markdown: bool,
+
+    /// Index what's in fenced and indented code blocks, true by default;
+    /// `--index-code-blocks=false` leaves them out so code heavy notes don't swamp prose
+    /// matches. They're still shown. Pass it to every command indexing notes.
+    #[clap(long, global = true, value_name = "BOOL", parse(try_from_str))]
+    index_code_blocks: Option<bool>,
+
+    /// Walk into symlinked directories too. A note reached through links is indexed once, under
+    /// its real path. Pass it to `reindex` as well as `update`, or linked notes are dropped.
+    #[clap(long, global = true)]
+    follow_symlinks: bool,
+
+    /// Lowercase tags as notes are indexed, so `Rust` and `rust` are stored and listed as one
+    /// tag. Reindex after changing it, and pass it to every command indexing notes.
+    #[clap(long, global = true)]
+    lowercase_tags: bool,
+
+    /// Timezone dates are shown in: local, utc or an offset like +05:30
+    #[clap(long, default_value = "local")]
+    date_tz: String,
+
+    /// strftime-style format dates are shown in, e.g. %Y-%m-%d; RFC 3339 when empty
+    #[clap(long, default_value = "")]
+    date_format: String,
+
+    /// Specify where to write the DB to [default: ~/.mdq-data]
+    #[clap(short, long, parse(from_os_str), value_name = "XAPIAN DB DIR")]
+    db_path: Option<Box<OsStr>>,
+
+    /// Storage format for new indexes: glass, brass (only readable by Xapian 1.3) or default
+    /// (whatever the linked Xapian picks). Existing indexes keep their format.
+    #[clap(long, default_value = "glass")]
+    backend: Backend,
 
     #[clap(subcommand)]
     subcommand: Option<Subcommands>,
@@ -44,15 +179,896 @@ enum Subcommands {
     Update {
         /// Directories to search recursively for markdown content
         paths: Vec<String>,
+
+        /// Index exactly the files listed one per line on stdin, e.g. by `git diff --name-only`
+        /// or `fd`, rather than walking directories. Relative paths are from the current
+        /// directory, files other than `.md` ones are ignored, and listed files which are gone
+        /// are removed from the index.
+        #[clap(long, conflicts_with = "paths")]
+        stdin_paths: bool,
+
+        /// Paths on stdin are separated by NUL rather than newlines, as from `find -print0`
+        #[clap(short = '0', long, requires = "stdin-paths")]
+        null: bool,
+
+        /// Report what would change without writing to the index
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Remove notes from the index whose files are no longer found under the paths
+        #[clap(long)]
+        prune: bool,
+
+        /// Index files without frontmatter too, titled by their first `# ` heading
+        #[clap(long)]
+        infer_frontmatter: bool,
+
+        /// Abort when two notes share an `id`, e.g. a copied note, instead of warning
+        #[clap(long)]
+        fail_on_duplicate: bool,
+
+        /// Skip files unchanged since they were indexed, going by their modification time
+        /// (mtime) or, for vaults synced by tools that don't keep those, a hash of their
+        /// contents (hash). Every file is indexed again without this.
+        #[clap(long, value_name = "mtime|hash")]
+        change_detection: Option<ChangeDetection>,
+
+        /// Print how long was spent finding, parsing, indexing and committing notes
+        #[clap(long)]
+        profile: bool,
+
+        /// Index the notes in DIR too, searchable on their own as `notebook:NAME`. Repeat for
+        /// each notebook.
+        #[clap(
+            long = "notebook",
+            value_name = "NAME=DIR",
+            multiple_occurrences = true
+        )]
+        notebooks: Vec<Notebook>,
+    },
+
+    /// Rebuild the index from scratch, replacing the existing one only once complete. Indexing
+    /// flags like `--metadata-only` are needed here as for `update` to build the same index.
+    Reindex {
+        /// Directories to search recursively for markdown content
+        paths: Vec<String>,
+
+        /// Index files without frontmatter too, titled by their first `# ` heading
+        #[clap(long)]
+        infer_frontmatter: bool,
+
+        /// Index the notes in DIR too, searchable on their own as `notebook:NAME`. Repeat for
+        /// each notebook.
+        #[clap(
+            long = "notebook",
+            value_name = "NAME=DIR",
+            multiple_occurrences = true
+        )]
+        notebooks: Vec<Notebook>,
+    },
+
+    /// Parse every markdown file under each path and report which have invalid frontmatter,
+    /// dates or tags, without touching the index. Fails if any do, e.g. for CI.
+    Check {
+        /// Directories to search recursively for markdown content, or single files
+        #[clap(required = true)]
+        paths: Vec<String>,
+
+        /// Accept files without frontmatter, as `update --infer-frontmatter` would index them
+        #[clap(long)]
+        infer_frontmatter: bool,
+    },
+
+    /// List every notebook along with how many notes are in it
+    Notebooks {
+        /// Output as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// List every tag along with how many notes carry it
+    Tags {
+        /// Sort tags alphabetically rather than by count
+        #[clap(long)]
+        alpha: bool,
+
+        /// Output as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Open a note in the editor straight away, found by its exact id or else by title, and
+    /// reindex it once the editor exits
+    Edit {
+        /// The note's id, or (part of) its title
+        note: String,
+
+        /// When several notes match, pick one in the interactive query rather than failing
+        #[clap(long)]
+        pick: bool,
+    },
+
+    /// Index a single note, e.g. from an editor on saving it, without walking a whole vault
+    Add {
+        /// The markdown file to index
+        file: String,
+
+        /// Index the file even without frontmatter, titled by its first `# ` heading
+        #[clap(long)]
+        infer_frontmatter: bool,
+    },
+
+    /// Remove a single note from the index, by the path it was indexed under
+    Remove {
+        /// The markdown file to remove, which needn't exist any more
+        file: String,
+    },
+
+    /// Answer search and index requests from an editor, one JSON object per line on stdin,
+    /// with a JSON response per line on stdout
+    Serve,
+
+    /// Clear a lock left on the index, e.g. one a network filesystem kept after an update
+    /// which didn't finish cleanly, so it can be updated again. Names the process holding it
+    /// where it can, and only clears a lock still held with `--force`.
+    Repair {
+        /// Clear the lock even though something holds it, once sure no mdq is still writing
+        #[clap(long)]
+        force: bool,
     },
 
     /// Specify a starting query for interactive query mode
     Query {
-        /// Query string
-        query: String,
+        /// Query string. Given several, each is run in turn and its matches printed under it
+        /// rather than starting the interactive query.
+        #[clap(required_unless_present = "query_file")]
+        query: Vec<String>,
+
+        /// Run each line of this file as a query, after any given as arguments, printing the
+        /// matches of each in turn
+        #[clap(long, value_name = "FILE")]
+        query_file: Option<PathBuf>,
+
+        /// Open the selected note in the editor on Enter rather than printing it, overrides
+        /// --output
+        #[clap(long)]
+        open: bool,
+
+        /// Print every match expanded into this template instead of starting the interactive
+        /// query, e.g. '{date}\t{title}\t{full_path}'. Fields are id, title, subtitle, date,
+        /// modified, tags, authors, full_path, percent and duplicates.
+        #[clap(long, value_name = "TEMPLATE", conflicts_with = "json")]
+        format: Option<String>,
+
+        /// Print every match as a JSON array instead of starting the interactive query
+        #[clap(long)]
+        json: bool,
+
+        /// Print every match as a line of its day, title and tags instead of starting the
+        /// interactive query
+        #[clap(long, conflicts_with_all = &["json", "format"])]
+        list: bool,
+
+        /// Print at most this many matches of each query outside of interactive mode
+        #[clap(long, value_name = "N")]
+        limit: Option<usize>,
+
+        /// Fields each note has in --json output: its frontmatter (disk), that along with its
+        /// path and body (human), or everything kept in the index (storage)
+        #[clap(long, value_name = "disk|human|storage", default_value = "disk")]
+        projection: SerializationType,
+
+        /// Only match notes dated on or after this, as RFC 3339, YYYY-MM-DD or epoch seconds
+        #[clap(long, value_name = "DATE")]
+        since: Option<Date>,
+
+        /// Only match notes dated on or before this, a YYYY-MM-DD takes in that whole day
+        #[clap(long, value_name = "DATE", parse(try_from_str = Date::parse_end))]
+        until: Option<Date>,
+
+        /// Only match notes dated within this span back from now, e.g. 7d, 2w, 3m or 1y. In
+        /// interactive mode Ctrl-t cycles through 7d, 30d, 90d and 1y.
+        #[clap(long, value_name = "SPAN")]
+        recent: Option<Recency>,
+
+        /// Print how the query is parsed, the Xapian prefix each term is searched under and
+        /// the resulting Xapian query, rather than running it
+        #[clap(long)]
+        explain: bool,
     },
 }
 
+/// `query --open` needs the selected note's path, whatever `--output` asks for
+fn query_output(open: bool, output: interactive::Output) -> interactive::Output {
+    if open {
+        interactive::Output::Path
+    } else {
+        output
+    }
+}
+
+/// The note for `query --open` to open, out of what the query returned
+fn note_to_open(selected: Vec<String>) -> Option<PathBuf> {
+    selected.into_iter().next().map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod test_utils {
+    use super::*;
+
+    /// Write a note with frontmatter to `dir/name`
+    pub fn write_note(dir: &Path, name: &str, title: &str, tags: &[&str], body: &str) {
+        let tags = if tags.is_empty() {
+            String::new()
+        } else {
+            format!("tags: [{}]\n", tags.join(", "))
+        };
+        let note = format!(
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: {}\n{}---\n\n{}\n",
+            title, tags, body
+        );
+        fs::write(dir.join(name), note).expect("Failed to write note");
+    }
+
+    /// Index and commit a note for each `(id, title)` of `notes`, a title of `Title | alias`
+    /// giving the note an alias
+    pub fn index_notes(db_path: &Path, notes: &[(&str, &str)]) {
+        let mut indexer = Indexer::open(db_path).unwrap();
+        for (i, (id, title)) in notes.iter().enumerate() {
+            let mut doc = Document::new();
+            doc.id = id.to_string();
+            let mut names = title.split(" | ");
+            doc.title = names.next().unwrap().to_string();
+            doc.aliases = names.map(String::from).collect();
+            doc.fullpath = format!("/notes/{}.md", i);
+            indexer.index_document(&doc).unwrap();
+        }
+        indexer.commit().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod open_tests {
+    use super::*;
+    use interactive::Output;
+
+    #[test]
+    fn open_prefers_path() {
+        assert_eq!(Output::Path, query_output(true, Output::Title));
+        assert_eq!(Output::Id, query_output(false, Output::Id));
+    }
+
+    #[test]
+    fn selected_note() {
+        assert_eq!(
+            Some(PathBuf::from("/notes/one.md")),
+            note_to_open(vec![String::from("/notes/one.md")])
+        );
+        assert_eq!(None, note_to_open(Vec::new()));
+    }
+}
+
+/// What an `edit` argument resolved to
+#[derive(Debug, PartialEq)]
+enum Resolved {
+    Found(Document),
+    /// Every note the title could mean, best match first
+    Ambiguous(Vec<Document>),
+    Missing,
+}
+
+/// Query matching titles containing the words of `title`, punctuation the query parser
+/// wouldn't take is dropped. None if there are no words left.
+fn title_query(title: &str) -> Option<String> {
+    let words: Vec<&str> = title
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .filter(|w| !w.is_empty())
+        .collect();
+    if words.is_empty() {
+        None
+    } else {
+        Some(format!("title:\"{}\"", words.join(" ")))
+    }
+}
+
+/// Find the note `needle` refers to: the one with exactly that id, else the one whose title or
+/// alias it is, else the only note whose title or an alias contains it
+fn resolve_note(db_path: &str, needle: &str) -> Result<Resolved, Report> {
+    let indexed = indexed_documents(db_path)?;
+    if let Some(doc) = indexed.into_iter().find(|doc| doc.id == needle) {
+        return Ok(Resolved::Found(doc));
+    }
+
+    let query = match title_query(needle) {
+        Some(query) => query,
+        None => return Ok(Resolved::Missing),
+    };
+    let mut matches = markdown_query::search(Path::new(db_path), &query, SearchOptions::default())?;
+    let needle = needle.to_lowercase();
+    let exact: Vec<usize> = (0..matches.len())
+        .filter(|i| {
+            let doc = &matches[*i];
+            doc.title.to_lowercase() == needle
+                || doc.aliases.iter().any(|a| a.to_lowercase() == needle)
+        })
+        .collect();
+    Ok(match (exact.as_slice(), matches.len()) {
+        ([i], _) => Resolved::Found(matches.swap_remove(*i)),
+        (_, 0) => Resolved::Missing,
+        (_, 1) => Resolved::Found(matches.remove(0)),
+        ([], _) => Resolved::Ambiguous(matches),
+        (exact, _) => Resolved::Ambiguous(exact.iter().map(|i| matches[*i].clone()).collect()),
+    })
+}
+
+/// Open the note at `path` in `editor`, at the start of its body for editors which can be told
+/// where to start
+fn open_note(editor: &str, path: &Path) -> io::Result<()> {
+    let line = Document::parse_file(path, false)
+        .ok()
+        .map(|doc| doc.body_line);
+    interactive::run_on_file_at(editor, path, line)
+}
+
+/// Open `path` in `editor`, then index it again to pick up whatever was changed
+fn edit_and_reindex(
+    editor: &str,
+    path: &Path,
+    db_path: &str,
+    settings: &IndexSettings,
+) -> Result<(), Report> {
+    open_note(editor, path)?;
+    let failures = add_note(path, db_path, settings, false)?;
+    report_failures(&failures);
+    Ok(())
+}
+
+/// How notes are indexed, the same whichever command indexes them
+#[derive(Clone)]
+struct IndexSettings {
+    backend: Backend,
+    custom_fields: Vec<CustomField>,
+    hierarchical_tags: bool,
+    cjk: bool,
+    metadata_only: bool,
+    strip_markdown: bool,
+    index_code_blocks: bool,
+    follow_symlinks: bool,
+    lowercase_tags: bool,
+    notebooks: Vec<Notebook>,
+}
+
+impl Default for IndexSettings {
+    fn default() -> IndexSettings {
+        IndexSettings {
+            backend: Backend::default(),
+            custom_fields: Vec::new(),
+            hierarchical_tags: false,
+            cjk: false,
+            metadata_only: false,
+            strip_markdown: false,
+            index_code_blocks: true,
+            follow_symlinks: false,
+            lowercase_tags: false,
+            notebooks: Vec::new(),
+        }
+    }
+}
+
+impl IndexSettings {
+    /// Open the index at `db_path` to add notes to this way, creating it if need be
+    fn open(&self, db_path: &Path) -> Result<Indexer, Report> {
+        let mut indexer = Indexer::open_with(db_path, self.backend)?;
+        self.apply(&mut indexer);
+        Ok(indexer)
+    }
+
+    fn apply(&self, indexer: &mut Indexer) {
+        indexer.custom_fields = self.custom_fields.clone();
+        indexer.hierarchical_tags = self.hierarchical_tags;
+        indexer.cjk = self.cjk;
+        indexer.metadata_only = self.metadata_only;
+        indexer.strip_markdown = self.strip_markdown;
+        indexer.index_code_blocks = self.index_code_blocks;
+        indexer.follow_symlinks = self.follow_symlinks;
+        indexer.lowercase_tags = self.lowercase_tags;
+        indexer.notebooks = self.notebooks.clone();
+    }
+}
+
+/// Index the single note at `path` and commit straight away, returning why it couldn't be
+/// loaded if it couldn't
+fn add_note(
+    path: &Path,
+    db_path: &str,
+    settings: &IndexSettings,
+    infer_frontmatter: bool,
+) -> Result<Vec<String>, Report> {
+    if !path.is_file() {
+        return Err(eyre!("No note found at {}", path.display()));
+    }
+    let mut indexer = settings.open(Path::new(db_path))?;
+    indexer.infer_frontmatter = infer_frontmatter;
+    if indexer.index_path(path)?.is_empty() {
+        return Err(eyre!("{} isn't a markdown note", path.display()));
+    }
+    indexer.commit()?;
+    Ok(std::mem::take(&mut indexer.failures))
+}
+
+/// Index notes again as interactive mode changes them, the way `add` would
+fn reindexer<'a>(
+    db_path: &'a str,
+    settings: &'a IndexSettings,
+) -> impl Fn(&Path) -> Result<Database, Report> + 'a {
+    move |path| {
+        let failures = add_note(path, db_path, settings, false)?;
+        if let Some(failure) = failures.first() {
+            return Err(eyre!("{}", failure));
+        }
+        Ok(Database::new_with_path(db_path, DB_CREATE_OR_OPEN)?)
+    }
+}
+
+/// Drop the note at `path` from the index, whether or not the file is still there
+fn remove_note(path: &Path, db_path: &str, backend: Backend) -> Result<(), Report> {
+    let mut indexer = Indexer::open_with(Path::new(db_path), backend)?;
+    indexer.prune(&[path.to_path_buf()])?;
+    Ok(indexer.commit()?)
+}
+
+#[cfg(test)]
+mod add_remove_tests {
+    use super::test_utils::write_note;
+    use super::*;
+
+    fn titles(db_path: &str, query: &str) -> Vec<String> {
+        markdown_query::search(Path::new(db_path), query, SearchOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|doc| doc.title)
+            .collect()
+    }
+
+    #[test]
+    fn add_then_remove() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        write_note(tmp.path(), "note.md", "Grep open buffers", &[], "vimgrep");
+        let note = tmp.path().join("note.md");
+
+        let failures = add_note(&note, &db_path, &IndexSettings::default(), false).unwrap();
+        assert!(failures.is_empty(), "{:?}", failures);
+        assert_eq!(vec!["Grep open buffers"], titles(&db_path, "vimgrep"));
+
+        // Gone from the index even with the file still there
+        remove_note(&note, &db_path, Backend::default()).unwrap();
+        assert!(titles(&db_path, "vimgrep").is_empty());
+    }
+
+    #[test]
+    fn add_metadata_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        write_note(tmp.path(), "note.md", "Grep open buffers", &[], "vimgrep");
+        let note = tmp.path().join("note.md");
+
+        let settings = IndexSettings {
+            metadata_only: true,
+            ..IndexSettings::default()
+        };
+        add_note(&note, &db_path, &settings, false).unwrap();
+        assert!(titles(&db_path, "vimgrep").is_empty());
+        assert_eq!(vec!["Grep open buffers"], titles(&db_path, "grep"));
+    }
+
+    #[test]
+    fn add_lowercase_tags() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        write_note(
+            tmp.path(),
+            "note.md",
+            "Grep open buffers",
+            &["Vim", "vim"],
+            "",
+        );
+        let note = tmp.path().join("note.md");
+
+        let settings = IndexSettings {
+            lowercase_tags: true,
+            ..IndexSettings::default()
+        };
+        add_note(&note, &db_path, &settings, false).unwrap();
+        let mut db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN).unwrap();
+        let tags = interactive::xapian_utils::tag_counts(&mut db).unwrap();
+        assert_eq!(vec![(String::from("vim"), 1)], tags);
+    }
+
+    #[test]
+    fn not_a_note() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        let text = tmp.path().join("note.txt");
+        fs::write(&text, "vimgrep\n").unwrap();
+        assert!(add_note(&text, &db_path, &IndexSettings::default(), false).is_err());
+
+        // Unparseable frontmatter is reported rather than indexed
+        let note = tmp.path().join("note.md");
+        fs::write(&note, "no frontmatter\n").unwrap();
+        let failures = add_note(&note, &db_path, &IndexSettings::default(), false).unwrap();
+        assert_eq!(1, failures.len());
+    }
+}
+
+#[cfg(test)]
+mod edit_tests {
+    use super::test_utils::index_notes;
+    use super::*;
+
+    fn titles(docs: &[Document]) -> Vec<&str> {
+        let mut titles: Vec<&str> = docs.iter().map(|d| d.title.as_str()).collect();
+        titles.sort_unstable();
+        titles
+    }
+
+    #[test]
+    fn exact_id() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        index_notes(
+            &db_path,
+            &[("abc123", "Rust lifetimes"), ("def456", "Rust traits")],
+        );
+        match resolve_note(&db_path.to_string_lossy(), "def456").unwrap() {
+            Resolved::Found(doc) => assert_eq!("Rust traits", doc.title),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn title() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        index_notes(
+            &db_path,
+            &[
+                ("a", "Rust lifetimes"),
+                ("b", "Rust traits"),
+                ("c", "Rust"),
+                ("d", "Vim buffers"),
+            ],
+        );
+        let db_path = db_path.to_string_lossy();
+        let resolve = |needle| resolve_note(&db_path, needle).unwrap();
+
+        // The only title containing it
+        match resolve("vim buffers!") {
+            Resolved::Found(doc) => assert_eq!("d", doc.id),
+            other => panic!("Unexpected {:?}", other),
+        }
+        // A title matched exactly wins over those merely containing it
+        match resolve("Rust") {
+            Resolved::Found(doc) => assert_eq!("c", doc.id),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(Resolved::Missing, resolve("emacs"));
+        assert_eq!(Resolved::Missing, resolve("!!"));
+    }
+
+    #[test]
+    fn alias() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        index_notes(
+            &db_path,
+            &[("a", "Kubernetes | k8s"), ("b", "k8s networking")],
+        );
+        // Named exactly by the first note's alias, though both contain it
+        match resolve_note(&db_path.to_string_lossy(), "K8s").unwrap() {
+            Resolved::Found(doc) => assert_eq!("a", doc.id),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ambiguous_title() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        index_notes(
+            &db_path,
+            &[
+                ("a", "Rust lifetimes"),
+                ("b", "Rust traits"),
+                ("c", "Vim buffers"),
+            ],
+        );
+        match resolve_note(&db_path.to_string_lossy(), "rust").unwrap() {
+            Resolved::Ambiguous(docs) => {
+                assert_eq!(vec!["Rust lifetimes", "Rust traits"], titles(&docs))
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
+
+/// Write out what was `selected` in interactive mode, one per line or each ended by a NUL
+/// when `null`
+fn print_selected(out: &mut impl Write, selected: Vec<String>, null: bool) -> io::Result<()> {
+    let end = if null { '\0' } else { '\n' };
+    for s in selected {
+        write!(out, "{}{}", s, end)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod print_selected_tests {
+    use super::*;
+    #[test]
+    fn nul_separated() {
+        let selected = vec![
+            String::from("/notes/grep open buffers.md"),
+            String::from("/notes/two words.md"),
+        ];
+        let mut out = Vec::new();
+        print_selected(&mut out, selected.clone(), true).unwrap();
+        assert_eq!(
+            b"/notes/grep open buffers.md\0/notes/two words.md\0".to_vec(),
+            out
+        );
+
+        let mut out = Vec::new();
+        print_selected(&mut out, selected, false).unwrap();
+        assert_eq!(
+            "/notes/grep open buffers.md\n/notes/two words.md\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
+
+/// `given` queries followed by each non-blank line of `file`
+fn read_queries(given: Vec<String>, file: Option<&Path>) -> Result<Vec<String>, Report> {
+    let mut queries = given;
+    if let Some(file) = file {
+        let contents = fs::read_to_string(file)
+            .wrap_err_with(|| format!("Couldn't read queries from {}", file.display()))?;
+        queries.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from),
+        );
+    }
+    Ok(queries)
+}
+
+/// How `query` searches outside of interactive mode, for every match unless `limit` is given
+fn search_options(
+    options: &interactive::Options,
+    dates: DateBounds,
+    limit: Option<usize>,
+) -> SearchOptions {
+    SearchOptions {
+        limit: limit.unwrap_or(i32::MAX as usize),
+        sort: options.sort,
+        reverse: options.reverse,
+        tiebreak: options.tiebreak,
+        min_score: options.min_score,
+        dedupe_by: options.dedupe_by,
+        default_fields: options.default_fields.clone(),
+        custom_fields: options.custom_fields.clone(),
+        cjk: options.cjk,
+        dates,
+        ..SearchOptions::default()
+    }
+}
+
+/// Write out the matches of each of several queries: with `json` set as a JSON array of
+/// `{query, results}`, otherwise a block per query headed by `==> query <==` with each match
+/// expanded into `template`, or as `output` says without one
+fn print_batch(
+    out: &mut impl Write,
+    batch: Vec<(String, Vec<Document>)>,
+    template: Option<&Template>,
+    json: Option<SerializationType>,
+    list: bool,
+    output: interactive::Output,
+) -> Result<(), Report> {
+    if let Some(projection) = json {
+        let batch: Vec<serde_json::Value> = batch
+            .into_iter()
+            .map(|(query, docs)| {
+                let docs: Vec<Document> = docs
+                    .into_iter()
+                    .map(|doc| doc.with_serialization(projection.clone()))
+                    .collect();
+                serde_json::json!({ "query": query, "results": docs })
+            })
+            .collect();
+        writeln!(out, "{}", serde_json::to_string(&batch)?)?;
+        return Ok(());
+    }
+
+    for (i, (query, docs)) in batch.into_iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, "==> {} <==", query)?;
+        for doc in docs {
+            match template {
+                Some(template) => writeln!(out, "{}", template.render(&doc))?,
+                None if list => writeln!(out, "{}", interactive::list_row(&doc))?,
+                None => writeln!(out, "{}", output.of(&doc))?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod batch_tests {
+    use super::test_utils::write_note;
+    use super::*;
+    use markdown_query::date::with_date_format;
+
+    #[test]
+    fn query_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = tmp.path().join("notes");
+        fs::create_dir(&notes).unwrap();
+        for (name, body) in [
+            ("rust", "traits"),
+            ("vim", "buffers"),
+            ("both", "rust and vim"),
+        ] {
+            write_note(&notes, &format!("{}.md", name), name, &[], body);
+        }
+        let db_path = tmp.path().join("db");
+        {
+            let mut indexer = Indexer::open(&db_path).unwrap();
+            index_paths(&mut indexer, &[notes.to_string_lossy().to_string()]).unwrap();
+        }
+        let file = tmp.path().join("queries.txt");
+        fs::write(
+            &file,
+            "title:vim
+
+  title:both 
+",
+        )
+        .unwrap();
+
+        let queries = read_queries(vec![String::from("traits")], Some(&file)).unwrap();
+        assert_eq!(vec!["traits", "title:vim", "title:both"], queries);
+        let opts = search_options(
+            &interactive::Options::default(),
+            DateBounds::default(),
+            None,
+        );
+        let batch = || -> Vec<(String, Vec<Document>)> {
+            queries
+                .iter()
+                .map(|q| {
+                    let docs = markdown_query::search(&db_path, q, opts.clone()).unwrap();
+                    (q.clone(), docs)
+                })
+                .collect()
+        };
+
+        let template = Template::parse("{title}").unwrap();
+        let mut out = Vec::new();
+        print_batch(
+            &mut out,
+            batch(),
+            Some(&template),
+            None,
+            false,
+            interactive::Output::Path,
+        )
+        .unwrap();
+        assert_eq!(
+            "==> traits <==\nrust\n\n==> title:vim <==\nvim\n\n==> title:both <==\nboth\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        let mut out = Vec::new();
+        print_batch(
+            &mut out,
+            batch(),
+            None,
+            Some(SerializationType::Disk),
+            false,
+            interactive::Output::Path,
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let json = json.as_array().unwrap();
+        assert_eq!(3, json.len());
+        assert_eq!("title:vim", json[1]["query"]);
+        assert_eq!("vim", json[1]["results"][0]["title"]);
+        assert_eq!(1, json[2]["results"].as_array().unwrap().len());
+
+        let mut out = Vec::new();
+        with_date_format(DateFormat::new("utc", "%c").unwrap(), || {
+            print_batch(
+                &mut out,
+                batch(),
+                None,
+                None,
+                true,
+                interactive::Output::Path,
+            )
+        })
+        .unwrap();
+        assert_eq!(
+            "==> traits <==\n2021-06-22  rust\n\n==> title:vim <==\n2021-06-22  vim\n\n\
+             ==> title:both <==\n2021-06-22  both\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        let limited = search_options(
+            &interactive::Options::default(),
+            DateBounds::default(),
+            Some(1),
+        );
+        assert_eq!(
+            2,
+            markdown_query::search(&db_path, "rust", opts.clone())
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            1,
+            markdown_query::search(&db_path, "rust", limited)
+                .unwrap()
+                .len()
+        );
+
+        assert!(read_queries(Vec::new(), Some(&tmp.path().join("missing.txt"))).is_err());
+    }
+}
+
+/// A whole number percent, 0 to 100
+fn parse_percent(s: &str) -> Result<i32, Report> {
+    match s.parse() {
+        Ok(percent) if (0..=100).contains(&percent) => Ok(percent),
+        _ => Err(eyre!("Expected a percent from 0 to 100, got '{}'", s)),
+    }
+}
+
+#[cfg(test)]
+mod parse_percent_tests {
+    use super::*;
+    #[test]
+    fn within_range() {
+        assert_eq!(0, parse_percent("0").unwrap());
+        assert_eq!(60, parse_percent("60").unwrap());
+        assert!(parse_percent("101").is_err());
+        assert!(parse_percent("-5").is_err());
+        assert!(parse_percent("half").is_err());
+    }
+}
+
+/// Colors stay on unless turned off by the flag or a NO_COLOR env var with any non-empty value,
+/// see https://no-color.org
+fn use_color(no_color: bool, no_color_env: Option<OsString>) -> bool {
+    !no_color && no_color_env.map_or(true, |v| v.is_empty())
+}
+
+#[cfg(test)]
+mod use_color_tests {
+    use super::*;
+    #[test]
+    fn flag_and_env() {
+        assert!(use_color(false, None));
+        assert!(use_color(false, Some(OsString::new())));
+        assert!(!use_color(false, Some(OsString::from("1"))));
+        assert!(!use_color(true, None));
+    }
+}
+
 fn setup() -> Result<(), Report> {
     if std::env::var("RUST_LIB_BACKTRACE").is_err() {
         std::env::set_var("RUST_LIB_BACKTRACE", "1")
@@ -62,6 +1078,443 @@ fn setup() -> Result<(), Report> {
     Ok(())
 }
 
+/// Walk each of `paths` for markdown files and add them to the index, returning every markdown
+/// file found. A path which no longer exists is warned about and has nothing found under it, so
+/// its notes are pruned.
+fn index_paths(indexer: &mut Indexer, paths: &[String]) -> Result<HashSet<String>, Report> {
+    let mut walked = HashSet::new();
+    for path in paths {
+        let found = match indexer.index_path(Path::new(path)) {
+            Ok(found) => found,
+            Err(MdqError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("⚠ {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for path in found {
+            walked.insert(path.to_string_lossy().to_string());
+        }
+        indexer.commit()?;
+    }
+
+    Ok(walked)
+}
+
+/// Index each of the markdown files `paths` listed for `update --stdin-paths`, removing those
+/// which are gone, and commit them all at once. Returns every listed file found.
+fn index_listed(indexer: &mut Indexer, paths: &[String]) -> Result<HashSet<String>, Report> {
+    let mut walked = HashSet::new();
+    let mut gone = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if !path.exists() {
+            gone.push(path.to_path_buf());
+            continue;
+        }
+        for path in indexer.index_path(path)? {
+            walked.insert(path.to_string_lossy().to_string());
+        }
+    }
+    indexer.prune(&gone)?;
+    indexer.commit()?;
+
+    Ok(walked)
+}
+
+/// The markdown files listed in `input`, one per line or NUL separated when `null`, as `update
+/// --stdin-paths` reads them. Relative paths are resolved against `cwd`, as the index keeps
+/// whole paths.
+fn listed_paths(input: impl BufRead, null: bool, cwd: &Path) -> Result<Vec<String>, Report> {
+    let separator = if null { b'\0' } else { b'\n' };
+    let mut paths = Vec::new();
+    for path in input.split(separator) {
+        let path = String::from_utf8(path?).wrap_err("Paths on stdin must be UTF-8")?;
+        let path = if null {
+            path.as_str()
+        } else {
+            path.trim_end_matches('\r')
+        };
+        if Path::new(path).extension().map_or(false, |ext| ext == "md") {
+            paths.push(cwd.join(path).to_string_lossy().to_string());
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod listed_paths_tests {
+    use super::test_utils::write_note;
+    use super::*;
+
+    #[test]
+    fn filters_markdown() {
+        let cwd = Path::new("/home/me");
+        let input = "notes/one.md\r\nREADME.txt\n\nnotes/two words.md\nnotes\n/tmp/three.md\n";
+        assert_eq!(
+            vec![
+                "/home/me/notes/one.md",
+                "/home/me/notes/two words.md",
+                "/tmp/three.md"
+            ],
+            listed_paths(input.as_bytes(), false, cwd).unwrap()
+        );
+        let input = "one.md\0line\nbreak.md\0image.png\0";
+        assert_eq!(
+            vec!["/home/me/one.md", "/home/me/line\nbreak.md"],
+            listed_paths(input.as_bytes(), true, cwd).unwrap()
+        );
+    }
+
+    #[test]
+    fn indexes_only_listed() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let note = |name: &str| {
+            write_note(tmp.path(), name, name, &[], "Some text");
+            tmp.path().join(name).to_string_lossy().to_string()
+        };
+        let listed = note("listed.md");
+        let deleted = note("deleted.md");
+        note("unlisted.md");
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        let indexed = || -> Vec<String> {
+            indexed_documents(&db_path)
+                .unwrap()
+                .into_iter()
+                .map(|doc| doc.fullpath)
+                .collect()
+        };
+        let update = |input: String| {
+            let paths = listed_paths(input.as_bytes(), false, tmp.path()).unwrap();
+            let mut indexer = Indexer::open(Path::new(&db_path)).unwrap();
+            index_listed(&mut indexer, &paths).expect("Failed to index")
+        };
+
+        let walked = update(format!("{}\n{}\n", listed, note("listed.txt")));
+        assert_eq!(HashSet::from([listed.clone()]), walked);
+        assert_eq!(vec![listed.clone()], indexed());
+
+        // Relative to the current directory, and removed once it's gone
+        update(String::from("deleted.md\n"));
+        assert_eq!(vec![deleted.clone(), listed.clone()], {
+            let mut indexed = indexed();
+            indexed.sort();
+            indexed
+        });
+        fs::remove_file(&deleted).unwrap();
+        update(format!("{}\n", deleted));
+        assert_eq!(vec![listed], indexed());
+    }
+}
+
+/// `paths` along with the directory of each of `notebooks`, everything an update walks
+fn with_notebooks(paths: &[String], notebooks: &[Notebook]) -> Vec<String> {
+    paths
+        .iter()
+        .cloned()
+        .chain(
+            notebooks
+                .iter()
+                .map(|notebook| notebook.root.to_string_lossy().to_string()),
+        )
+        .collect()
+}
+
+/// Parse every markdown file under `paths`, writing whether each is fine along with a final
+/// count, and fail if any couldn't be parsed
+fn check_notes(
+    out: &mut impl Write,
+    paths: &[String],
+    infer_frontmatter: bool,
+) -> Result<(), Report> {
+    let (mut checked, mut failed) = (0, 0);
+    for path in paths {
+        for path in markdown_files(path, false) {
+            checked += 1;
+            match Document::parse_file(&path, infer_frontmatter) {
+                Ok(_) => writeln!(out, "✅ {}", path.display())?,
+                Err(e) => {
+                    failed += 1;
+                    // Most errors name the file already
+                    let prefix = format!("{}: ", path.display());
+                    let msg = e.to_string();
+                    let msg = msg.strip_prefix(&prefix).unwrap_or(&msg);
+                    writeln!(out, "❌ {}: {}", path.display(), msg)?;
+                }
+            }
+        }
+    }
+    writeln!(out, "{} note(s) checked, {} failed", checked, failed)?;
+    if failed > 0 {
+        return Err(eyre!("{} of {} note(s) failed to parse", failed, checked));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod check_notes_tests {
+    use super::test_utils::write_note;
+    use super::*;
+
+    #[test]
+    fn broken_note() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(notes.join("sub")).unwrap();
+        write_note(&notes, "good.md", "Good", &["rust"], "body");
+        fs::write(
+            notes.join("sub").join("bad.md"),
+            "---\ndate: someday\ntitle: Bad\n---\n\nbody\n",
+        )
+        .unwrap();
+        let paths = vec![notes.to_string_lossy().to_string()];
+
+        let mut out = Vec::new();
+        let e = check_notes(&mut out, &paths, false).unwrap_err();
+        assert!(e.to_string().contains("1 of 2"), "{}", e);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(3, lines.len(), "{}", out);
+        assert!(lines.contains(&format!("✅ {}", notes.join("good.md").display()).as_str()));
+        let bad = format!("❌ {}: ", notes.join("sub").join("bad.md").display());
+        assert!(lines.iter().any(|l| l.starts_with(&bad)), "{}", out);
+        assert_eq!("2 note(s) checked, 1 failed", lines[2]);
+
+        fs::remove_file(notes.join("sub").join("bad.md")).unwrap();
+        let mut out = Vec::new();
+        check_notes(&mut out, &paths, false).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("1 note(s) checked, 0 failed\n"));
+    }
+}
+
+/// Summarize why files couldn't be indexed, once they've all been tried
+fn report_failures(failures: &[String]) {
+    if failures.is_empty() {
+        return;
+    }
+    eprintln!("❌ {} file(s) couldn't be indexed:", failures.len());
+    for failure in failures {
+        eprintln!("  {}", failure);
+    }
+}
+
+/// Mention files which changed while they were being indexed, and so were left for next time
+fn report_skipped(skipped: &[PathBuf]) {
+    if skipped.is_empty() {
+        return;
+    }
+    eprintln!(
+        "⏭ {} file(s) changed while being indexed and were skipped:",
+        skipped.len()
+    );
+    for path in skipped {
+        eprintln!("  {}", path.display());
+    }
+}
+
+/// Every document in the index at `db_path`, without creating the index if it doesn't exist
+fn indexed_documents(db_path: &str) -> Result<Vec<Document>, Report> {
+    if !Path::new(db_path).exists() {
+        return Ok(Vec::new());
+    }
+    let mut db = Database::new_with_path(db_path, DB_CREATE_OR_OPEN)?;
+    interactive::xapian_utils::all_documents(&mut db)
+}
+
+/// Indexed paths which are under one of `roots` but weren't `walked`, ie whose files are gone
+fn stale_paths(
+    indexed: impl IntoIterator<Item = String>,
+    roots: &[String],
+    walked: &HashSet<String>,
+) -> Vec<String> {
+    let mut stale: Vec<String> = indexed
+        .into_iter()
+        .filter(|path| !walked.contains(path))
+        .filter(|path| roots.iter().any(|root| Path::new(path).starts_with(root)))
+        .collect();
+    stale.sort();
+    stale
+}
+
+/// How running Update would change the index
+#[derive(Debug, Default)]
+struct UpdatePlan {
+    new: Vec<String>,
+    modified: Vec<String>,
+    unchanged: Vec<String>,
+    deleted: Vec<String>,
+    failed: Vec<String>,
+    /// Why each of the failed files couldn't be loaded
+    failures: Vec<String>,
+}
+
+impl UpdatePlan {
+    fn categories(&self) -> [(&str, &Vec<String>); 5] {
+        [
+            ("new", &self.new),
+            ("modified", &self.modified),
+            ("unchanged", &self.unchanged),
+            ("deleted", &self.deleted),
+            ("failed", &self.failed),
+        ]
+    }
+
+    /// Print a count per category, and each file at debug verbosity
+    fn report(&self) {
+        for (category, paths) in self.categories() {
+            for path in paths {
+                debug!("{:<10} {}", category, path);
+            }
+        }
+        for (category, paths) in self.categories() {
+            println!("{:<10} {:>6}", category, paths.len());
+        }
+        report_failures(&self.failures);
+    }
+}
+
+/// Classify each markdown file under `paths` against what's already `indexed`, without writing
+/// anything. Deletions are only worked out when pruning.
+fn plan_update(
+    indexed: Vec<Document>,
+    paths: &[String],
+    prune: bool,
+    infer_frontmatter: bool,
+    settings: &IndexSettings,
+) -> UpdatePlan {
+    let mut indexed: HashMap<String, Document> = indexed
+        .into_iter()
+        .map(|doc| (doc.fullpath.clone(), doc))
+        .collect();
+
+    let mut plan = UpdatePlan::default();
+    let mut walked = HashSet::new();
+    for path in paths {
+        for path in markdown_files(path, settings.follow_symlinks) {
+            let fullpath = path.to_string_lossy().to_string();
+            match (
+                Document::parse_file(&path, infer_frontmatter),
+                indexed.remove(&fullpath),
+            ) {
+                (Err(e), _) => {
+                    plan.failed.push(fullpath.clone());
+                    plan.failures.push(e.to_string());
+                }
+                (Ok(_), None) => plan.new.push(fullpath.clone()),
+                (Ok(mut doc), Some(existing)) => {
+                    // Notes without an id in their frontmatter get a fresh one on every parse, and
+                    // only the contents count here rather than when the file was written
+                    doc.id = existing.id.clone();
+                    doc.file_modified = existing.file_modified.clone();
+                    doc.content_hash = existing.content_hash.clone();
+                    // As the indexer would file it, so a note moved between notebooks counts
+                    doc.notebook = notebook_for(&settings.notebooks, &path);
+                    if settings.metadata_only {
+                        doc.body.clear();
+                    }
+                    if settings.lowercase_tags {
+                        doc.tags = document::clean_tags(doc.tags, true);
+                    }
+                    if doc == existing {
+                        plan.unchanged.push(fullpath.clone());
+                    } else {
+                        plan.modified.push(fullpath.clone());
+                    }
+                }
+            }
+            walked.insert(fullpath);
+        }
+    }
+
+    if prune {
+        plan.deleted = stale_paths(indexed.into_keys(), paths, &walked);
+    }
+    plan
+}
+
+/// Build a fresh index of `paths` in a temporary directory next to `db_path`, and only swap it
+/// into place once it's complete so that an interrupted reindex leaves the live index untouched
+fn reindex(
+    db_path: &str,
+    paths: &[String],
+    infer_frontmatter: bool,
+    settings: &IndexSettings,
+) -> Result<(), Report> {
+    let db_path = Path::new(db_path);
+    let name = db_path
+        .file_name()
+        .ok_or_else(|| eyre!("Invalid db path {}", db_path.display()))?
+        .to_string_lossy();
+    let parent = match db_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+
+    // Removed automatically if anything below fails
+    let tmp = tempfile::Builder::new()
+        .prefix(&format!(".{}-reindex-", name))
+        .tempdir_in(parent)?;
+    {
+        let mut indexer = Indexer::create_with(tmp.path(), settings.backend)?;
+        settings.apply(&mut indexer);
+        indexer.infer_frontmatter = infer_frontmatter;
+        index_paths(&mut indexer, paths)?;
+        report_failures(&indexer.failures);
+        report_skipped(&indexer.skipped);
+    }
+
+    // Move the old index aside rather than deleting it first, so it can be restored if the new
+    // one can't be moved into place. Its own temporary directory means one left behind by an
+    // earlier run is never in the way, and it goes once the new index is in place.
+    let aside = tempfile::Builder::new()
+        .prefix(&format!(".{}-old-", name))
+        .tempdir_in(parent)?;
+    let old = aside.path().join(&*name);
+    if db_path.exists() {
+        fs::rename(db_path, &old)?;
+    }
+    if let Err(e) = fs::rename(tmp.path(), db_path) {
+        if old.exists() {
+            if let Err(restore) = fs::rename(&old, db_path) {
+                let kept = aside.into_path().join(&*name);
+                return Err(eyre!(
+                    "Couldn't replace {}: {}, nor put the old index back: {}. It's kept at {}",
+                    db_path.display(),
+                    e,
+                    restore,
+                    kept.display()
+                ));
+            }
+        }
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Guidance to print instead of starting a query when there's no index at `db_path` yet. Doesn't
+/// create the index if it's missing.
+fn missing_index_message(db_path: &str) -> Result<Option<String>, Report> {
+    let empty = if Path::new(db_path).exists() {
+        let mut db = Database::new_with_path(db_path, DB_CREATE_OR_OPEN)?;
+        interactive::xapian_utils::is_empty(&mut db)?
+    } else {
+        true
+    };
+
+    if empty {
+        Ok(Some(format!(
+            "No index found at {}; run `mdq update <dir>` first",
+            db_path
+        )))
+    } else {
+        Ok(None)
+    }
+}
+
 fn main() -> Result<(), Report> {
     // Parse CLI Arguments
     let cli = Cli::parse();
@@ -69,73 +1522,674 @@ fn main() -> Result<(), Report> {
         .filter_level(cli.verbose.log_level_filter())
         .init();
 
-    let db_path: String = shellexpand::tilde(cli.db_path.to_str().unwrap()).into();
+    // Flags win over the config of the vault we're in, which wins over the global config
+    let config = Config::discover(&std::env::current_dir()?)?;
+    let db_path: String = match cli.db_path {
+        Some(ref path) => shellexpand::tilde(path.to_str().unwrap()).into(),
+        None => config
+            .db_path
+            .unwrap_or_else(|| shellexpand::tilde("~/.mdq-data").into()),
+    };
+    let default_fields = match cli.default_fields {
+        Some(ref fields) => fields.clone(),
+        None => config.default_fields.map_or_else(
+            || String::from("title,body,tags"),
+            |fields| fields.join(","),
+        ),
+    };
 
     setup()?;
+    set_date_format(DateFormat::new(&cli.date_tz, &cli.date_format)?);
 
-    match cli.subcommand {
-        Some(Subcommands::Update { ref paths }) => {
-            let mut db = WritableDatabase::new(&db_path, BRASS, DB_CREATE_OR_OPEN)
-                .expect("Could not open db for writing");
-            let mut tg = TermGenerator::new()?;
-            let mut stemmer = Stem::new("en")?;
-            tg.set_stemmer(&mut stemmer)?;
+    let custom_fields = match cli.field_config.or(config.field_config) {
+        Some(ref path) => {
+            let path: String = shellexpand::tilde(path).into();
+            let config = fs::read_to_string(&path)
+                .wrap_err_with(|| format!("while reading field config {}", path))?;
+            parse_custom_fields(&config).wrap_err_with(|| format!("in field config {}", path))?
+        }
+        None => Vec::new(),
+    };
+    // Where notebooks are, for notes indexed one at a time as well as by `update --notebook`
+    let notebooks: Vec<Notebook> = config
+        .notebooks
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, root)| Notebook {
+            name,
+            root: PathBuf::from(root),
+        })
+        .collect();
+    let mut settings = IndexSettings {
+        backend: cli.backend,
+        custom_fields: custom_fields.clone(),
+        hierarchical_tags: cli.hierarchical_tags,
+        cjk: cli.cjk,
+        metadata_only: cli.metadata_only,
+        strip_markdown: cli.strip_markdown,
+        index_code_blocks: cli.index_code_blocks.unwrap_or(true),
+        follow_symlinks: cli.follow_symlinks,
+        lowercase_tags: cli.lowercase_tags,
+        notebooks,
+    };
 
-            for path in paths {
-                let walker = WalkDir::new(path).into_iter();
-                for entry in walker.filter_entry(|e| {
-                    !e.file_name()
-                        .to_str()
-                        .map(|s| s.starts_with('.'))
-                        .unwrap_or(false)
-                }) {
-                    match entry {
-                        Ok(path) => {
-                            let path = path.path();
-                            if path.extension().is_none() || path.extension().unwrap() != "md" {
-                                continue;
-                            }
-                            if let Ok(doc) = document::Document::parse_file(path) {
-                                doc.update_index(&mut db, &mut tg)?;
-                                debug!("✅ {}", path.display());
-                            } else {
-                                error!("❌ Failed to load file {}", path.display());
-                            }
-                        }
+    let select_style = match config.select_style {
+        Some(ref style) => parse_style(style).wrap_err("in config select_style")?,
+        None => default_selected(),
+    };
+    let options = interactive::Options {
+        max_input_len: cli.max_input_len,
+        color: use_color(cli.no_color, std::env::var_os("NO_COLOR")),
+        output: cli.output,
+        default_fields: interactive::xapian_utils::parse_fields(&default_fields)?,
+        custom_fields: custom_fields.clone(),
+        cjk: cli.cjk,
+        pager_always: cli.pager_always,
+        sort: cli.sort,
+        reverse: cli.reverse,
+        tiebreak: cli.tiebreak,
+        min_score: cli.min_score,
+        dedupe_by: cli.dedupe_by,
+        select_symbol: config
+            .select_symbol
+            .unwrap_or_else(|| String::from(SELECT_SYMBOL)),
+        select_style,
+        key_bindings: interactive::keys::parse_bindings(&config.keys.unwrap_or_default())
+            .wrap_err("in config keys")?,
+        dates: DateBounds::default(),
+        recent: None,
+        preview_mode: cli.preview_mode,
+        cache_size: cli.cache_size,
+    };
 
-                        Err(e) => error!("❌ {:?}", e),
+    match cli.subcommand {
+        Some(Subcommands::Update {
+            ref paths,
+            stdin_paths,
+            null,
+            dry_run,
+            prune,
+            infer_frontmatter,
+            fail_on_duplicate,
+            change_detection,
+            profile,
+            notebooks: ref given,
+        }) => {
+            let paths = &if stdin_paths {
+                listed_paths(io::stdin().lock(), null, &std::env::current_dir()?)?
+            } else {
+                with_notebooks(paths, given)
+            };
+            settings.notebooks.extend(given.iter().cloned());
+            let indexed = if dry_run || prune || change_detection.is_some() {
+                indexed_documents(&db_path)?
+            } else {
+                Vec::new()
+            };
+
+            if dry_run {
+                // Listed files which are gone are removed whether or not pruning
+                let prune = prune || stdin_paths;
+                plan_update(indexed, paths, prune, infer_frontmatter, &settings).report();
+            } else {
+                let mut indexer = settings.open(Path::new(&db_path))?;
+                indexer.infer_frontmatter = infer_frontmatter;
+                indexer.fail_on_duplicate = fail_on_duplicate;
+                if profile {
+                    indexer.profile = Some(Profile::default());
+                }
+                if let Some(detection) = change_detection {
+                    indexer.skip_unchanged(detection, &indexed);
+                }
+                let walked = if stdin_paths {
+                    index_listed(&mut indexer, paths)?
+                } else {
+                    index_paths(&mut indexer, paths)?
+                };
+                report_failures(&indexer.failures);
+                report_skipped(&indexer.skipped);
+                if prune {
+                    let indexed = indexed.into_iter().map(|doc| doc.fullpath);
+                    let stale: Vec<PathBuf> = stale_paths(indexed, paths, &walked)
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect();
+                    indexer.prune(&stale)?;
+                    indexer.commit()?;
+                }
+                if let Some(profile) = &indexer.profile {
+                    eprintln!("{}", profile.summary());
+                }
+            }
+        }
+        Some(Subcommands::Reindex {
+            ref paths,
+            infer_frontmatter,
+            notebooks: ref given,
+        }) => {
+            settings.notebooks.extend(given.iter().cloned());
+            reindex(
+                &db_path,
+                &with_notebooks(paths, given),
+                infer_frontmatter,
+                &settings,
+            )?;
+        }
+        Some(Subcommands::Check {
+            ref paths,
+            infer_frontmatter,
+        }) => {
+            check_notes(&mut io::stdout().lock(), paths, infer_frontmatter)?;
+        }
+        Some(Subcommands::Notebooks { json }) => {
+            let mut db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN)?;
+            let notebooks = interactive::xapian_utils::notebook_counts(&mut db)?;
+            if json {
+                let notebooks: Vec<serde_json::Value> = notebooks
+                    .iter()
+                    .map(|(name, count)| serde_json::json!({ "notebook": name, "count": count }))
+                    .collect();
+                println!("{}", serde_json::to_string(&notebooks)?);
+            } else {
+                for (name, count) in notebooks {
+                    println!("{:>6}  {}", count, name);
+                }
+            }
+        }
+        Some(Subcommands::Add {
+            ref file,
+            infer_frontmatter,
+        }) => {
+            let failures = add_note(Path::new(file), &db_path, &settings, infer_frontmatter)?;
+            if !failures.is_empty() {
+                return Err(eyre!("Couldn't index {}: {}", file, failures.join("; ")));
+            }
+        }
+        Some(Subcommands::Remove { ref file }) => {
+            if let Some(msg) = missing_index_message(&db_path)? {
+                eprintln!("{}", msg);
+                return Ok(());
+            }
+            remove_note(Path::new(file), &db_path, cli.backend)?;
+        }
+        Some(Subcommands::Repair { force }) => {
+            if repair(Path::new(&db_path), force)? {
+                eprintln!("Cleared the lock on {}", db_path);
+            } else {
+                eprintln!("{} isn't locked", db_path);
+            }
+        }
+        Some(Subcommands::Serve) => {
+            let open_indexer = || settings.open(Path::new(&db_path));
+            let mut server = Server::new(Path::new(&db_path), &open_indexer)?;
+            server.search_options = SearchOptions {
+                sort: options.sort,
+                reverse: options.reverse,
+                tiebreak: options.tiebreak,
+                min_score: options.min_score,
+                dedupe_by: options.dedupe_by,
+                default_fields: options.default_fields,
+                custom_fields: options.custom_fields,
+                cjk: options.cjk,
+                ..SearchOptions::default()
+            };
+            server.serve(io::stdin().lock(), io::stdout().lock())?;
+        }
+        Some(Subcommands::Edit { ref note, pick }) => {
+            if let Some(msg) = missing_index_message(&db_path)? {
+                eprintln!("{}", msg);
+                return Ok(());
+            }
+            let path = match resolve_note(&db_path, note)? {
+                Resolved::Found(doc) => PathBuf::from(doc.fullpath),
+                Resolved::Missing => return Err(eyre!("No note has the id or title '{}'", note)),
+                Resolved::Ambiguous(_) if pick => {
+                    interactive::setup_panic();
+                    let db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN)?;
+                    let options = interactive::Options {
+                        output: interactive::Output::Path,
+                        ..options
+                    };
+                    // Resolving found matches, so there is a title query
+                    let query = title_query(note).unwrap_or_default();
+                    let reindex = reindexer(&db_path, &settings);
+                    let selected = interactive::query(
+                        db,
+                        cli.pager,
+                        cli.editor.clone(),
+                        query,
+                        options,
+                        &reindex,
+                    )?;
+                    match note_to_open(selected) {
+                        Some(path) => path,
+                        None => return Ok(()),
                     }
                 }
-
-                db.commit()?;
+                Resolved::Ambiguous(candidates) => {
+                    eprintln!("{} notes match '{}':", candidates.len(), note);
+                    for doc in &candidates {
+                        eprintln!("  {}\t{}\t{}", doc.id, doc.title, doc.fullpath);
+                    }
+                    return Err(eyre!(
+                        "'{}' is ambiguous, use one of the ids above or --pick",
+                        note
+                    ));
+                }
+            };
+            edit_and_reindex(&cli.editor, &path, &db_path, &settings)?;
+        }
+        Some(Subcommands::Tags { alpha, json }) => {
+            let mut db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN)?;
+            let mut tags = interactive::xapian_utils::tag_counts(&mut db)?;
+            if alpha {
+                tags.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+            if json {
+                let tags: Vec<serde_json::Value> = tags
+                    .iter()
+                    .map(|(tag, count)| serde_json::json!({ "tag": tag, "count": count }))
+                    .collect();
+                println!("{}", serde_json::to_string(&tags)?);
+            } else {
+                for (tag, count) in tags {
+                    println!("{:>6}  {}", count, tag);
+                }
             }
         }
         None => {
+            if let Some(msg) = missing_index_message(&db_path)? {
+                eprintln!("{}", msg);
+                return Ok(());
+            }
             interactive::setup_panic();
             let db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN)?;
-            let iter = IntoIterator::into_iter(interactive::query(
+            let reindex = reindexer(&db_path, &settings);
+            let selected = interactive::query(
                 db,
                 cli.pager,
                 cli.editor,
                 String::from(""),
-            )?); // strings is moved here
-            for s in iter {
-                // next() moves a string out of the iter
-                println!("{}", s);
-            }
+                options,
+                &reindex,
+            )?;
+            print_selected(&mut io::stdout().lock(), selected, cli.output_null)?;
         }
-        Some(Subcommands::Query { query }) => {
+        Some(Subcommands::Query {
+            query,
+            query_file,
+            open,
+            since,
+            until,
+            recent,
+            format,
+            json,
+            list,
+            limit,
+            projection,
+            explain,
+        }) => {
+            let dates = DateBounds { since, until };
+            // Fixed from here on, outside of interactive mode where it can be changed
+            let fixed_dates = match &recent {
+                Some(recent) => dates.within(&recent.range(Utc::now())),
+                None => dates.clone(),
+            };
+            let batch = query.len() > 1 || query_file.is_some();
+            let queries = read_queries(query, query_file.as_deref())?;
+            if explain {
+                let parse_options = ParseOptions {
+                    default_fields: options.default_fields,
+                    custom_fields: options.custom_fields,
+                    cjk: options.cjk,
+                    ..ParseOptions::default()
+                };
+                for (i, query) in queries.iter().enumerate() {
+                    if batch {
+                        println!("{}==> {} <==", if i > 0 { "\n" } else { "" }, query);
+                    }
+                    println!("{}", explain_query(query, &parse_options, &fixed_dates)?);
+                }
+                return Ok(());
+            }
+
+            let template = format.as_deref().map(Template::parse).transpose()?;
+            if batch {
+                if open {
+                    return Err(eyre!("--open needs a single query"));
+                }
+                let opts = search_options(&options, fixed_dates, limit);
+                let mut results = Vec::new();
+                for query in queries {
+                    let docs = markdown_query::search(Path::new(&db_path), &query, opts.clone())?;
+                    results.push((query, docs));
+                }
+                print_batch(
+                    &mut io::stdout().lock(),
+                    results,
+                    template.as_ref(),
+                    if json { Some(projection) } else { None },
+                    list,
+                    options.output,
+                )?;
+                return Ok(());
+            }
+            let query = queries.into_iter().next().unwrap_or_default();
+
+            if format.is_some() || json || list {
+                let opts = search_options(&options, fixed_dates, limit);
+                let docs = markdown_query::search(Path::new(&db_path), &query, opts)?;
+                match template {
+                    None if list => {
+                        for doc in docs {
+                            println!("{}", interactive::list_row(&doc));
+                        }
+                    }
+                    Some(template) => {
+                        for doc in docs {
+                            println!("{}", template.render(&doc));
+                        }
+                    }
+                    None => {
+                        let docs: Vec<Document> = docs
+                            .into_iter()
+                            .map(|doc| doc.with_serialization(projection.clone()))
+                            .collect();
+                        println!("{}", serde_json::to_string(&docs)?);
+                    }
+                }
+                return Ok(());
+            }
+
+            if let Some(msg) = missing_index_message(&db_path)? {
+                eprintln!("{}", msg);
+                return Ok(());
+            }
             interactive::setup_panic();
 
             let db = Database::new_with_path(&db_path, DB_CREATE_OR_OPEN)?;
-            let iter =
-                IntoIterator::into_iter(interactive::query(db, cli.pager, cli.editor, query)?); // strings is moved here
-            for s in iter {
-                // next() moves a string out of the iter
-                println!("{}", s);
+            let options = interactive::Options {
+                output: query_output(open, options.output),
+                dates,
+                recent,
+                ..options
+            };
+            let reindex = reindexer(&db_path, &settings);
+            let selected =
+                interactive::query(db, cli.pager, cli.editor.clone(), query, options, &reindex)?;
+            if open {
+                // The TUI has exited by now, so the editor gets the terminal to itself
+                if let Some(path) = note_to_open(selected) {
+                    open_note(&cli.editor, &path)?;
+                }
+                return Ok(());
             }
+            print_selected(&mut io::stdout().lock(), selected, cli.output_null)?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod reindex_tests {
+    use super::test_utils::write_note;
+    use super::*;
+    use xapian_rusty::FeatureFlag::FlagBoolean;
+    use xapian_rusty::QueryParser;
+
+    // Sorted paths of the documents matching `query`
+    fn query_paths(db_path: &str, query: &str) -> Vec<String> {
+        let mut db =
+            Database::new_with_path(db_path, DB_CREATE_OR_OPEN).expect("Failed to open db");
+        let mut enq = db.new_enquire().expect("Failed to create enquire");
+        let mut qp = QueryParser::new().expect("Failed to create queryparser");
+        let mut query = qp
+            .parse_query(query, FlagBoolean as i16)
+            .expect("Failed to parse query");
+        enq.set_query(&mut query).expect("Failed to set query");
+        let mut mset = enq.get_mset(0, 100).expect("Failed to get mset");
+
+        let mut paths = Vec::new();
+        let mut v = mset.iterator().unwrap();
+        while v.is_next().unwrap() {
+            let data = v.get_document_data().expect("Failed to get document data");
+            let doc: document::Document = serde_json::from_str(&data).expect("Invalid document");
+            paths.push(doc.fullpath);
+            v.next().unwrap();
+        }
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn reindex_matches_update() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let notes = tmp.path().join("notes");
+        fs::create_dir(&notes).unwrap();
+        write_note(&notes, "one.md", "First note", &["rust"], "Some rust text");
+        write_note(&notes, "two.md", "Second note", &["rust"], "Some rust text");
+        write_note(&notes, "three.md", "Third note", &["vim"], "Some vim text");
+
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        let paths = vec![notes.to_string_lossy().to_string()];
+        {
+            let mut indexer = Indexer::open(Path::new(&db_path)).unwrap();
+            index_paths(&mut indexer, &paths).expect("Failed to index");
+        }
+        let before = query_paths(&db_path, "rust");
+        assert_eq!(2, before.len());
+
+        reindex(&db_path, &paths, false, &IndexSettings::default()).expect("Failed to reindex");
+        assert_eq!(before, query_paths(&db_path, "rust"));
+
+        let mut entries: Vec<String> = fs::read_dir(tmp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        entries.sort();
+        assert_eq!(vec!["db", "notes"], entries);
+    }
+
+    #[test]
+    fn reindex_past_a_leftover_index() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let notes = tmp.path().join("notes");
+        fs::create_dir(&notes).unwrap();
+        write_note(&notes, "one.md", "First note", &["rust"], "Some rust text");
+
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        let paths = vec![notes.to_string_lossy().to_string()];
+        // Where older versions moved the live index aside, left behind by an interrupted run
+        let leftover = tmp.path().join(".db-old");
+        fs::create_dir(&leftover).unwrap();
+        fs::write(leftover.join("flintlock"), "").unwrap();
+
+        for _ in 0..2 {
+            reindex(&db_path, &paths, false, &IndexSettings::default()).expect("Failed to reindex");
+            assert_eq!(1, query_paths(&db_path, "rust").len());
+        }
+        assert!(leftover.join("flintlock").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reindex_follows_symlinks() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let shared = tmp.path().join("shared");
+        let notes = tmp.path().join("notes");
+        fs::create_dir(&shared).unwrap();
+        fs::create_dir(&notes).unwrap();
+        write_note(
+            &shared,
+            "linked.md",
+            "Linked note",
+            &["rust"],
+            "Some rust text",
+        );
+        write_note(&notes, "own.md", "Own note", &["rust"], "Some rust text");
+        std::os::unix::fs::symlink(&shared, notes.join("shared")).unwrap();
+
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        let paths = vec![notes.to_string_lossy().to_string()];
+        let settings = IndexSettings {
+            follow_symlinks: true,
+            ..IndexSettings::default()
+        };
+        reindex(&db_path, &paths, false, &settings).expect("Failed to reindex");
+        assert_eq!(2, query_paths(&db_path, "rust").len());
+    }
+}
+
+#[cfg(test)]
+mod missing_index_tests {
+    use super::test_utils::write_note;
+    use super::*;
+
+    #[test]
+    fn fresh_path() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+
+        let msg = missing_index_message(&db_path)
+            .expect("Checking a missing index failed")
+            .expect("No guidance for a missing index");
+        assert!(msg.contains(&db_path), "{}", msg);
+        assert!(msg.contains("mdq update"), "{}", msg);
+        // Checking must not leave an empty index behind
+        assert!(!Path::new(&db_path).exists());
+    }
+
+    #[test]
+    fn empty_index() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        {
+            Indexer::open(Path::new(&db_path))
+                .unwrap()
+                .commit()
+                .unwrap();
+        }
+        assert!(missing_index_message(&db_path).unwrap().is_some());
+    }
+
+    #[test]
+    fn populated_index() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let notes = tmp.path().join("notes");
+        fs::create_dir(&notes).unwrap();
+        write_note(&notes, "note.md", "A note", &[], "body");
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        {
+            let mut indexer = Indexer::open(Path::new(&db_path)).unwrap();
+            index_paths(&mut indexer, &[notes.to_string_lossy().to_string()]).unwrap();
+        }
+        assert!(missing_index_message(&db_path).unwrap().is_none());
+    }
+}
+
+#[cfg(test)]
+mod update_plan_tests {
+    use super::test_utils::write_note;
+    use super::*;
+
+    #[test]
+    fn classifies_changes() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let notes = tmp.path().join("notes");
+        fs::create_dir(&notes).unwrap();
+        write_note(&notes, "same.md", "same.md", &[], "untouched");
+        write_note(&notes, "edited.md", "edited.md", &[], "before");
+        write_note(&notes, "gone.md", "gone.md", &[], "removed later");
+
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        let paths = vec![notes.to_string_lossy().to_string()];
+        {
+            let mut indexer = Indexer::open(Path::new(&db_path)).unwrap();
+            index_paths(&mut indexer, &paths).expect("Failed to index");
+        }
+
+        write_note(&notes, "edited.md", "edited.md", &[], "after");
+        write_note(&notes, "added.md", "added.md", &[], "brand new");
+        fs::remove_file(notes.join("gone.md")).unwrap();
+        fs::write(notes.join("broken.md"), "no frontmatter here\n").unwrap();
+
+        let indexed = indexed_documents(&db_path).unwrap();
+        let plan = plan_update(
+            indexed.clone(),
+            &paths,
+            true,
+            false,
+            &IndexSettings::default(),
+        );
+        let counts: Vec<usize> = plan.categories().iter().map(|(_, p)| p.len()).collect();
+        // new, modified, unchanged, deleted, failed
+        assert_eq!(vec![1, 1, 1, 1, 1], counts);
+        assert!(plan.deleted[0].ends_with("gone.md"));
+        assert!(plan.modified[0].ends_with("edited.md"));
+
+        // Without pruning nothing is reported as deleted
+        assert!(plan_update(
+            indexed.clone(),
+            &paths,
+            false,
+            false,
+            &IndexSettings::default()
+        )
+        .deleted
+        .is_empty());
+
+        // Inferring frontmatter picks up the file that failed
+        let plan = plan_update(indexed, &paths, true, true, &IndexSettings::default());
+        assert!(plan.failed.is_empty());
+        assert_eq!(2, plan.new.len());
+    }
+
+    #[test]
+    fn notebooks() {
+        let tmp = tempfile::tempdir().expect("Failed to create tempdir");
+        let notes = tmp.path().join("work");
+        fs::create_dir(&notes).unwrap();
+        write_note(&notes, "standup.md", "Standup", &[], "notes from standup");
+
+        let settings = IndexSettings {
+            notebooks: vec![Notebook {
+                name: String::from("work"),
+                root: notes.clone(),
+            }],
+            ..IndexSettings::default()
+        };
+        let db_path = tmp.path().join("db").to_string_lossy().to_string();
+        let paths = with_notebooks(&[], &settings.notebooks);
+        {
+            let mut indexer = settings.open(Path::new(&db_path)).unwrap();
+            index_paths(&mut indexer, &paths).expect("Failed to index");
+        }
+        let indexed = indexed_documents(&db_path).unwrap();
+        assert_eq!("work", indexed[0].notebook);
+
+        // A dry run with the same notebooks leaves the note be
+        let plan = plan_update(indexed.clone(), &paths, false, false, &settings);
+        let standup = notes.join("standup.md").to_string_lossy().to_string();
+        assert_eq!(vec![standup], plan.unchanged);
+
+        // And without them would take it out of its notebook
+        let plan = plan_update(indexed, &paths, false, false, &IndexSettings::default());
+        assert_eq!(1, plan.modified.len());
+        assert!(plan.unchanged.is_empty());
+    }
+
+    #[test]
+    fn stale_only_under_roots() {
+        let walked: HashSet<String> = [String::from("/notes/a.md")].into_iter().collect();
+        let indexed = vec![
+            String::from("/notes/a.md"),
+            String::from("/notes/b.md"),
+            String::from("/other/c.md"),
+        ];
+        assert_eq!(
+            vec![String::from("/notes/b.md")],
+            stale_paths(indexed, &[String::from("/notes")], &walked)
+        );
+    }
+}
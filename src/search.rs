@@ -0,0 +1,595 @@
+use crate::document::{content_hash, Document, SerializationType, DATE_SLOT};
+use crate::error::MdqError;
+use crate::interactive::xapian_utils::{
+    all_matches, apply_date_bounds, mset_documents, parse_user_query_with, CustomField, DateBounds,
+    ParseOptions, XapianTag, DEFAULT_FIELDS,
+};
+use clap::ArgEnum;
+use color_eyre::Report;
+use eyre::Result;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+use xapian_rusty::{Database, Enquire, MultiValueKeyMaker, DB_CREATE_OR_OPEN};
+
+/// Order to return search results in
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Sort {
+    /// Best match first
+    Relevance,
+    /// Most recently written first, by `date`
+    Newest,
+    /// Least recently written first, by `date`
+    Oldest,
+    /// Most recently edited first, by `modified`
+    Modified,
+}
+
+impl Default for Sort {
+    fn default() -> Sort {
+        Sort::Relevance
+    }
+}
+
+/// How matches which `Sort` ranks the same, e.g. scoring exactly alike, are ordered among
+/// themselves, so that listing them again gives the same order
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Tiebreak {
+    /// Newest first by `date`, then by title, then by id
+    Date,
+    /// By title, then by id
+    Title,
+    /// By id
+    Id,
+    /// Whatever order the index returns them in
+    None,
+}
+
+impl Default for Tiebreak {
+    fn default() -> Tiebreak {
+        Tiebreak::Date
+    }
+}
+
+impl Tiebreak {
+    /// How `a` and `b` are ordered when they'd otherwise tie
+    pub fn order(self, a: &Document, b: &Document) -> Ordering {
+        let by_id = || a.id.cmp(&b.id);
+        let by_title = || a.title.cmp(&b.title).then_with(by_id);
+        match self {
+            Tiebreak::Date => b.date.cmp(&a.date).then_with(by_title),
+            Tiebreak::Title => by_title(),
+            Tiebreak::Id => by_id(),
+            Tiebreak::None => Ordering::Equal,
+        }
+    }
+}
+
+/// What makes two matches the same note, e.g. a copy kept in two places
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DedupeBy {
+    Title,
+    Id,
+    /// Notes with exactly the same body, whatever their frontmatter
+    BodyHash,
+}
+
+/// Tunables for `search`
+#[derive(Clone, Debug)]
+pub struct SearchOptions {
+    /// Most results to return
+    pub limit: usize,
+    /// Number of results to skip, for paging through them
+    pub offset: usize,
+    pub sort: Sort,
+    /// List matches in the opposite of `sort` order, e.g. least relevant first
+    pub reverse: bool,
+    /// Order of matches `sort` ranks the same
+    pub tiebreak: Tiebreak,
+    /// Language to stem query words for, this should match what the index was built with
+    pub lang: String,
+    /// Fields a word without a `field:` prefix is searched in
+    pub default_fields: Vec<XapianTag>,
+    /// Extra fields the index was built with, see `Indexer::custom_fields`
+    pub custom_fields: Vec<CustomField>,
+    /// Split Chinese, Japanese and Korean query text into runs of characters, for an index built
+    /// with `Indexer::cjk`
+    pub cjk: bool,
+    /// Only return notes dated within these
+    pub dates: DateBounds,
+    /// Leave out matches scoring below this percent, 0 returns every match however weak
+    pub min_score: i32,
+    /// Collapse matches which are the same note by this into one, see `dedupe`
+    pub dedupe_by: Option<DedupeBy>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions {
+            limit: 100,
+            offset: 0,
+            sort: Sort::Relevance,
+            reverse: false,
+            tiebreak: Tiebreak::Date,
+            lang: String::from("en"),
+            default_fields: DEFAULT_FIELDS.to_vec(),
+            custom_fields: Vec::new(),
+            cjk: false,
+            dates: DateBounds::default(),
+            min_score: 0,
+            dedupe_by: None,
+        }
+    }
+}
+
+/// Search the index at `db_path` using the same query syntax as the interactive interface
+///
+/// ```
+/// use markdown_query::{search, Indexer, SearchOptions};
+///
+/// # fn main() -> Result<(), color_eyre::Report> {
+/// let tmp = tempfile::tempdir()?;
+/// let note = tmp.path().join("note.md");
+/// std::fs::write(
+///     &note,
+///     "---\ndate: 2021-06-22T12:48:16-0400\ntitle: Grep buffers\ntags:\n- vim\n---\n\n:bufdo vimgrepadd\n",
+/// )?;
+///
+/// let db_path = tmp.path().join("db");
+/// {
+///     let mut indexer = Indexer::open(&db_path)?;
+///     indexer.index_path(tmp.path())?;
+///     indexer.commit()?;
+/// }
+///
+/// let found = search(&db_path, "tag:vim", SearchOptions::default())?;
+/// assert_eq!("Grep buffers", found[0].title);
+/// # Ok(())
+/// # }
+/// ```
+pub fn search(db_path: &Path, query: &str, opts: SearchOptions) -> Result<Vec<Document>, MdqError> {
+    // Opening creates a missing index, don't leave an empty one behind for a typo'd path
+    if !db_path.exists() {
+        return Err(MdqError::DbOpen(format!(
+            "No index found at {}",
+            db_path.display()
+        )));
+    }
+    let mut db = Database::new_with_path(&db_path.to_string_lossy(), DB_CREATE_OR_OPEN)
+        .map_err(MdqError::db_open)?;
+    search_db(&mut db, query, opts)
+}
+
+/// Like `search`, in an index which is already open, e.g. kept open between searches
+pub fn search_db(
+    db: &mut Database,
+    query: &str,
+    opts: SearchOptions,
+) -> Result<Vec<Document>, MdqError> {
+    // The trailing ` ;` hints to Nom that it has a "full" string
+    let parse_options = ParseOptions {
+        lang: opts.lang.clone(),
+        default_fields: opts.default_fields.clone(),
+        custom_fields: opts.custom_fields.clone(),
+        cjk: opts.cjk,
+    };
+    let query = parse_user_query_with(&format!("{} ;", query), &parse_options)
+        .map_err(MdqError::query_parse)?;
+    let mut query = apply_date_bounds(query, &opts.dates).map_err(MdqError::query_parse)?;
+    let mut enq = db.new_enquire().map_err(MdqError::index)?;
+    enq.set_query(&mut query).map_err(MdqError::index)?;
+
+    sorted_matches(&mut enq, &opts, &SerializationType::Storage).map_err(MdqError::index)
+}
+
+// The `opts.limit` long window of matches for the query already set on `enq` starting at
+// `opts.offset`, once they're put in `opts.sort` order, or its opposite when `opts.reverse`.
+// Weak matches are left out, and duplicates collapsed, before paging. Best first, Xapian
+// itself orders equally scored matches newest first for `Tiebreak::Date`, though notes dated
+// alike too only go by title within the page, and leaves them be for `Tiebreak::None`, so
+// only the page is fetched. Other tiebreaks fetch the matches up to the end of the page along
+// with any past it scoring the same as the last on it, so that equally scored matches are in
+// `opts.tiebreak` order across pages.
+pub(crate) fn sorted_matches(
+    enq: &mut Enquire,
+    opts: &SearchOptions,
+    serialization: &SerializationType,
+) -> Result<Vec<Document>, Report> {
+    let (sort, offset, limit) = (opts.sort, opts.offset, opts.limit);
+    if sort == Sort::Relevance && !opts.reverse && opts.dedupe_by.is_none() {
+        // Best first, so the weak matches are all after any which are kept
+        let (mut docs, skip) = match opts.tiebreak {
+            Tiebreak::Date | Tiebreak::None => {
+                if opts.tiebreak == Tiebreak::Date {
+                    let mut newest = MultiValueKeyMaker::new()?;
+                    newest.add_value(DATE_SLOT, true)?;
+                    enq.set_sort_by_relevance_then_key(&mut newest, false)?;
+                }
+                let page = mset_documents(enq, offset as i32, limit as i32, serialization)?;
+                (page, 0)
+            }
+            Tiebreak::Title | Tiebreak::Id => {
+                let end = offset + limit;
+                let mut docs = mset_documents(enq, 0, end as i32, serialization)?;
+                while end > 0 && docs.len() >= end {
+                    let last = docs[docs.len() - 1].score;
+                    if last != docs[end - 1].score {
+                        break;
+                    }
+                    let more = mset_documents(enq, docs.len() as i32, limit as i32, serialization)?;
+                    if more.is_empty() {
+                        break;
+                    }
+                    docs.extend(more);
+                }
+                (docs, offset)
+            }
+        };
+        docs.retain(|doc| doc.percent >= opts.min_score);
+        // Stable, so ties among these which Xapian has already ordered stay put
+        docs.sort_by(|a, b| by_score(a, b).then_with(|| opts.tiebreak.order(a, b)));
+        return Ok(docs.into_iter().skip(skip).take(limit).collect());
+    }
+
+    // Xapian doesn't sort by these, and a copy could be anywhere among the matches, so every
+    // match has to be fetched and ordered here
+    let mut docs = all_matches(enq)?;
+    docs.retain(|doc| doc.percent >= opts.min_score);
+    if let Some(by) = opts.dedupe_by {
+        docs = dedupe(docs, by);
+    }
+    docs.sort_by(|a, b| {
+        match sort {
+            Sort::Relevance => by_score(a, b),
+            Sort::Newest => b.date.cmp(&a.date),
+            Sort::Oldest => a.date.cmp(&b.date),
+            Sort::Modified => b.modified.cmp(&a.modified),
+        }
+        .then_with(|| opts.tiebreak.order(a, b))
+    });
+    // The exact opposite order, ties included
+    if opts.reverse {
+        docs.reverse();
+    }
+    Ok(docs
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|mut doc| {
+            doc.serialization_type = serialization.clone();
+            doc
+        })
+        .collect())
+}
+
+// Best scoring first
+fn by_score(a: &Document, b: &Document) -> Ordering {
+    b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal)
+}
+
+/// `docs` with each set of matches which are the same note `by` collapsed into the best scoring
+/// of them, which counts the others in its `duplicates`. Otherwise in the same order.
+pub fn dedupe(docs: Vec<Document>, by: DedupeBy) -> Vec<Document> {
+    let key = |doc: &Document| match by {
+        DedupeBy::Title => doc.title.clone(),
+        DedupeBy::Id => doc.id.clone(),
+        DedupeBy::BodyHash => content_hash(doc.body.as_bytes()),
+    };
+    // For each note, its best scoring match and how many matches it has
+    let mut best: HashMap<String, (usize, usize)> = HashMap::new();
+    for (i, doc) in docs.iter().enumerate() {
+        let (kept, count) = best.entry(key(doc)).or_insert((i, 0));
+        *count += 1;
+        if doc.score > docs[*kept].score {
+            *kept = i;
+        }
+    }
+    docs.into_iter()
+        .enumerate()
+        .filter_map(|(i, mut doc)| match best.get(&key(&doc)) {
+            Some(&(kept, count)) if kept == i => {
+                doc.duplicates = count - 1;
+                Some(doc)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod dedupe_tests {
+    use super::*;
+
+    fn doc(path: &str, title: &str, id: &str, body: &str, score: f64) -> Document {
+        let mut doc = Document::new();
+        doc.fullpath = String::from(path);
+        doc.title = String::from(title);
+        doc.id = String::from(id);
+        doc.body = String::from(body);
+        doc.score = score;
+        doc
+    }
+
+    // Each kept match's path and how many duplicates it stands for
+    fn kept(docs: Vec<Document>) -> Vec<(String, usize)> {
+        docs.into_iter()
+            .map(|doc| (doc.fullpath, doc.duplicates))
+            .collect()
+    }
+
+    #[test]
+    fn each_key() {
+        let docs = vec![
+            doc("/a/vim.md", "Vim", "1", "buffers", 1.0),
+            doc("/a/rust.md", "Rust", "2", "traits", 3.0),
+            doc("/b/vim.md", "Vim", "1", "buffers", 2.0),
+            doc("/b/vim-edited.md", "Vim", "3", "buffers and more", 0.5),
+            doc("/c/vim.md", "Vim copy", "1", "buffers", 0.1),
+        ];
+        let expected = |kept: &[(&str, usize)]| -> Vec<(String, usize)> {
+            kept.iter().map(|(p, n)| (p.to_string(), *n)).collect()
+        };
+
+        assert_eq!(
+            expected(&[("/a/rust.md", 0), ("/b/vim.md", 2), ("/c/vim.md", 0)]),
+            kept(dedupe(docs.clone(), DedupeBy::Title))
+        );
+        assert_eq!(
+            expected(&[("/a/rust.md", 0), ("/b/vim.md", 2), ("/b/vim-edited.md", 0)]),
+            kept(dedupe(docs.clone(), DedupeBy::Id))
+        );
+        assert_eq!(
+            expected(&[("/a/rust.md", 0), ("/b/vim.md", 2), ("/b/vim-edited.md", 0)]),
+            kept(dedupe(docs.clone(), DedupeBy::BodyHash))
+        );
+        // Ties keep the first
+        let tied = vec![
+            doc("/a.md", "A", "1", "", 1.0),
+            doc("/b.md", "A", "2", "", 1.0),
+        ];
+        assert_eq!(
+            expected(&[("/a.md", 1)]),
+            kept(dedupe(tied, DedupeBy::Title))
+        );
+    }
+}
+
+#[cfg(test)]
+mod search_tests {
+    use super::*;
+    use crate::date::Date;
+    use crate::interactive::xapian_utils::test_utils::{index_documents, note};
+
+    // Notes 0..count, all mentioning rust, dated in index order
+    fn index_notes(db_path: &Path, count: usize) {
+        let docs: Vec<Document> = (0..count)
+            .map(|i| {
+                let mut doc = note(i, &"rust ".repeat(i + 1), &[]);
+                doc.date = Date::new(1_600_000_000 + i as i64);
+                // Edited in the reverse order they were written
+                doc.modified = Date::new(1_700_000_000 - i as i64);
+                doc
+            })
+            .collect();
+        index_documents(&db_path.to_string_lossy(), &docs);
+    }
+
+    fn titles(docs: Vec<Document>) -> Vec<String> {
+        docs.into_iter().map(|d| d.title).collect()
+    }
+
+    #[test]
+    fn missing_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        assert!(search(&db_path, "rust", SearchOptions::default()).is_err());
+        assert!(!db_path.exists());
+    }
+
+    #[test]
+    fn limit_and_offset() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        index_notes(&db_path, 5);
+
+        let all = search(&db_path, "rust", SearchOptions::default()).unwrap();
+        assert_eq!(5, all.len());
+        assert!(all.iter().all(|d| !d.body.is_empty()));
+
+        let page = search(
+            &db_path,
+            "rust",
+            SearchOptions {
+                limit: 2,
+                offset: 1,
+                ..SearchOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(titles(all[1..3].to_vec()), titles(page));
+    }
+
+    #[test]
+    fn by_date() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        index_notes(&db_path, 4);
+
+        let sorted = |sort, offset| {
+            titles(
+                search(
+                    &db_path,
+                    "rust",
+                    SearchOptions {
+                        limit: 2,
+                        offset,
+                        sort,
+                        ..SearchOptions::default()
+                    },
+                )
+                .unwrap(),
+            )
+        };
+        assert_eq!(vec!["Note 3", "Note 2"], sorted(Sort::Newest, 0));
+        assert_eq!(vec!["Note 1", "Note 0"], sorted(Sort::Newest, 2));
+        assert_eq!(vec!["Note 0", "Note 1"], sorted(Sort::Oldest, 0));
+        assert_eq!(vec!["Note 0", "Note 1"], sorted(Sort::Modified, 0));
+        assert_eq!(vec!["Note 2", "Note 3"], sorted(Sort::Modified, 2));
+    }
+
+    #[test]
+    fn reverse() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        index_notes(&db_path, 4);
+
+        let sorted = |sort, reverse| {
+            titles(
+                search(
+                    &db_path,
+                    "rust",
+                    SearchOptions {
+                        sort,
+                        reverse,
+                        ..SearchOptions::default()
+                    },
+                )
+                .unwrap(),
+            )
+        };
+        for sort in [Sort::Relevance, Sort::Newest, Sort::Oldest, Sort::Modified] {
+            let mut reversed = sorted(sort, false);
+            reversed.reverse();
+            assert_eq!(reversed, sorted(sort, true), "{:?}", sort);
+        }
+        // Least relevant first, the note mentioning rust least
+        assert_eq!("Note 0", sorted(Sort::Relevance, true)[0]);
+
+        // Paging goes through the reversed order
+        let page = search(
+            &db_path,
+            "rust",
+            SearchOptions {
+                limit: 2,
+                offset: 1,
+                sort: Sort::Newest,
+                reverse: true,
+                ..SearchOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(vec!["Note 1", "Note 2"], titles(page));
+    }
+
+    #[test]
+    fn tiebreak() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        let docs: Vec<Document> = [
+            ("Beta", "b", 1),
+            ("Alpha", "c", 2),
+            ("Gamma", "d", 3),
+            ("Alpha", "a", 2),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, (title, id, date))| {
+            let mut doc = note(i, "", &["rust"]);
+            doc.title = title.to_string();
+            doc.id = id.to_string();
+            doc.date = Date::new(1_600_000_000 + *date);
+            doc
+        })
+        .collect();
+        index_documents(&db_path.to_string_lossy(), &docs);
+
+        let ids = |sort, tiebreak, reverse| -> Vec<String> {
+            // Matching on the tag alone, every note scores the same
+            let found = search(
+                &db_path,
+                "tag:rust",
+                SearchOptions {
+                    sort,
+                    tiebreak,
+                    reverse,
+                    ..SearchOptions::default()
+                },
+            )
+            .unwrap();
+            assert!(found.windows(2).all(|w| w[0].score == w[1].score));
+            found.into_iter().map(|d| d.id).collect()
+        };
+        for sort in [Sort::Relevance, Sort::Modified] {
+            assert_eq!(vec!["d", "a", "c", "b"], ids(sort, Tiebreak::Date, false));
+            assert_eq!(vec!["a", "c", "b", "d"], ids(sort, Tiebreak::Title, false));
+            assert_eq!(vec!["a", "b", "c", "d"], ids(sort, Tiebreak::Id, false));
+            assert_eq!(vec!["d", "c", "b", "a"], ids(sort, Tiebreak::Id, true));
+        }
+        // Ties on the date itself go by title
+        assert_eq!(
+            vec!["b", "a", "c", "d"],
+            ids(Sort::Oldest, Tiebreak::Date, false)
+        );
+
+        // Pages of tied matches follow the tiebreak too, rather than the order they're indexed in
+        let page = |offset, limit, tiebreak| -> Vec<String> {
+            let found = search(
+                &db_path,
+                "tag:rust",
+                SearchOptions {
+                    limit,
+                    offset,
+                    tiebreak,
+                    ..SearchOptions::default()
+                },
+            )
+            .unwrap();
+            found.into_iter().map(|d| d.id).collect()
+        };
+        assert_eq!(vec!["a", "b"], page(0, 2, Tiebreak::Id));
+        assert_eq!(vec!["b", "c"], page(1, 2, Tiebreak::Id));
+        assert_eq!(vec!["c", "d"], page(2, 2, Tiebreak::Id));
+        // Which Xapian orders by date itself
+        assert_eq!(vec!["d"], page(0, 1, Tiebreak::Date));
+        assert_eq!(vec!["b"], page(3, 1, Tiebreak::Date));
+        assert_eq!(2, page(2, 2, Tiebreak::None).len());
+    }
+
+    #[test]
+    fn min_score() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        // Only the first matches both words, the rest trail off with one each
+        let docs: Vec<Document> = ["rust vim", "rust", "vim and a lot more besides"]
+            .iter()
+            .enumerate()
+            .map(|(i, body)| {
+                let mut doc = note(i, body, &[]);
+                doc.date = Date::new(1_600_000_000 + i as i64);
+                doc
+            })
+            .collect();
+        index_documents(&db_path.to_string_lossy(), &docs);
+
+        let found = |sort, min_score| {
+            search(
+                &db_path,
+                "rust vim",
+                SearchOptions {
+                    sort,
+                    min_score,
+                    ..SearchOptions::default()
+                },
+            )
+            .unwrap()
+        };
+        let all = found(Sort::Relevance, 0);
+        assert_eq!(3, all.len());
+        let best = all[0].percent;
+        assert!(all[1..].iter().all(|doc| doc.percent < best));
+
+        assert_eq!(vec!["Note 0"], titles(found(Sort::Relevance, best)));
+        assert_eq!(vec!["Note 0"], titles(found(Sort::Newest, best)));
+        assert!(found(Sort::Relevance, 101).is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::document::Document;
+use crate::error::MdqError;
+use crate::indexer::Indexer;
+use crate::search::{search_db, SearchOptions};
+use color_eyre::Report;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use xapian_rusty::{Database, DB_CREATE_OR_OPEN};
+
+/// One line of input to `Server::serve`, e.g. `{"method":"search","query":"tag:vim"}`
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum Request {
+    /// Search with the same query syntax as `mdq query`
+    Search {
+        query: String,
+        /// Most matches to return, instead of the server's default
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// Index the markdown file, or every one under the directory, at `path` and commit
+    Index { path: String },
+}
+
+/// One line of output from `Server::serve`, answering the request on the same line of input
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+    Results(Vec<Document>),
+    Indexed {
+        /// Every markdown file found
+        paths: Vec<String>,
+        /// Why each file which couldn't be indexed couldn't be
+        failures: Vec<String>,
+    },
+    Error(String),
+}
+
+/// Opens the index for writing, the way the notes served should be indexed
+pub type OpenIndexer<'a> = dyn Fn() -> Result<Indexer, Report> + 'a;
+
+/// A long running process answering requests, one JSON object per line, for editors to search
+/// and update the index without starting `mdq` for each
+pub struct Server<'a> {
+    db_path: PathBuf,
+    // Kept open for searches between requests. The index is only opened for writing to answer
+    // an index request, so that `mdq update` and others can write to it meanwhile.
+    db: Database,
+    open_indexer: &'a OpenIndexer<'a>,
+    /// How searches are run, a request's `limit` aside
+    pub search_options: SearchOptions,
+}
+
+impl<'a> Server<'a> {
+    /// Serve the index at `db_path`, creating it if need be, and updating it with what
+    /// `open_indexer` opens
+    pub fn new(db_path: &Path, open_indexer: &'a OpenIndexer<'a>) -> Result<Server<'a>, Report> {
+        Ok(Server {
+            db_path: db_path.to_path_buf(),
+            db: open_db(db_path)?,
+            open_indexer,
+            search_options: SearchOptions::default(),
+        })
+    }
+
+    /// Answer each request read from `input` on `output` until `input` ends. A request which
+    /// fails gets an error response, the server carries on with the next one.
+    pub fn serve<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> Result<(), Report> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str(&line) {
+                Ok(request) => self
+                    .handle(request)
+                    .unwrap_or_else(|e| Response::Error(e.to_string())),
+                Err(e) => Response::Error(format!("Invalid request: {}", e)),
+            };
+            serde_json::to_writer(&mut output, &response)?;
+            writeln!(output)?;
+            // The client waits on each response before sending more
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Response, Report> {
+        match request {
+            Request::Search { query, limit } => {
+                let opts = SearchOptions {
+                    limit: limit.unwrap_or(self.search_options.limit),
+                    ..self.search_options.clone()
+                };
+                let found = match search_db(&mut self.db, &query, opts.clone()) {
+                    Ok(found) => found,
+                    // What's open may have been overtaken by other writers' commits since, so
+                    // try the latest once more
+                    Err(MdqError::Index(_)) => {
+                        self.db = open_db(&self.db_path)?;
+                        search_db(&mut self.db, &query, opts)?
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                Ok(Response::Results(found))
+            }
+            Request::Index { path } => {
+                let mut indexer = (self.open_indexer)()?;
+                let paths = indexer.index_path(Path::new(&path))?;
+                indexer.commit()?;
+                // Let go of the lock before answering, and search what was just committed
+                let failures = std::mem::take(&mut indexer.failures);
+                drop(indexer);
+                self.db = open_db(&self.db_path)?;
+                Ok(Response::Indexed {
+                    paths: paths
+                        .iter()
+                        .map(|p| p.to_string_lossy().to_string())
+                        .collect(),
+                    failures,
+                })
+            }
+        }
+    }
+}
+
+fn open_db(db_path: &Path) -> Result<Database, Report> {
+    Ok(Database::new_with_path(
+        &db_path.to_string_lossy(),
+        DB_CREATE_OR_OPEN,
+    )?)
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+
+    // Each line of output, parsed
+    fn responses(output: Vec<u8>) -> Vec<serde_json::Value> {
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = tmp.path().join("notes");
+        fs::create_dir_all(&notes).unwrap();
+        fs::write(
+            notes.join("vim.md"),
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: Grep buffers\ntags:\n- vim\n---\n\n:bufdo vimgrepadd\n",
+        )
+        .unwrap();
+        fs::write(notes.join("plain.md"), "no frontmatter\n").unwrap();
+
+        let db_path = tmp.path().join("db");
+        let open_indexer = || Ok(Indexer::open(&db_path)?);
+        let mut server = Server::new(&db_path, &open_indexer).unwrap();
+        let input = format!(
+            "{}\n\n{}\n{}\n{}\n{}\n",
+            r#"{"method":"search","query":"tag:vim"}"#,
+            serde_json::json!({"method": "index", "path": notes}),
+            r#"{"method":"search","query":"tag:vim","limit":5}"#,
+            r#"{"method":"lookup","query":"vim"}"#,
+            r#"{"method":"search","query":"tag:vim","limit":0}"#,
+        );
+        let mut output = Vec::new();
+        server.serve(Cursor::new(input), &mut output).unwrap();
+
+        let responses = responses(output);
+        assert_eq!(5, responses.len());
+        // Nothing's indexed yet
+        assert_eq!(0, responses[0]["results"].as_array().unwrap().len());
+        assert_eq!(
+            2,
+            responses[1]["indexed"]["paths"].as_array().unwrap().len()
+        );
+        let failures = responses[1]["indexed"]["failures"].as_array().unwrap();
+        assert_eq!(1, failures.len());
+        assert!(failures[0].as_str().unwrap().contains("plain.md"));
+        // Searches see what was indexed by an earlier request
+        assert_eq!("Grep buffers", responses[2]["results"][0]["title"]);
+        assert!(responses[3]["error"]
+            .as_str()
+            .unwrap()
+            .contains("Invalid request"));
+        assert_eq!(0, responses[4]["results"].as_array().unwrap().len());
+
+        // Other writers aren't locked out while the server is running
+        let mut indexer = Indexer::open(&db_path).unwrap();
+        indexer.index_path(&notes.join("vim.md")).unwrap();
+        indexer.commit().unwrap();
+    }
+
+    #[test]
+    fn requests() {
+        assert_eq!(
+            Request::Search {
+                query: String::from("rust"),
+                limit: None
+            },
+            serde_json::from_str(r#"{"method":"search","query":"rust"}"#).unwrap()
+        );
+        assert_eq!(
+            Request::Index {
+                path: String::from("/notes")
+            },
+            serde_json::from_str(r#"{"method":"index","path":"/notes"}"#).unwrap()
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"method":"index"}"#).is_err());
+    }
+}
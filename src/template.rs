@@ -0,0 +1,165 @@
+use crate::document::Document;
+use color_eyre::Report;
+use eyre::{eyre, Result};
+
+/// Document fields a template can refer to, as `{name}`
+const FIELDS: [&str; 11] = [
+    "id",
+    "title",
+    "subtitle",
+    "date",
+    "modified",
+    "tags",
+    "authors",
+    "full_path",
+    "fullpath",
+    "percent",
+    "duplicates",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    Field(&'static str),
+}
+
+/// A format string like `{date}\t{title}\t{full_path}` for printing a line per document. Fields
+/// are named in braces, `{{` and `}}` are literal braces, and `\t`, `\n` and `\\` are a tab, a
+/// newline and a backslash.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(Vec<Piece>);
+
+impl Template {
+    /// Parse `fmt`, failing on unknown fields so that mistakes show up before any querying
+    pub fn parse(fmt: &str) -> Result<Template, Report> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = fmt.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('t') => text.push('\t'),
+                    Some('n') => text.push('\n'),
+                    Some('\\') => text.push('\\'),
+                    Some(other) => return Err(eyre!("Unknown escape `\\{}` in template", other)),
+                    None => return Err(eyre!("Template ends with a lone `\\`")),
+                },
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(eyre!("Unclosed `{{{}` in template", name)),
+                        }
+                    }
+                    let field = FIELDS
+                        .iter()
+                        .copied()
+                        .find(|field| *field == name)
+                        .ok_or_else(|| {
+                            eyre!(
+                                "Unknown field `{{{}}}` in template, expected one of {}",
+                                name,
+                                FIELDS.join(", ")
+                            )
+                        })?;
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(Piece::Field(field));
+                }
+                '}' => return Err(eyre!("Unmatched `}}` in template, use `}}}}` for a brace")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Template(pieces))
+    }
+
+    /// `doc` expanded into the template
+    pub fn render(&self, doc: &Document) -> String {
+        let mut out = String::new();
+        for piece in &self.0 {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Field(field) => out.push_str(&field_value(doc, field)),
+            }
+        }
+        out
+    }
+}
+
+fn field_value(doc: &Document, field: &str) -> String {
+    match field {
+        "id" => doc.id.clone(),
+        "title" => doc.title.clone(),
+        "subtitle" => doc.subtitle.clone(),
+        "date" => doc.date.to_string(),
+        "modified" => doc.modified.to_string(),
+        "tags" => doc.tags.join(","),
+        "authors" => doc.authors.to_string(),
+        "full_path" | "fullpath" => doc.fullpath.clone(),
+        "percent" => doc.percent.to_string(),
+        "duplicates" => doc.duplicates.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// `doc` expanded into the template `fmt`, see `Template`
+pub fn render_template(doc: &Document, fmt: &str) -> Result<String, Report> {
+    Ok(Template::parse(fmt)?.render(doc))
+}
+
+#[cfg(test)]
+mod template_tests {
+    use super::*;
+    use crate::date::Date;
+
+    fn doc() -> Document {
+        let mut doc = Document::new();
+        doc.id = String::from("abc123");
+        doc.title = String::from("Grep open buffers");
+        doc.fullpath = String::from("/notes/vim.md");
+        doc.tags = vec![String::from("vim"), String::from("search")];
+        doc.date = Date::new(1624380496);
+        doc
+    }
+
+    #[test]
+    fn fields_and_escapes() {
+        let doc = doc();
+        assert_eq!(
+            "Grep open buffers\t/notes/vim.md",
+            render_template(&doc, "{title}\\t{full_path}").unwrap()
+        );
+        assert_eq!(
+            "abc123: vim,search\n",
+            render_template(&doc, "{id}: {tags}\\n").unwrap()
+        );
+        assert_eq!(
+            format!("{}|{{title}}|\\", doc.date),
+            render_template(&doc, "{date}|{{title}}|\\\\").unwrap()
+        );
+        assert_eq!("", render_template(&doc, "").unwrap());
+    }
+
+    #[test]
+    fn unknown_field() {
+        let e = Template::parse("{date}\\t{name}").unwrap_err();
+        assert!(e.to_string().contains("{name}"), "{}", e);
+        assert!(Template::parse("{title").is_err());
+        assert!(Template::parse("title}").is_err());
+        assert!(Template::parse("\\q").is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! Fixtures shared by the integration tests and benchmarks
+#![allow(dead_code)]
+
+use markdown_query::document::Document;
+use markdown_query::{search, Indexer, SearchOptions};
+use std::path::Path;
+use tempfile::TempDir;
+
+/// An index in a temporary directory, removed along with everything in it when dropped
+pub struct TestIndex {
+    dir: TempDir,
+}
+
+impl TestIndex {
+    /// A new, empty index
+    pub fn new() -> TestIndex {
+        let dir = tempfile::Builder::new()
+            .prefix("mdq-test-")
+            .tempdir()
+            .expect("Failed to create tempdir");
+        Indexer::create(&dir.path().join("db")).expect("Failed to create index");
+        TestIndex { dir }
+    }
+
+    /// A new index holding `docs`
+    pub fn with(docs: &[Document]) -> TestIndex {
+        let index = TestIndex::new();
+        index.add(docs);
+        index
+    }
+
+    pub fn path(&self) -> std::path::PathBuf {
+        self.dir.path().join("db")
+    }
+
+    /// Directory next to the index for writing notes to
+    pub fn notes_dir(&self) -> std::path::PathBuf {
+        let notes = self.dir.path().join("notes");
+        std::fs::create_dir_all(&notes).expect("Failed to create notes dir");
+        notes
+    }
+
+    /// Index and commit `docs`
+    pub fn add(&self, docs: &[Document]) {
+        let mut indexer = self.indexer();
+        for doc in docs {
+            indexer.index_document(doc).expect("Failed to index");
+        }
+        indexer.commit().expect("Failed to commit");
+    }
+
+    /// An indexer writing to this index
+    pub fn indexer(&self) -> Indexer {
+        Indexer::open(&self.path()).expect("Failed to open index")
+    }
+
+    /// Titles of the documents matching `query`, sorted
+    pub fn titles(&self, query: &str) -> Vec<String> {
+        let mut titles: Vec<String> = self
+            .search(query, SearchOptions::default())
+            .into_iter()
+            .map(|d| d.title)
+            .collect();
+        titles.sort();
+        titles
+    }
+
+    pub fn search(&self, query: &str, opts: SearchOptions) -> Vec<Document> {
+        search(&self.path(), query, opts).expect("Failed to search")
+    }
+}
+
+/// A minimal note numbered `i`, titled `Note {i}`
+pub fn note(i: usize, body: &str, tags: &[&str]) -> Document {
+    let mut doc = Document::new();
+    doc.fullpath = format!("/notes/{}.md", i);
+    doc.title = format!("Note {}", i);
+    doc.body = body.to_string();
+    doc.tags = tags.iter().map(|t| t.to_string()).collect();
+    doc
+}
+
+/// Write a note with frontmatter to `dir/name`
+pub fn write_note(dir: &Path, name: &str, title: &str, body: &str) {
+    std::fs::write(
+        dir.join(name),
+        format!(
+            "---\ndate: 2021-06-22T12:48:16-0400\ntitle: {}\n---\n\n{}\n",
+            title, body
+        ),
+    )
+    .expect("Failed to write note");
+}
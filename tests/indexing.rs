@@ -0,0 +1,44 @@
+mod common;
+
+use common::{note, write_note, TestIndex};
+use std::path::PathBuf;
+
+#[test]
+fn index_path_then_prune() {
+    let index = TestIndex::new();
+    let notes = index.notes_dir();
+    write_note(&notes, "one.md", "One", "rust");
+    write_note(&notes, "two.md", "Two", "rust and vim");
+    std::fs::write(notes.join("broken.md"), "---\ntitle: Undated\n---\n\nrust\n").unwrap();
+
+    let mut indexer = index.indexer();
+    assert_eq!(3, indexer.index_path(&notes).unwrap().len());
+    assert_eq!(1, indexer.failures.len());
+    indexer.commit().unwrap();
+    assert_eq!(vec!["One", "Two"], index.titles("rust"));
+
+    indexer.prune(&[notes.join("one.md")]).unwrap();
+    indexer.commit().unwrap();
+    assert_eq!(vec!["Two"], index.titles("rust"));
+}
+
+#[test]
+fn reindexing_replaces() {
+    let index = TestIndex::with(&[note(0, "rust", &[])]);
+    let mut doc = note(0, "vim", &[]);
+    doc.title = String::from("Renamed");
+    index.add(&[doc]);
+
+    assert!(index.titles("rust").is_empty());
+    assert_eq!(vec!["Renamed"], index.titles("vim"));
+}
+
+#[test]
+fn removed_with_fixture() {
+    let path: PathBuf = {
+        let index = TestIndex::with(&[note(0, "rust", &[])]);
+        assert!(index.path().exists());
+        index.path()
+    };
+    assert!(!path.exists());
+}
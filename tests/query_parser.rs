@@ -0,0 +1,100 @@
+use markdown_query::interactive::xapian_utils::parse_user_query;
+use proptest::prelude::*;
+
+const WORDS: [&str; 5] = ["rust", "vim", "kernel", "git", "shell"];
+
+// Operators joining the parts of a query, along with how Xapian describes them
+const OPERATORS: [(&str, &str); 5] = [
+    ("AND", " AND "),
+    ("OR", " OR "),
+    ("XOR", " XOR "),
+    ("AND NOT", " AND_NOT "),
+    ("AND MAYBE", " AND_MAYBE "),
+];
+
+// Bits of query syntax, so that generated queries get into the parser's corners more often than
+// arbitrary text would
+fn token() -> impl Strategy<Value = String> {
+    prop_oneof![
+        prop::sample::select(WORDS.to_vec()).prop_map(String::from),
+        prop::sample::select(vec![
+            "AND",
+            "OR",
+            "XOR",
+            "NOT",
+            "AND NOT",
+            "AND MAYBE",
+            "NEAR",
+            "SYNONYM",
+            "FILTER",
+            "PHRASE",
+            "SCALED",
+            "RANGE",
+            "ELITE",
+            ">",
+            "<",
+            "AN",
+            "MAYBE",
+        ])
+        .prop_map(String::from),
+        prop::sample::select(vec![
+            "tag:",
+            "title:",
+            "date:",
+            "status:",
+            "notebook:",
+            "has:",
+            "filename:",
+            "-",
+            "+",
+            "\"",
+            "'",
+            ";",
+            "(",
+            ")",
+            "*",
+            "..",
+        ])
+        .prop_map(String::from),
+        "\\PC{0,8}",
+    ]
+}
+
+proptest! {
+    // Whatever gets typed, parsing it gives back a query or an error rather than panicking
+    #[test]
+    fn arbitrary_text(query in "\\PC*") {
+        let _ = parse_user_query(&query);
+        let _ = parse_user_query(&format!("{} ;", query));
+    }
+
+    #[test]
+    fn arbitrary_tokens(tokens in prop::collection::vec(token(), 0..80), glue in "[ ]{0,2}") {
+        let query = tokens.join(&glue);
+        let _ = parse_user_query(&query);
+        let _ = parse_user_query(&format!("{} ;", query));
+    }
+
+    // Words joined by operators parse, keeping every word and operator
+    #[test]
+    fn operator_chains(
+        first in prop::sample::select(WORDS.to_vec()),
+        rest in prop::collection::vec(
+            (prop::sample::select(OPERATORS.to_vec()), prop::sample::select(WORDS.to_vec())),
+            1..20,
+        ),
+    ) {
+        let mut query = first.to_string();
+        for ((op, _), word) in &rest {
+            query.push_str(&format!(" {} {}", op, word));
+        }
+        let mut parsed = parse_user_query(&format!("{} ;", query))
+            .map_err(|e| TestCaseError::fail(format!("{:#}", e)))?;
+        let desc = parsed.get_description();
+        prop_assert!(desc.contains(first), "{} became {}", query, desc);
+        for ((_, described), word) in &rest {
+            prop_assert!(desc.contains(described), "{} became {}", query, desc);
+            prop_assert!(desc.contains(word), "{} became {}", query, desc);
+        }
+    }
+}
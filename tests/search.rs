@@ -0,0 +1,48 @@
+mod common;
+
+use common::{note, TestIndex};
+use markdown_query::date::Date;
+use markdown_query::{SearchOptions, Sort};
+
+#[test]
+fn words_and_tags() {
+    let index = TestIndex::with(&[
+        note(0, "borrow checker", &["rust"]),
+        note(1, "buffers and windows", &["vim"]),
+        note(2, "rust.vim plugin", &["vim", "rust"]),
+    ]);
+
+    assert_eq!(vec!["Note 0", "Note 2"], index.titles("tag:rust"));
+    assert_eq!(vec!["Note 1", "Note 2"], index.titles("tag:vim"));
+    assert_eq!(vec!["Note 1"], index.titles("tag:vim -tag:rust"));
+    assert_eq!(vec!["Note 0"], index.titles("borrow"));
+    assert!(index.titles("emacs").is_empty());
+}
+
+#[test]
+fn sorted_and_paged() {
+    let docs: Vec<_> = (0..5)
+        .map(|i| {
+            let mut doc = note(i, "rust", &[]);
+            doc.date = Date::new(1_600_000_000 + i as i64);
+            doc
+        })
+        .collect();
+    let index = TestIndex::with(&docs);
+
+    let titles = |offset| -> Vec<String> {
+        let opts = SearchOptions {
+            sort: Sort::Newest,
+            offset,
+            limit: 2,
+            ..SearchOptions::default()
+        };
+        index
+            .search("rust", opts)
+            .into_iter()
+            .map(|d| d.title)
+            .collect()
+    };
+    assert_eq!(vec!["Note 4", "Note 3"], titles(0));
+    assert_eq!(vec!["Note 0"], titles(4));
+}